@@ -5,7 +5,7 @@ setup(
     name="mailpar",
     version="1.0",
     rust_extensions=[
-        RustExtension("mailpar", binding=Binding.PyO3)
+        RustExtension("mailpar", binding=Binding.PyO3, features=["python"])
     ],
     #packages=["mailpar"],
     # rust extensions are not zip safe, just like C-extensions.
@@ -0,0 +1,70 @@
+use mailparse::MailAddr;
+use mailparse::MailHeader;
+use mailparse::MailParseError;
+use mailparse::SingleInfo;
+
+
+/// Headers whose values are address lists (RFC 5322 3.6.2, 3.6.3, 3.6.6).
+pub const ADDRESS_HEADERS: &[&str] = &[
+    "From", "Sender", "Reply-To", "To", "Cc", "Bcc",
+    "Resent-From", "Resent-Sender", "Resent-To", "Resent-Cc", "Resent-Bcc",
+];
+
+
+#[derive(Clone, Debug, PartialEq)]
+pub struct Mailbox {
+    pub name: Option<String>,
+    pub addr: String,
+}
+
+
+/// One entry of an address list: a single mailbox, or a named group of
+/// mailboxes such as "undisclosed-recipients:;".
+#[derive(Clone, Debug, PartialEq)]
+pub enum Address {
+    Single(Mailbox),
+    Group {
+        name: String,
+        members: Vec<Mailbox>,
+    },
+}
+
+
+impl From<SingleInfo> for Mailbox {
+    fn from(info: SingleInfo) -> Mailbox {
+        Mailbox {
+            name: info.display_name,
+            addr: info.addr,
+        }
+    }
+}
+
+
+impl From<MailAddr> for Address {
+    fn from(addr: MailAddr) -> Address {
+        match addr {
+            MailAddr::Single(info) => Address::Single(info.into()),
+            MailAddr::Group(info) => Address::Group {
+                name: info.group_name,
+                members: info.addrs.into_iter().map(Mailbox::from).collect(),
+            },
+        }
+    }
+}
+
+
+impl Address {
+    /// The mailboxes this entry stands for; a group yields its members.
+    pub fn mailboxes(&self) -> Vec<&Mailbox> {
+        match self {
+            Address::Single(mailbox) => vec![mailbox],
+            Address::Group { members, .. } => members.iter().collect(),
+        }
+    }
+}
+
+
+pub fn parse_header(header: &MailHeader) -> Result<Vec<Address>, MailParseError> {
+    let addrs = mailparse::addrparse_header(header)?;
+    Ok(addrs.into_inner().into_iter().map(Address::from).collect())
+}
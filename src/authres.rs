@@ -0,0 +1,479 @@
+//! Authentication-Results (RFC 8601) parsing and ARC (RFC 8617) set
+//! assembly.
+
+use std::collections::BTreeMap;
+use std::collections::HashMap;
+
+use crate::dkim::parse_tags;
+use crate::message::MessageHeaders;
+
+
+/// RFC 8617 4.2.1: chains longer than this are invalid.
+const MAX_ARC_INSTANCES: u32 = 50;
+
+
+#[derive(Clone, Debug)]
+pub struct AuthProperty {
+    /// "smtp", "header", "body" or "policy".
+    pub ptype: String,
+    pub property: String,
+    pub value: String,
+}
+
+
+#[derive(Clone, Debug)]
+pub struct AuthResult {
+    pub method: String,
+    pub version: Option<String>,
+    pub result: String,
+    pub reason: Option<String>,
+    pub properties: Vec<AuthProperty>,
+}
+
+
+#[derive(Clone, Debug)]
+pub struct AuthResults {
+    pub authserv_id: String,
+    pub version: Option<String>,
+    /// Empty for a "none" result.
+    pub results: Vec<AuthResult>,
+}
+
+
+struct Cursor<'a> {
+    s: &'a [u8],
+    pos: usize,
+}
+
+
+impl<'a> Cursor<'a> {
+    fn peek(&self) -> Option<u8> {
+        self.s.get(self.pos).cloned()
+    }
+
+    /// Skip whitespace and (nested) comments.
+    fn skip_cfws(&mut self) {
+        let mut depth = 0;
+        while let Some(c) = self.peek() {
+            match c {
+                b'(' => depth += 1,
+                b')' if depth > 0 => depth -= 1,
+                b'\\' if depth > 0 => self.pos += 1,
+                _ if depth > 0 || c.is_ascii_whitespace() => {},
+                _ => return
+            }
+            self.pos += 1;
+        }
+    }
+
+    fn eat(&mut self, c: u8) -> bool {
+        self.skip_cfws();
+        if self.peek() == Some(c) {
+            self.pos += 1;
+            true
+        } else {
+            false
+        }
+    }
+
+    fn at_end(&mut self) -> bool {
+        self.skip_cfws();
+        self.pos >= self.s.len()
+    }
+
+    /// A run of characters up to whitespace, a comment, ';' or any of
+    /// `stop`.
+    fn token(&mut self, stop: &[u8]) -> String {
+        self.skip_cfws();
+        let start = self.pos;
+        while let Some(c) = self.peek() {
+            if c.is_ascii_whitespace() || c == b';' || c == b'(' || stop.contains(&c) {
+                break;
+            }
+            self.pos += 1;
+        }
+        String::from_utf8_lossy(&self.s[start..self.pos]).into_owned()
+    }
+
+    /// A quoted-string or a token.
+    fn value(&mut self) -> String {
+        self.skip_cfws();
+        if self.peek() != Some(b'"') {
+            return self.token(&[]);
+        }
+
+        self.pos += 1;
+        let mut out = Vec::new();
+        while let Some(c) = self.peek() {
+            self.pos += 1;
+            match c {
+                b'"' => break,
+                b'\\' => {
+                    if let Some(next) = self.peek() {
+                        out.push(next);
+                        self.pos += 1;
+                    }
+                },
+                _ => out.push(c)
+            }
+        }
+        String::from_utf8_lossy(&out).into_owned()
+    }
+}
+
+
+fn parse_resinfo(c: &mut Cursor) -> Result<AuthResult, String> {
+    let method = c.token(b"=");
+    if method.is_empty() {
+        return Err("missing method".to_string());
+    }
+    if !c.eat(b'=') {
+        return Err(format!("missing result for {}", method));
+    }
+    let result = c.token(&[]).to_ascii_lowercase();
+
+    let (method, version) = match method.split_once('/') {
+        Some((m, v)) => (m.trim().to_string(), Some(v.trim().to_string())),
+        None => (method, None)
+    };
+
+    let mut out = AuthResult {
+        method: method.to_ascii_lowercase(),
+        version: version,
+        result: result,
+        reason: None,
+        properties: Vec::new(),
+    };
+
+    loop {
+        if c.at_end() || c.peek() == Some(b';') {
+            return Ok(out);
+        }
+
+        let name = c.token(b"=");
+        if name.is_empty() || !c.eat(b'=') {
+            return Err(format!("malformed property after {}", out.method));
+        }
+        let value = c.value();
+
+        if name.eq_ignore_ascii_case("reason") {
+            out.reason = Some(value);
+            continue;
+        }
+        match name.split_once('.') {
+            Some((ptype, property)) => out.properties.push(AuthProperty {
+                ptype: ptype.to_ascii_lowercase(),
+                property: property.to_string(),
+                value: value,
+            }),
+            None => return Err(format!("property {} has no type", name))
+        }
+    }
+}
+
+
+/// Parse the value of an Authentication-Results field.
+pub fn parse_authentication_results(value: &str) -> Result<AuthResults, String> {
+    let mut c = Cursor { s: value.as_bytes(), pos: 0 };
+
+    let authserv_id = c.value();
+    if authserv_id.is_empty() {
+        return Err("missing authserv-id".to_string());
+    }
+
+    let mut version = None;
+    if !c.at_end() && c.peek() != Some(b';') {
+        let v = c.token(&[]);
+        if !v.chars().all(|ch| ch.is_ascii_digit()) {
+            return Err(format!("unexpected {:?} after authserv-id", v));
+        }
+        version = Some(v);
+    }
+
+    let mut results = Vec::new();
+    while c.eat(b';') {
+        if c.at_end() {
+            break;
+        }
+        let start = c.pos;
+        if c.token(&[]).eq_ignore_ascii_case("none") && (c.at_end() || c.peek() == Some(b';')) {
+            continue;
+        }
+        c.pos = start;
+        results.push(parse_resinfo(&mut c)?);
+    }
+
+    if !c.at_end() {
+        return Err("trailing data".to_string());
+    }
+
+    Ok(AuthResults {
+        authserv_id: authserv_id,
+        version: version,
+        results: results,
+    })
+}
+
+
+/// Split "i=N; rest" from the front of an ARC-Authentication-Results value.
+fn split_instance(value: &str) -> Option<(u32, &str)> {
+    let (tag, rest) = value.split_once(';')?;
+    let (name, n) = tag.split_once('=')?;
+    // Tag names are case-sensitive, as in `parse_tags`.
+    if name.trim() != "i" {
+        return None;
+    }
+    Some((n.trim().parse().ok()?, rest))
+}
+
+
+#[derive(Clone, Debug, Default)]
+pub struct ArcSet {
+    pub instance: u32,
+    pub authentication_results: Option<AuthResults>,
+    /// Tags of the ARC-Message-Signature.
+    pub message_signature: Option<HashMap<String, String>>,
+    /// Tags of the ARC-Seal.
+    pub seal: Option<HashMap<String, String>>,
+}
+
+
+#[derive(Clone, Debug)]
+pub struct ArcChain {
+    /// Sets in order of instance number.
+    pub sets: Vec<ArcSet>,
+    /// "none" if there are no ARC fields, "fail" if there are problems or a
+    /// seal says cv=fail, else "pass". Signatures are not verified.
+    pub status: &'static str,
+    pub problems: Vec<String>,
+}
+
+
+/// Group the ARC fields of `headers` into sets by instance number and check
+/// the structure of the chain (RFC 8617 5.1.1): every instance from 1 to
+/// the highest has exactly one of each field, and cv= is "none" on the
+/// first seal and "pass" on the others.
+pub fn arc_chain(headers: &MessageHeaders) -> ArcChain {
+    let mut sets: BTreeMap<u32, ArcSet> = BTreeMap::new();
+    let mut problems = Vec::new();
+
+    for value in headers.all("ARC-Authentication-Results") {
+        let (i, rest) = match split_instance(&value) {
+            Some(v) => v,
+            None => {
+                problems.push("ARC-Authentication-Results without a valid i= tag".to_string());
+                continue;
+            }
+        };
+        let set = sets.entry(i).or_default();
+        if set.authentication_results.is_some() {
+            problems.push(format!("duplicate ARC-Authentication-Results i={}", i));
+        }
+        match parse_authentication_results(rest) {
+            Ok(ar) => set.authentication_results = Some(ar),
+            Err(e) => problems.push(format!("ARC-Authentication-Results i={}: {}", i, e))
+        }
+    }
+
+    for kind in ["ARC-Message-Signature", "ARC-Seal"] {
+        for value in headers.all(kind) {
+            let tags = parse_tags(&value);
+            let i = match tags.as_ref().and_then(|t| t.get("i")).and_then(|i| i.parse::<u32>().ok()) {
+                Some(i) => i,
+                None => {
+                    problems.push(format!("{} without a valid i= tag", kind));
+                    continue;
+                }
+            };
+            let set = sets.entry(i).or_default();
+            let slot = match kind {
+                "ARC-Seal" => &mut set.seal,
+                _ => &mut set.message_signature
+            };
+            if slot.is_some() {
+                problems.push(format!("duplicate {} i={}", kind, i));
+            }
+            *slot = tags;
+        }
+    }
+
+    if sets.is_empty() && problems.is_empty() {
+        return ArcChain {
+            sets: Vec::new(),
+            status: "none",
+            problems: problems,
+        };
+    }
+
+    let highest = sets.keys().next_back().cloned().unwrap_or(0);
+    if highest > MAX_ARC_INSTANCES {
+        problems.push(format!("{} instances exceeds the limit of {}", highest, MAX_ARC_INSTANCES));
+    }
+
+    let mut cv_fail = false;
+    for i in 1..=highest.min(MAX_ARC_INSTANCES) {
+        let set = match sets.get_mut(&i) {
+            Some(s) => s,
+            None => {
+                problems.push(format!("missing ARC set i={}", i));
+                continue;
+            }
+        };
+
+        if set.authentication_results.is_none() {
+            problems.push(format!("missing ARC-Authentication-Results i={}", i));
+        }
+        if set.message_signature.is_none() {
+            problems.push(format!("missing ARC-Message-Signature i={}", i));
+        }
+
+        let cv = set.seal.as_ref().map(|s| s.get("cv").map(|v| v.to_ascii_lowercase()));
+        match (i, cv) {
+            (_, None) => problems.push(format!("missing ARC-Seal i={}", i)),
+            (_, Some(None)) => problems.push(format!("ARC-Seal i={} has no cv=", i)),
+            (_, Some(Some(cv))) if cv == "fail" => cv_fail = true,
+            (1, Some(Some(cv))) if cv != "none" => {
+                problems.push(format!("ARC-Seal i=1 has cv={}, expected none", cv));
+            },
+            (n, Some(Some(cv))) if n > 1 && cv != "pass" => {
+                problems.push(format!("ARC-Seal i={} has cv={}, expected pass", n, cv));
+            },
+            _ => {}
+        }
+    }
+    if sets.contains_key(&0) {
+        problems.push("ARC set with i=0".to_string());
+    }
+
+    for (&i, set) in sets.iter_mut() {
+        set.instance = i;
+    }
+
+    ArcChain {
+        status: if problems.is_empty() && !cv_fail { "pass" } else { "fail" },
+        sets: sets.into_values().collect(),
+        problems: problems,
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::message::Message;
+
+    #[test]
+    fn results_with_comments_and_quoted_values() {
+        let ar = parse_authentication_results(
+            "mx.example.com 1 (checked (twice)); spf=pass (sender ok) smtp.mailfrom=alice@example.com;\r\n \
+             DKIM=Pass header.d=example.com header.s=\"sel 1\" reason=\"good \\\"sig\\\"\"").unwrap();
+        assert_eq!(ar.authserv_id, "mx.example.com");
+        assert_eq!(ar.version.as_deref(), Some("1"));
+        assert_eq!(ar.results.len(), 2);
+
+        let spf = &ar.results[0];
+        assert_eq!((spf.method.as_str(), spf.result.as_str()), ("spf", "pass"));
+        assert_eq!(spf.properties[0].ptype, "smtp");
+        assert_eq!(spf.properties[0].property, "mailfrom");
+        assert_eq!(spf.properties[0].value, "alice@example.com");
+
+        let dkim = &ar.results[1];
+        assert_eq!((dkim.method.as_str(), dkim.result.as_str()), ("dkim", "pass"));
+        assert_eq!(dkim.reason.as_deref(), Some("good \"sig\""));
+        assert_eq!(dkim.properties[1].value, "sel 1");
+
+        let ar = parse_authentication_results("\"mx.example.com\"; dkim/1=fail header.d=x").unwrap();
+        assert_eq!(ar.authserv_id, "mx.example.com");
+        assert_eq!(ar.results[0].method, "dkim");
+        assert_eq!(ar.results[0].version.as_deref(), Some("1"));
+        assert_eq!(ar.results[0].result, "fail");
+    }
+
+    #[test]
+    fn none_results() {
+        for value in ["example.com; none", "example.com; none (no checks)", "example.com 1;none",
+                      "example.com"] {
+            let ar = parse_authentication_results(value).unwrap();
+            assert!(ar.results.is_empty(), "{}", value);
+        }
+        // A method that happens to be called "none" still needs a result.
+        let ar = parse_authentication_results("example.com; none=pass").unwrap();
+        assert_eq!(ar.results[0].method, "none");
+    }
+
+    #[test]
+    fn malformed_results() {
+        for value in ["", "(only a comment)", "example.com junk; spf=pass", "example.com; spf",
+                      "example.com; =pass", "example.com; spf=pass smtp.mailfrom",
+                      "example.com; spf=pass mailfrom=alice@example.com",
+                      "example.com; spf=pass smtp.mailfrom=a@example.com trailing"] {
+            assert!(parse_authentication_results(value).is_err(), "{}", value);
+        }
+    }
+
+    /// The three fields of ARC set `i`, with the seal saying `cv`.
+    fn arc_set(i: u32, cv: &str) -> String {
+        format!("ARC-Seal: i={}; a=rsa-sha256; cv={}; d=example.org; s=s; b=AAAA\r\n\
+ARC-Message-Signature: i={}; a=rsa-sha256; d=example.org; s=s; h=from; bh=AAAA; b=AAAA\r\n\
+ARC-Authentication-Results: i={}; mx.example.org; spf=pass smtp.mailfrom=example.com\r\n",
+                i, cv, i, i)
+    }
+
+    fn chain(fields: &str) -> ArcChain {
+        let raw = format!("{}Subject: x\r\n\r\nbody\r\n", fields);
+        let msg = Message::parse(raw.as_bytes()).unwrap();
+        arc_chain(&msg.headers())
+    }
+
+    #[test]
+    fn arc_chain_structure() {
+        let c = chain("");
+        assert_eq!(c.status, "none");
+
+        let c = chain(&(arc_set(3, "pass") + &arc_set(2, "pass") + &arc_set(1, "none")));
+        assert_eq!(c.status, "pass", "{:?}", c.problems);
+        assert_eq!(c.sets.iter().map(|s| s.instance).collect::<Vec<_>>(), [1, 2, 3]);
+        assert_eq!(c.sets[0].seal.as_ref().unwrap()["cv"], "none");
+        assert_eq!(c.sets[2].authentication_results.as_ref().unwrap().authserv_id, "mx.example.org");
+
+        // A seal reporting an earlier failure fails the chain, but is not a
+        // structural problem.
+        let c = chain(&(arc_set(2, "fail") + &arc_set(1, "none")));
+        assert_eq!(c.status, "fail");
+        assert!(c.problems.is_empty());
+    }
+
+    #[test]
+    fn arc_chain_problems() {
+        let cases = [
+            (arc_set(3, "pass") + &arc_set(1, "none"), "missing ARC set i=2"),
+            (arc_set(1, "none") + &arc_set(1, "none"), "duplicate ARC-Seal i=1"),
+            (arc_set(1, "pass"), "ARC-Seal i=1 has cv=pass, expected none"),
+            (arc_set(2, "none") + &arc_set(1, "none"), "ARC-Seal i=2 has cv=none, expected pass"),
+            (arc_set(1, "none").replace("ARC-Message-Signature: i=1", "X-Removed: i=1"),
+             "missing ARC-Message-Signature i=1"),
+            (arc_set(1, "none").replace("cv=none; ", ""), "ARC-Seal i=1 has no cv="),
+            (arc_set(1, "none").replace("ARC-Seal: i=1", "ARC-Seal: i=one"),
+             "ARC-Seal without a valid i= tag"),
+            (arc_set(0, "none") + &arc_set(1, "none"), "ARC set with i=0"),
+            (arc_set(1, "none").replace("ARC-Seal: i=1", "ARC-Seal: I=1"),
+             "ARC-Seal without a valid i= tag"),
+            (arc_set(1, "none").replace("ARC-Authentication-Results: i=1", "ARC-Authentication-Results: I=1"),
+             "ARC-Authentication-Results without a valid i= tag"),
+            (arc_set(1, "none").replace("cv=none", "CV=none"), "ARC-Seal i=1 has no cv="),
+        ];
+        for (fields, problem) in &cases {
+            let c = chain(fields);
+            assert_eq!(c.status, "fail", "{}", problem);
+            assert!(c.problems.iter().any(|p| p == problem), "{}: {:?}", problem, c.problems);
+        }
+
+        let mut fields = arc_set(1, "none");
+        for i in 2..=51 {
+            fields += &arc_set(i, "pass");
+        }
+        let c = chain(&fields);
+        assert_eq!(c.status, "fail");
+        assert_eq!(c.problems, ["51 instances exceeds the limit of 50"]);
+    }
+}
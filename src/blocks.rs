@@ -0,0 +1,419 @@
+//! Detection of files embedded in text bodies by uuencode, yEnc and BinHex
+//! 4.0, as sent by old mailers and newsreaders.
+
+use base64::Engine;
+
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum BlockFormat {
+    /// Classic "begin 644 name" uuencode.
+    Uuencode,
+    /// "begin-base64 644 name", as written by `uuencode -m`.
+    UuencodeBase64,
+    Yenc,
+    BinHex,
+}
+
+
+impl BlockFormat {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            BlockFormat::Uuencode => "uuencode",
+            BlockFormat::UuencodeBase64 => "uuencode-base64",
+            BlockFormat::Yenc => "yenc",
+            BlockFormat::BinHex => "binhex",
+        }
+    }
+}
+
+
+#[derive(Clone, Debug)]
+pub struct EncodedBlock {
+    pub format: BlockFormat,
+    pub filename: Option<String>,
+    /// Unix permissions from a uuencode "begin" line.
+    pub mode: Option<u32>,
+    /// Span of the block, from its first line through its last, within the
+    /// decoded text it was found in.
+    pub offset: [usize; 2],
+    pub data: Vec<u8>,
+}
+
+
+const BINHEX_INTRO: &[u8] = b"(This file must be converted with BinHex";
+const BINHEX_ALPHABET: &[u8] = b"!\"#$%&'()*+,-012345689@ABCDEFGHIJKLMNPQRSTUVXYZ[`abcdefhijklmpqr";
+
+
+/// Lines of `text` as `(start offset, line without its ending, offset of
+/// the next line)`.
+fn lines(text: &[u8]) -> Vec<(usize, &[u8], usize)> {
+    let mut out = Vec::new();
+    let mut pos = 0;
+    for line in text.split_inclusive(|&b| b == b'\n') {
+        let content = line.strip_suffix(b"\n").unwrap_or(line);
+        let content = content.strip_suffix(b"\r").unwrap_or(content);
+        out.push((pos, content, pos + line.len()));
+        pos += line.len();
+    }
+    out
+}
+
+
+fn filename(bytes: &[u8]) -> Option<String> {
+    let name = String::from_utf8_lossy(bytes).trim().to_string();
+    if name.is_empty() { None } else { Some(name) }
+}
+
+
+/// Parse "begin 644 name" or "begin-base64 644 name".
+fn uu_begin(line: &[u8]) -> Option<(BlockFormat, u32, Option<String>)> {
+    let (format, rest) = if let Some(rest) = line.strip_prefix(b"begin-base64 ") {
+        (BlockFormat::UuencodeBase64, rest)
+    } else {
+        (BlockFormat::Uuencode, line.strip_prefix(b"begin ")?)
+    };
+
+    let space = rest.iter().position(|&b| b == b' ')?;
+    let mode = std::str::from_utf8(&rest[..space]).ok()?;
+    if mode.len() < 3 || mode.len() > 4 {
+        return None;
+    }
+    let mode = u32::from_str_radix(mode, 8).ok()?;
+    Some((format, mode, filename(&rest[space+1..])))
+}
+
+
+/// Decode one line of classic uuencode.
+fn uu_line(line: &[u8], out: &mut Vec<u8>) -> Option<()> {
+    let val = |c: u8| (c.wrapping_sub(b' ')) & 0x3f;
+    let len = match line.first() {
+        Some(&c) => val(c) as usize,
+        None => return Some(())
+    };
+    let mut bytes = Vec::with_capacity(len + 2);
+    for group in line[1..].chunks(4) {
+        let mut g = [0u8; 4];
+        for (i, &c) in group.iter().enumerate() {
+            if !(b' '..=b'`').contains(&c) {
+                return None;
+            }
+            g[i] = val(c);
+        }
+        bytes.push((g[0] << 2) | (g[1] >> 4));
+        bytes.push((g[1] << 4) | (g[2] >> 2));
+        bytes.push((g[2] << 6) | g[3]);
+    }
+    if bytes.len() < len {
+        return None;
+    }
+    out.extend_from_slice(&bytes[..len]);
+    Some(())
+}
+
+
+/// Decode the uuencode block whose "begin" line is `text_lines[start]`;
+/// returns the block and the index of its closing line.
+fn uuencode(text_lines: &[(usize, &[u8], usize)], start: usize) -> Option<(EncodedBlock, usize)> {
+    let (begin, _, _) = text_lines[start];
+    let (format, mode, name) = uu_begin(text_lines[start].1)?;
+    let terminator: &[u8] = match format {
+        BlockFormat::UuencodeBase64 => b"====",
+        _ => b"end"
+    };
+
+    let mut data = Vec::new();
+    let mut b64 = Vec::new();
+    for (i, &(_, line, next)) in text_lines.iter().enumerate().skip(start + 1) {
+        if line.trim_ascii_end() == terminator {
+            if format == BlockFormat::UuencodeBase64 {
+                data = base64::engine::general_purpose::STANDARD.decode(&b64).ok()?;
+            }
+            let block = EncodedBlock {
+                format: format,
+                filename: name,
+                mode: Some(mode),
+                offset: [begin, next - begin],
+                data: data,
+            };
+            return Some((block, i));
+        }
+
+        match format {
+            BlockFormat::UuencodeBase64 => b64.extend(line.iter().filter(|b| !b.is_ascii_whitespace())),
+            _ => uu_line(line, &mut data)?
+        }
+    }
+
+    None
+}
+
+
+/// The value of `key=` in a yEnc header line; "name" runs to the end of
+/// the line.
+fn yenc_field<'a>(line: &'a [u8], key: &str) -> Option<&'a [u8]> {
+    let pat = format!(" {}=", key);
+    let start = line.windows(pat.len()).position(|w| w == pat.as_bytes())? + pat.len();
+    let rest = &line[start..];
+    if key == "name" {
+        return Some(rest);
+    }
+    let end = rest.iter().position(|&b| b == b' ').unwrap_or(rest.len());
+    Some(&rest[..end])
+}
+
+
+/// Whether `data`, decoded from a yEnc block with header line `header` and
+/// trailer `trailer`, has the sizes and CRC-32 they declare. For one part of
+/// a multipart post the "=ybegin" size is that of the whole file, and the
+/// part's own CRC is "pcrc32".
+fn yenc_intact(header: &[u8], trailer: &[u8], data: &[u8]) -> bool {
+    let number = |line: &[u8], key: &str, radix: u32| -> Result<Option<u64>, ()> {
+        match yenc_field(line, key) {
+            Some(v) => std::str::from_utf8(v).ok()
+                .and_then(|v| u64::from_str_radix(v.trim(), radix).ok())
+                .map(Some)
+                .ok_or(()),
+            None => Ok(None)
+        }
+    };
+    let is_part = yenc_field(header, "part").is_some();
+    let crc_key = if is_part { "pcrc32" } else { "crc32" };
+
+    let checks = [
+        (number(trailer, "size", 10), data.len() as u64),
+        (if is_part { Ok(None) } else { number(header, "size", 10) }, data.len() as u64),
+        (number(trailer, crc_key, 16), crate::crc::crc32(data) as u64),
+    ];
+    checks.iter().all(|(declared, actual)| match declared {
+        Ok(Some(n)) => n == actual,
+        Ok(None) => true,
+        Err(()) => false
+    })
+}
+
+
+fn yenc(text_lines: &[(usize, &[u8], usize)], start: usize) -> Option<(EncodedBlock, usize)> {
+    let (begin, header, _) = text_lines[start];
+    let name = yenc_field(header, "name").and_then(filename);
+
+    let mut data = Vec::new();
+    for (i, &(_, line, next)) in text_lines.iter().enumerate().skip(start + 1) {
+        if line.starts_with(b"=ypart ") {
+            continue;
+        }
+        if line.starts_with(b"=yend") {
+            if !yenc_intact(header, line, &data) {
+                return None;
+            }
+            let block = EncodedBlock {
+                format: BlockFormat::Yenc,
+                filename: name,
+                mode: None,
+                offset: [begin, next - begin],
+                data: data,
+            };
+            return Some((block, i));
+        }
+
+        let mut escaped = false;
+        for &b in line {
+            if escaped {
+                data.push(b.wrapping_sub(64).wrapping_sub(42));
+                escaped = false;
+            } else if b == b'=' {
+                escaped = true;
+            } else {
+                data.push(b.wrapping_sub(42));
+            }
+        }
+    }
+
+    None
+}
+
+
+/// Undo BinHex's run-length encoding: 0x90 n repeats the previous byte to
+/// make n copies in all, and 0x90 0x00 is a literal 0x90.
+fn binhex_rle(data: &[u8]) -> Vec<u8> {
+    let mut out: Vec<u8> = Vec::with_capacity(data.len());
+    let mut i = 0;
+    while i < data.len() {
+        if data[i] == 0x90 && i + 1 < data.len() {
+            let n = data[i+1] as usize;
+            if n == 0 {
+                out.push(0x90);
+            } else if let Some(&prev) = out.last() {
+                for _ in 1..n {
+                    out.push(prev);
+                }
+            }
+            i += 2;
+        } else {
+            out.push(data[i]);
+            i += 1;
+        }
+    }
+    out
+}
+
+
+fn binhex(text: &[u8], text_lines: &[(usize, &[u8], usize)], start: usize)
+    -> Option<(EncodedBlock, usize)>
+{
+    let (begin, _, body_start) = text_lines[start];
+    let colon = body_start + text[body_start..].iter().position(|&b| b == b':')?;
+    let close = colon + 1 + text[colon+1..].iter().position(|&b| b == b':')?;
+
+    let mut bits = 0u32;
+    let mut nbits = 0;
+    let mut packed = Vec::new();
+    for &c in &text[colon+1..close] {
+        if c.is_ascii_whitespace() {
+            continue;
+        }
+        let v = BINHEX_ALPHABET.iter().position(|&a| a == c)? as u32;
+        bits = (bits << 6) | v;
+        nbits += 6;
+        if nbits >= 8 {
+            nbits -= 8;
+            packed.push((bits >> nbits) as u8);
+            bits &= (1 << nbits) - 1;
+        }
+    }
+    let data = binhex_rle(&packed);
+
+    // Header: name length, name, version, type, creator, flags, data fork
+    // length, resource fork length, CRC.
+    let name_len = *data.first()? as usize;
+    let name = data.get(1..1+name_len)?;
+    let h = 1 + name_len + 1 + 4 + 4 + 2;
+    let data_len = u32::from_be_bytes(data.get(h..h+4)?.try_into().ok()?) as usize;
+    let fork_start = h + 4 + 4 + 2;
+    let fork = data.get(fork_start..fork_start.checked_add(data_len)?)?;
+
+    let end_line = text_lines.iter().position(|&(_, _, next)| next > close)
+        .unwrap_or(text_lines.len() - 1);
+    let block = EncodedBlock {
+        format: BlockFormat::BinHex,
+        filename: filename(name),
+        mode: None,
+        offset: [begin, text_lines[end_line].2 - begin],
+        data: fork.to_vec(),
+    };
+    Some((block, end_line))
+}
+
+
+/// Find and decode every uuencode, yEnc and BinHex 4.0 block in `text`,
+/// a transfer-decoded body. Blocks that are malformed, lack their closing
+/// line, or (yEnc) fail their size or CRC check are left alone.
+pub fn find_blocks(text: &[u8]) -> Vec<EncodedBlock> {
+    let mut out = Vec::new();
+    let needles: [&[u8]; 3] = [b"begin", b"=ybegin ", BINHEX_INTRO];
+    if !needles.iter().any(|n| text.windows(n.len()).any(|w| w == *n)) {
+        return out;
+    }
+
+    let text_lines = lines(text);
+    let mut i = 0;
+    while i < text_lines.len() {
+        let line = text_lines[i].1;
+        let found = if line.starts_with(b"begin") {
+            uuencode(&text_lines, i)
+        } else if line.starts_with(b"=ybegin ") {
+            yenc(&text_lines, i)
+        } else if line.starts_with(BINHEX_INTRO) {
+            binhex(text, &text_lines, i)
+        } else {
+            None
+        };
+
+        match found {
+            Some((block, end)) => {
+                out.push(block);
+                i = end + 1;
+            },
+            None => i += 1
+        }
+    }
+
+    out
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn uuencode_blocks() {
+        let text = b"Here it is:\r\nbegin 644 cat.txt\r\n#0V%T\r\n`\r\nend\r\nBye.\r\n";
+        let blocks = find_blocks(text);
+        assert_eq!(blocks.len(), 1);
+        assert_eq!(blocks[0].format, BlockFormat::Uuencode);
+        assert_eq!(blocks[0].filename.as_deref(), Some("cat.txt"));
+        assert_eq!(blocks[0].mode, Some(0o644));
+        assert_eq!(blocks[0].data, b"Cat");
+        assert_eq!(blocks[0].offset, [13, 34]);
+
+        let blocks = find_blocks(b"begin-base64 600 cat.txt\nQ2F0\n====\n");
+        assert_eq!(blocks[0].format, BlockFormat::UuencodeBase64);
+        assert_eq!(blocks[0].mode, Some(0o600));
+        assert_eq!(blocks[0].data, b"Cat");
+
+        // No "end", a bad mode, or a character outside the alphabet.
+        assert!(find_blocks(b"begin 644 cat.txt\n#0V%T\n`\n").is_empty());
+        assert!(find_blocks(b"begin with this\n#0V%T\n`\nend\n").is_empty());
+        assert!(find_blocks(b"begin 644 cat.txt\n#0V%\x7f\n`\nend\n").is_empty());
+    }
+
+    const YENC: &[u8] = b"=ybegin line=128 size=7 name=odd bytes.bin\r\n\
+*+=@=M=}=Jk\r\n\
+=yend size=7 crc32=d58af737\r\n";
+
+    #[test]
+    fn yenc_blocks() {
+        let blocks = find_blocks(YENC);
+        assert_eq!(blocks.len(), 1);
+        assert_eq!(blocks[0].format, BlockFormat::Yenc);
+        assert_eq!(blocks[0].filename.as_deref(), Some("odd bytes.bin"));
+        assert_eq!(blocks[0].data, [0x00, 0x01, 0xd6, 0xe3, 0x13, 0xe0, 0x41]);
+        assert_eq!(blocks[0].offset, [0, YENC.len()]);
+
+        let without_checks = b"=ybegin line=128 name=a.bin\r\n*+=@=M=}=Jk\r\n=yend\r\n";
+        assert_eq!(find_blocks(without_checks).len(), 1);
+
+        let part = b"=ybegin part=1 line=128 size=700 name=a.bin\r\n=ypart begin=1 end=7\r\n\
+*+=@=M=}=Jk\r\n=yend size=7 part=1 pcrc32=d58af737 crc32=00000000\r\n";
+        assert_eq!(find_blocks(part).len(), 1);
+    }
+
+    #[test]
+    fn yenc_blocks_failing_their_checks_are_dropped() {
+        let s = std::str::from_utf8(YENC).unwrap();
+        for (from, to) in [("crc32=d58af737", "crc32=d58af738"),
+                           ("crc32=d58af737", "crc32=nothex"),
+                           ("=yend size=7", "=yend size=8"),
+                           ("line=128 size=7", "line=128 size=6"),
+                           ("*+=@", "*+=@+")] {
+            assert!(find_blocks(s.replace(from, to).as_bytes()).is_empty(), "{}", to);
+        }
+    }
+
+    #[test]
+    fn binhex_blocks() {
+        let text = b"(This file must be converted with BinHex 4.0)\r\n\r\n\
+:\"f0KG#jdH(3!9%9B9(4dH(3!N!8)!*!%T`U3!'&LN!Ckp3!!:\r\n\
+after\r\n";
+        let blocks = find_blocks(text);
+        assert_eq!(blocks.len(), 1);
+        assert_eq!(blocks[0].format, BlockFormat::BinHex);
+        assert_eq!(blocks[0].filename.as_deref(), Some("cat.txt"));
+        // 0x90 is escaped, and the run of b's run-length encoded.
+        assert_eq!(blocks[0].data, b"\x90abbbbbb");
+        assert_eq!(blocks[0].offset, [0, text.len() - 7]);
+
+        // No closing colon.
+        assert!(find_blocks(&text[..text.len() - 10]).is_empty());
+    }
+}
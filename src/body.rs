@@ -0,0 +1,218 @@
+use std::collections::HashSet;
+
+use crate::html;
+use crate::message::Message;
+use crate::parts::content_id;
+use crate::parts::part_kind;
+use crate::parts::PartKind;
+use crate::parts::related_root;
+use crate::render::cid_url;
+
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Preference {
+    Html,
+    Text,
+}
+
+
+impl Preference {
+    pub fn from_name(s: &str) -> Option<Preference> {
+        match s.to_ascii_lowercase().as_str() {
+            "html" => Some(Preference::Html),
+            "text" => Some(Preference::Text),
+            _ => None
+        }
+    }
+
+    fn mime_type(&self) -> &'static str {
+        match self {
+            Preference::Html => "text/html",
+            Preference::Text => "text/plain",
+        }
+    }
+}
+
+
+#[derive(Clone, Debug)]
+pub struct BodyChoice {
+    pub path: Vec<usize>,
+    pub mime_type: String,
+    /// Paths of inline parts from the enclosing multipart/related whose
+    /// Content-ID the body references as a `cid:` URL.
+    pub related: Vec<Vec<usize>>,
+}
+
+
+fn select(msg: &Message, prefer: Preference) -> Option<Message> {
+    let mime_type = msg.mime_type().to_ascii_lowercase();
+
+    match part_kind(msg) {
+        PartKind::Body => return Some(msg.clone()),
+        PartKind::Inline | PartKind::Attachment => return None,
+        PartKind::Container => {}
+    }
+
+    if mime_type == "multipart/alternative" {
+        // RFC 2046 5.1.4: alternatives are in increasing order of
+        // faithfulness, so search from the end for the preferred type and
+        // otherwise settle for the last usable one.
+        let candidates: Vec<Message> = msg.subparts().iter()
+            .filter_map(|sub| select(sub, prefer))
+            .collect();
+
+        return candidates.iter().rev()
+            .find(|c| c.mime_type().eq_ignore_ascii_case(prefer.mime_type()))
+            .or(candidates.last())
+            .cloned();
+    }
+
+    if mime_type == "multipart/related" {
+        // The root is the body by definition, whatever its headers would
+        // make it look like on its own.
+        let root = related_root(msg)?;
+        let root_type = root.mime_type().to_ascii_lowercase();
+        if root_type == "text/html" || root_type == "text/plain" {
+            return Some(root);
+        }
+        return select(&root, prefer);
+    }
+
+    // multipart/mixed, multipart/signed and anything unknown: the first part
+    // that yields a body.
+    msg.subparts().iter().find_map(|sub| select(sub, prefer))
+}
+
+
+fn nearest_related(msg: &Message) -> Option<Message> {
+    let mut cur = msg.parent();
+    while let Some(p) = cur {
+        if p.mime_type().eq_ignore_ascii_case("multipart/related") {
+            return Some(p);
+        }
+        cur = p.parent();
+    }
+    None
+}
+
+
+fn collect_inline(msg: &Message, out: &mut Vec<Message>) {
+    for sub in msg.subparts() {
+        if part_kind(&sub) == PartKind::Inline {
+            out.push(sub.clone());
+        }
+        collect_inline(&sub, out);
+    }
+}
+
+
+/// The content ids, lower-cased, of the `cid:` URLs in `body`: for HTML,
+/// those among the URLs `html::rewrite_html` defers; for other text, or HTML
+/// it cannot rewrite, any word delimited by whitespace, quotes or brackets.
+fn referenced_cids(body: &Message) -> HashSet<String> {
+    let text = body.body().unwrap_or_default();
+    let rewritten = match body.mime_type().eq_ignore_ascii_case("text/html") {
+        true => html::rewrite_html(&text).ok(),
+        false => None
+    };
+    let urls: Vec<String> = match rewritten {
+        Some(output) => output.deferrals.into_iter()
+            .filter(|d| d.kind.is_url())
+            .map(|d| d.data)
+            .collect(),
+        None => text.split(|c: char| c.is_whitespace() || "\"'<>()[]".contains(c))
+            .map(|w| w.to_string())
+            .collect()
+    };
+    urls.iter()
+        .filter_map(|url| cid_url(url))
+        .map(|cid| cid.to_ascii_lowercase())
+        .collect()
+}
+
+
+/// Choose the body a reader should see, descending through alternative,
+/// related and mixed containers and skipping attachments.
+pub fn preferred_body(msg: &Message, prefer: Preference) -> Option<BodyChoice> {
+    let body = select(msg, prefer)?;
+
+    let mut related = Vec::new();
+    if let Some(container) = nearest_related(&body) {
+        let referenced = referenced_cids(&body);
+
+        let mut inline = Vec::new();
+        collect_inline(&container, &mut inline);
+        for part in inline {
+            let cid = match content_id(&part) {
+                Some(cid) => cid.to_ascii_lowercase(),
+                None => continue
+            };
+            if referenced.contains(&cid) {
+                related.push(part.path().to_vec());
+            }
+        }
+    }
+
+    Some(BodyChoice {
+        path: body.path().to_vec(),
+        mime_type: body.mime_type().clone(),
+        related: related,
+    })
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const RELATED: &[u8] = b"Content-Type: multipart/related; boundary=r; start=\"<root@x>\"\r\n\
+\r\n\
+--r\r\n\
+Content-Type: image/png\r\n\
+Content-ID: <logo@x>\r\n\
+\r\n\
+PNG\r\n\
+--r\r\n\
+Content-Type: image/gif\r\n\
+Content-ID: <unused@x>\r\n\
+\r\n\
+GIF\r\n\
+--r\r\n\
+Content-Type: text/html\r\n\
+Content-ID: <root@x>\r\n\
+\r\n\
+<img src=\"CID:logo@x\">\r\n\
+--r--\r\n";
+
+    #[test]
+    fn related_start_selects_root() {
+        let msg = Message::parse(RELATED).unwrap();
+        let choice = preferred_body(&msg, Preference::Html).unwrap();
+        assert_eq!(choice.path, vec![2]);
+        assert_eq!(choice.mime_type, "text/html");
+        assert_eq!(choice.related, vec![vec![0]]);
+    }
+
+    #[test]
+    fn related_parts_match_whole_content_ids() {
+        // "cid:logo@x.png" only starts with "cid:logo@x", and
+        // "cid:unused%40x" names unused@x.
+        let buf = String::from_utf8(RELATED.to_vec()).unwrap()
+            .replace("<img src=\"CID:logo@x\">",
+                     "<img src=\"cid:logo@x.png\"><img src=\"cid:unused%40x\">");
+        let msg = Message::parse(buf.as_bytes()).unwrap();
+        assert_eq!(preferred_body(&msg, Preference::Html).unwrap().related, vec![vec![1]]);
+    }
+
+    #[test]
+    fn alternative_prefers_requested_type() {
+        let buf = b"Content-Type: multipart/mixed; boundary=m\r\n\r\n--m\r\n\
+Content-Type: multipart/alternative; boundary=a\r\n\r\n--a\r\n\
+Content-Type: text/plain\r\n\r\nplain\r\n--a\r\n\
+Content-Type: text/html\r\n\r\n<p>html</p>\r\n--a--\r\n--m\r\n\
+Content-Type: text/plain\r\nContent-Disposition: attachment; filename=notes.txt\r\n\r\nnotes\r\n--m--\r\n";
+        let msg = Message::parse(buf).unwrap();
+        assert_eq!(preferred_body(&msg, Preference::Html).unwrap().path, vec![0, 1]);
+        assert_eq!(preferred_body(&msg, Preference::Text).unwrap().path, vec![0, 0]);
+    }
+}
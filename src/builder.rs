@@ -0,0 +1,780 @@
+//! Construction and serialization of MIME messages.
+//!
+//! Every serialized body ends with CRLF and the next delimiter line follows
+//! it directly, so each part's bytes as split out by the parser decode back
+//! to exactly the data given here. Text passed to `Part::text` has its line
+//! endings canonicalized to CRLF first. The one exception is a message/*
+//! body without a final CRLF, which may not be encoded and gets one added.
+
+use base64::Engine;
+use sha2::Digest;
+
+use crate::address::Mailbox;
+
+
+/// Soft limit on header line length, RFC 5322 2.1.1.
+const FOLD_WIDTH: usize = 78;
+
+/// Line length limit for quoted-printable and base64, RFC 2045 6.7, 6.8.
+const ENCODED_WIDTH: usize = 76;
+
+/// RFC 2047 2: an encoded-word may not be longer than this.
+const MAX_ENCODED_WORD: usize = 75;
+
+/// Parameter values longer than this are split into RFC 2231 sections.
+const MAX_PARAM_SECTION: usize = 60;
+
+/// Headers generated from the part's own fields; any set with `header()`
+/// are ignored.
+const GENERATED_HEADERS: &[&str] = &[
+    "Content-Type", "Content-Disposition", "Content-Transfer-Encoding",
+];
+
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum TransferEncoding {
+    SevenBit,
+    EightBit,
+    QuotedPrintable,
+    Base64,
+}
+
+
+impl TransferEncoding {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            TransferEncoding::SevenBit => "7bit",
+            TransferEncoding::EightBit => "8bit",
+            TransferEncoding::QuotedPrintable => "quoted-printable",
+            TransferEncoding::Base64 => "base64",
+        }
+    }
+
+    pub fn from_name(name: &str) -> Option<TransferEncoding> {
+        match name.to_ascii_lowercase().as_str() {
+            "7bit" => Some(TransferEncoding::SevenBit),
+            "8bit" => Some(TransferEncoding::EightBit),
+            "quoted-printable" => Some(TransferEncoding::QuotedPrintable),
+            "base64" => Some(TransferEncoding::Base64),
+            _ => None
+        }
+    }
+}
+
+
+#[derive(Clone, Debug)]
+pub struct Part {
+    mime_type: String,
+    params: Vec<(String, String)>,
+    /// Names and values, the values already encoded but not folded.
+    headers: Vec<(String, String)>,
+    disposition: Option<String>,
+    disposition_params: Vec<(String, String)>,
+    encoding: Option<TransferEncoding>,
+    body: Vec<u8>,
+    children: Vec<Part>,
+}
+
+
+/// Convert bare CRs and LFs to CRLF.
+fn canonical_lines(text: &str) -> Vec<u8> {
+    text.replace("\r\n", "\n").replace('\r', "\n").replace('\n', "\r\n").into_bytes()
+}
+
+
+fn is_atext(c: char) -> bool {
+    c.is_ascii_alphanumeric() || "!#$%&'*+-/=?^_`{|}~".contains(c)
+}
+
+
+fn is_token_char(c: char) -> bool {
+    c.is_ascii_graphic() && !"()<>@,;:\\\"/[]?=".contains(c)
+}
+
+
+/// A header field name: printable ASCII other than ":", RFC 5322 2.2.
+pub(crate) fn check_header_name(name: &str) -> Result<(), String> {
+    if name.is_empty() || !name.chars().all(|c| c.is_ascii_graphic() && c != ':') {
+        return Err(format!("invalid header name {:?}", name));
+    }
+    Ok(())
+}
+
+
+fn is_token(s: &str) -> bool {
+    !s.is_empty() && s.chars().all(is_token_char)
+}
+
+
+/// A "type/subtype" media type, each half an RFC 2045 token.
+fn check_mime_type(mime_type: &str) -> Result<(), String> {
+    match mime_type.split_once('/') {
+        Some((t, sub)) if is_token(t) && is_token(sub) => Ok(()),
+        _ => Err(format!("invalid MIME type {:?}", mime_type))
+    }
+}
+
+
+fn quote(s: &str) -> String {
+    format!("\"{}\"", s.replace('\\', "\\\\").replace('"', "\\\""))
+}
+
+
+/// Encode `text` as RFC 2047 encoded-words separated by spaces, using Q
+/// encoding if it is mostly ASCII and B encoding otherwise.
+pub fn encode_words(text: &str) -> String {
+    let q_safe = |b: u8| b.is_ascii_alphanumeric() || b"!*+-/ ".contains(&b);
+    let escaped = text.bytes().filter(|&b| !q_safe(b)).count();
+    let use_b = escaped * 3 > text.len();
+
+    let prefix = if use_b { "=?utf-8?B?" } else { "=?utf-8?Q?" };
+    let room = MAX_ENCODED_WORD - prefix.len() - 2;
+
+    let mut words = Vec::new();
+    let mut chunk = String::new();
+    let encoded_len = |s: &str| if use_b {
+        s.len().div_ceil(3) * 4
+    } else {
+        s.bytes().map(|b| if q_safe(b) { 1 } else { 3 }).sum()
+    };
+
+    let flush = |chunk: &str, words: &mut Vec<String>| {
+        let payload = if use_b {
+            base64::engine::general_purpose::STANDARD.encode(chunk)
+        } else {
+            let mut q = String::new();
+            for b in chunk.bytes() {
+                match b {
+                    b' ' => q.push('_'),
+                    _ if q_safe(b) => q.push(b as char),
+                    _ => q.push_str(&format!("={:02X}", b))
+                }
+            }
+            q
+        };
+        words.push(format!("{}{}?=", prefix, payload));
+    };
+
+    for c in text.chars() {
+        let mut candidate = chunk.clone();
+        candidate.push(c);
+        if !chunk.is_empty() && encoded_len(&candidate) > room {
+            flush(&chunk, &mut words);
+            chunk.clear();
+        }
+        chunk.push(c);
+    }
+    if !chunk.is_empty() {
+        flush(&chunk, &mut words);
+    }
+    words.join(" ")
+}
+
+
+/// Encode an unstructured header value (Subject, Comments, ...): runs of
+/// words that are not plain ASCII become encoded-words, the rest is kept.
+pub fn encode_unstructured(value: &str) -> String {
+    let needs_encoding = |w: &str| {
+        !w.is_ascii() || w.contains("=?") || w.chars().any(|c| c.is_control())
+    };
+    if !needs_encoding(value) {
+        return value.to_string();
+    }
+
+    let words: Vec<&str> = value.split(' ').collect();
+    let mut out: Vec<String> = Vec::new();
+    let mut i = 0;
+    while i < words.len() {
+        if !needs_encoding(words[i]) {
+            out.push(words[i].to_string());
+            i += 1;
+            continue;
+        }
+
+        // Extend the run over following words that need encoding, and the
+        // spaces between them, which would be lost between encoded-words.
+        let mut j = i;
+        while let Some(k) = (j+1..words.len()).find(|&k| !words[k].is_empty()) {
+            if !needs_encoding(words[k]) {
+                break;
+            }
+            j = k;
+        }
+        out.push(encode_words(&words[i..=j].join(" ")));
+        i = j + 1;
+    }
+    out.join(" ")
+}
+
+
+/// A mailbox as it appears in an address header. The display name is
+/// quoted or encoded as needed; the address is used as is, so it may not
+/// contain control characters.
+pub fn format_mailbox(mailbox: &Mailbox) -> Result<String, String> {
+    if mailbox.addr.chars().any(|c| c.is_control()) {
+        return Err(format!("invalid address {:?}", mailbox.addr));
+    }
+    let name = match mailbox.name.as_deref() {
+        Some(n) if !n.is_empty() => n,
+        _ => return Ok(mailbox.addr.clone())
+    };
+
+    let display = if !name.is_ascii() || name.chars().any(|c| c.is_control()) {
+        encode_words(name)
+    } else if name.split(' ').all(|w| !w.is_empty() && w.chars().all(is_atext)) {
+        name.to_string()
+    } else {
+        quote(name)
+    };
+    Ok(format!("{} <{}>", display, mailbox.addr))
+}
+
+
+fn percent_encode(value: &str) -> String {
+    let mut out = String::new();
+    for b in value.bytes() {
+        if b.is_ascii_alphanumeric() || b"!#$&+-.^_`|~".contains(&b) {
+            out.push(b as char);
+        } else {
+            out.push_str(&format!("%{:02X}", b));
+        }
+    }
+    out
+}
+
+
+/// A Content-Type or Content-Disposition parameter, RFC 2231 encoded (and
+/// split into sections) if it is not short plain ASCII. Quotes and
+/// backslashes are percent-encoded too, as parsers that do not unescape
+/// quoted-pairs would otherwise keep the backslashes.
+pub fn encode_param(name: &str, value: &str) -> String {
+    let plain = value.is_ascii() && !value.chars().any(|c| c.is_control() || c == '"' || c == '\\');
+    if plain && value.len() <= MAX_PARAM_SECTION {
+        if !value.is_empty() && value.chars().all(is_token_char) {
+            return format!("{}={}", name, value);
+        }
+        return format!("{}={}", name, quote(value));
+    }
+
+    let mut sections = Vec::new();
+    if plain {
+        let chars: Vec<char> = value.chars().collect();
+        for chunk in chars.chunks(MAX_PARAM_SECTION) {
+            sections.push(quote(&chunk.iter().collect::<String>()));
+        }
+    } else {
+        let encoded = percent_encode(value);
+        let mut start = 0;
+        while start < encoded.len() {
+            let mut end = (start + MAX_PARAM_SECTION).min(encoded.len());
+            // Don't split a %XX escape.
+            if let Some(pct) = encoded[end.saturating_sub(2)..end].find('%') {
+                if end < encoded.len() {
+                    end = end - 2 + pct;
+                }
+            }
+            sections.push(encoded[start..end].to_string());
+            start = end;
+        }
+        sections[0] = format!("utf-8''{}", sections[0]);
+    }
+
+    if sections.len() == 1 {
+        return format!("{}*={}", name, sections[0]);
+    }
+    let star = if plain { "" } else { "*" };
+    sections.iter().enumerate()
+        .map(|(i, s)| format!("{}*{}{}={}", name, i, star, s))
+        .collect::<Vec<_>>()
+        .join("; ")
+}
+
+
+/// "Name: value" folded at spaces to keep lines within 78 characters where
+/// possible, with a trailing CRLF.
+pub(crate) fn fold_header(name: &str, value: &str) -> String {
+    let mut out = format!("{}:", name);
+    let mut line_len = out.len();
+    let mut first = true;
+    for word in value.split(' ') {
+        if !first && line_len + 1 + word.len() > FOLD_WIDTH {
+            out.push_str("\r\n");
+            line_len = 0;
+        }
+        out.push(' ');
+        out.push_str(word);
+        line_len += 1 + word.len();
+        first = false;
+    }
+    out.push_str("\r\n");
+    out
+}
+
+
+/// Quoted-printable with lines of at most 76 characters. The output always
+/// ends with CRLF, using a soft line break if `data` does not.
+pub fn encode_quoted_printable(data: &[u8]) -> Vec<u8> {
+    let mut out = Vec::with_capacity(data.len() * 2);
+    let mut line_len = 0;
+    let mut i = 0;
+    while i < data.len() {
+        let b = data[i];
+        if b == b'\r' && data.get(i+1) == Some(&b'\n') {
+            out.extend_from_slice(b"\r\n");
+            line_len = 0;
+            i += 2;
+            continue;
+        }
+
+        let at_line_end = i + 1 == data.len() || data[i+1..].starts_with(b"\r\n");
+        let literal = match b {
+            b' ' | b'\t' => !at_line_end,
+            b'=' => false,
+            _ => b.is_ascii_graphic()
+        };
+        let width = if literal { 1 } else { 3 };
+
+        // Leave room for the "=" of a soft break unless a hard one follows.
+        let limit = if data[i+1..].starts_with(b"\r\n") { ENCODED_WIDTH } else { ENCODED_WIDTH - 1 };
+        if line_len + width > limit {
+            out.extend_from_slice(b"=\r\n");
+            line_len = 0;
+        }
+        if literal {
+            out.push(b);
+        } else {
+            out.extend_from_slice(format!("={:02X}", b).as_bytes());
+        }
+        line_len += width;
+        i += 1;
+    }
+
+    if !out.is_empty() && !out.ends_with(b"\r\n") {
+        out.extend_from_slice(b"=\r\n");
+    }
+    out
+}
+
+
+pub(crate) fn encode_base64(data: &[u8]) -> Vec<u8> {
+    let encoded = base64::engine::general_purpose::STANDARD.encode(data);
+    let mut out = Vec::with_capacity(encoded.len() + encoded.len() / ENCODED_WIDTH * 2 + 2);
+    for line in encoded.as_bytes().chunks(ENCODED_WIDTH) {
+        out.extend_from_slice(line);
+        out.extend_from_slice(b"\r\n");
+    }
+    out
+}
+
+
+/// Whether `data` can go unencoded: CRLF line endings only, lines within
+/// the quoted-printable limit, and a final CRLF so the delimiter can
+/// follow directly.
+pub(crate) fn is_line_safe(data: &[u8]) -> bool {
+    if !data.is_empty() && !data.ends_with(b"\r\n") {
+        return false;
+    }
+    let mut len = 0;
+    for (i, &b) in data.iter().enumerate() {
+        match b {
+            b'\r' if data.get(i+1) == Some(&b'\n') => {},
+            b'\n' if i > 0 && data[i-1] == b'\r' => len = 0,
+            b'\r' | b'\n' | 0 => return false,
+            _ => {
+                len += 1;
+                if len > ENCODED_WIDTH {
+                    return false;
+                }
+            }
+        }
+    }
+    true
+}
+
+
+fn choose_encoding(mime_type: &str, data: &[u8]) -> TransferEncoding {
+    let line_safe = is_line_safe(data);
+    if line_safe && data.is_ascii() {
+        return TransferEncoding::SevenBit;
+    }
+
+    // mailparse's quoted-printable decoder ends the body with CRLF if the
+    // encoded text ends with a line break, soft or not, so only base64
+    // keeps a body that does not end with CRLF exact.
+    let is_text = mime_type.to_ascii_lowercase().starts_with("text/");
+    let escaped = data.iter().filter(|&&b| b >= 0x80 || (b < 0x20 && b != b'\r' && b != b'\n' && b != b'\t')).count();
+    if is_text && escaped * 3 <= data.len() && data.ends_with(b"\r\n") {
+        TransferEncoding::QuotedPrintable
+    } else {
+        TransferEncoding::Base64
+    }
+}
+
+
+/// A boundary that occurs in none of `parts`.
+fn make_boundary(parts: &[Vec<u8>]) -> String {
+    let mut hasher = sha2::Sha256::new();
+    for p in parts {
+        hasher.update(p);
+    }
+    let digest = hasher.finalize();
+    let mut boundary = format!("=_{}", digest[..12].iter().map(|b| format!("{:02x}", b)).collect::<String>());
+    while parts.iter().any(|p| p.windows(boundary.len()).any(|w| w == boundary.as_bytes())) {
+        boundary.push('_');
+    }
+    boundary
+}
+
+
+impl Part {
+    /// A leaf part holding `body`, which is encoded when serialized.
+    pub fn new(mime_type: &str, body: Vec<u8>) -> Result<Part, String> {
+        check_mime_type(mime_type)?;
+        Ok(Part::leaf(mime_type, body))
+    }
+
+    fn leaf(mime_type: &str, body: Vec<u8>) -> Part {
+        Part {
+            mime_type: mime_type.to_string(),
+            params: Vec::new(),
+            headers: Vec::new(),
+            disposition: None,
+            disposition_params: Vec::new(),
+            encoding: None,
+            body: body,
+            children: Vec::new(),
+        }
+    }
+
+    /// A text/`subtype` part in UTF-8.
+    pub fn text(subtype: &str, text: &str) -> Result<Part, String> {
+        let mut part = Part::new(&format!("text/{}", subtype), canonical_lines(text))?;
+        part.set_param("charset", "utf-8");
+        Ok(part)
+    }
+
+    pub fn attachment(filename: &str, mime_type: &str, data: Vec<u8>) -> Result<Part, String> {
+        let mut part = Part::new(mime_type, data)?;
+        part.set_param("name", filename);
+        part.disposition("attachment", Some(filename))?;
+        Ok(part)
+    }
+
+    /// A multipart/`subtype` part.
+    pub fn multipart(subtype: &str, children: Vec<Part>) -> Result<Part, String> {
+        let mut part = Part::new(&format!("multipart/{}", subtype), Vec::new())?;
+        part.children = children;
+        Ok(part)
+    }
+
+    /// A message/rfc822 part containing `message`.
+    pub fn message(message: &Part) -> Part {
+        Part::leaf("message/rfc822", message.to_bytes())
+    }
+
+    pub fn is_multipart(&self) -> bool {
+        self.mime_type.to_ascii_lowercase().starts_with("multipart/")
+    }
+
+    /// Add a header, RFC 2047 encoding the value as unstructured text if
+    /// it is not ASCII. Content-Type, Content-Disposition and
+    /// Content-Transfer-Encoding are generated and cannot be set here.
+    pub fn header(&mut self, name: &str, value: &str) -> Result<&mut Part, String> {
+        check_header_name(name)?;
+        self.headers.push((name.to_string(), encode_unstructured(value)));
+        Ok(self)
+    }
+
+    /// Add an address header (From, To, Cc, ...) listing `mailboxes`.
+    pub fn address_header(&mut self, name: &str, mailboxes: &[Mailbox]) -> Result<&mut Part, String> {
+        check_header_name(name)?;
+        let value = mailboxes.iter().map(format_mailbox).collect::<Result<Vec<_>, _>>()?.join(", ");
+        self.headers.push((name.to_string(), value));
+        Ok(self)
+    }
+
+    /// Set a Content-Type parameter, replacing any earlier value. The value
+    /// is quoted or RFC 2231 encoded as needed; the name must be a token.
+    pub fn param(&mut self, name: &str, value: &str) -> Result<&mut Part, String> {
+        if !is_token(name) {
+            return Err(format!("invalid parameter name {:?}", name));
+        }
+        self.set_param(name, value);
+        Ok(self)
+    }
+
+    fn set_param(&mut self, name: &str, value: &str) {
+        self.params.retain(|(n, _)| !n.eq_ignore_ascii_case(name));
+        self.params.push((name.to_string(), value.to_string()));
+    }
+
+    /// Set the Content-Disposition ("inline", "attachment"). The kind must
+    /// be a token.
+    pub fn disposition(&mut self, kind: &str, filename: Option<&str>) -> Result<&mut Part, String> {
+        if !is_token(kind) {
+            return Err(format!("invalid disposition {:?}", kind));
+        }
+        self.disposition = Some(kind.to_string());
+        self.disposition_params.clear();
+        if let Some(f) = filename {
+            self.disposition_params.push(("filename".to_string(), f.to_string()));
+        }
+        Ok(self)
+    }
+
+    /// Force a transfer encoding instead of choosing one. Ignored for
+    /// multipart and message parts, which are never encoded, and 7bit or
+    /// 8bit is ignored if the body does not fit it.
+    pub fn encoding(&mut self, encoding: TransferEncoding) -> &mut Part {
+        self.encoding = Some(encoding);
+        self
+    }
+
+    /// Append a child to a multipart part.
+    pub fn attach(&mut self, child: Part) -> &mut Part {
+        self.children.push(child);
+        self
+    }
+
+    pub fn children(&self) -> &[Part] {
+        &self.children
+    }
+
+    /// The encoded body and its transfer encoding, with the multipart
+    /// boundary if there is one.
+    fn encode_body(&self) -> (Vec<u8>, TransferEncoding, Option<String>) {
+        if self.is_multipart() {
+            let children: Vec<Vec<u8>> = self.children.iter().map(|c| c.serialize(false)).collect();
+            let boundary = make_boundary(&children);
+            let mut body = Vec::new();
+            for child in &children {
+                body.extend_from_slice(format!("--{}\r\n", boundary).as_bytes());
+                body.extend_from_slice(child);
+            }
+            body.extend_from_slice(format!("--{}--\r\n", boundary).as_bytes());
+            let encoding = if body.is_ascii() { TransferEncoding::SevenBit } else { TransferEncoding::EightBit };
+            return (body, encoding, Some(boundary));
+        }
+
+        let is_message = self.mime_type.to_ascii_lowercase().starts_with("message/");
+        let line_safe = is_line_safe(&self.body);
+        // A forced 7bit or 8bit is only honoured if the body can go out as
+        // it is; otherwise one is chosen as if none was set.
+        let forced = if is_message { None } else { self.encoding };
+        let encoding = match forced {
+            Some(TransferEncoding::SevenBit) if line_safe && self.body.is_ascii() => TransferEncoding::SevenBit,
+            Some(TransferEncoding::EightBit) if line_safe => TransferEncoding::EightBit,
+            Some(e @ TransferEncoding::QuotedPrintable) | Some(e @ TransferEncoding::Base64) => e,
+            _ => match choose_encoding(&self.mime_type, &self.body) {
+                TransferEncoding::SevenBit => TransferEncoding::SevenBit,
+                _ if is_message => TransferEncoding::EightBit,
+                e => e
+            }
+        };
+
+        let body = match encoding {
+            TransferEncoding::QuotedPrintable => encode_quoted_printable(&self.body),
+            TransferEncoding::Base64 => encode_base64(&self.body),
+            _ => {
+                // Line-safe bodies already end with CRLF. A message body
+                // may not be, but cannot be encoded either, and the
+                // delimiter still needs a line of its own.
+                let mut body = self.body.clone();
+                if !body.is_empty() && !body.ends_with(b"\r\n") {
+                    body.extend_from_slice(b"\r\n");
+                }
+                body
+            }
+        };
+        (body, encoding, None)
+    }
+
+    pub(crate) fn serialize(&self, root: bool) -> Vec<u8> {
+        let (body, encoding, boundary) = self.encode_body();
+
+        let mut out = String::new();
+        for (name, value) in &self.headers {
+            if !GENERATED_HEADERS.iter().any(|h| h.eq_ignore_ascii_case(name)) {
+                out.push_str(&fold_header(name, value));
+            }
+        }
+        if root && !self.headers.iter().any(|(n, _)| n.eq_ignore_ascii_case("MIME-Version")) {
+            out.push_str("MIME-Version: 1.0\r\n");
+        }
+
+        let mut ctype = self.mime_type.clone();
+        for (name, value) in &self.params {
+            ctype.push_str("; ");
+            ctype.push_str(&encode_param(name, value));
+        }
+        if let Some(b) = &boundary {
+            ctype.push_str(&format!("; boundary=\"{}\"", b));
+        }
+        out.push_str(&fold_header("Content-Type", &ctype));
+
+        if let Some(kind) = &self.disposition {
+            let mut disposition = kind.clone();
+            for (name, value) in &self.disposition_params {
+                disposition.push_str("; ");
+                disposition.push_str(&encode_param(name, value));
+            }
+            out.push_str(&fold_header("Content-Disposition", &disposition));
+        }
+
+        if boundary.is_none() || encoding != TransferEncoding::SevenBit {
+            out.push_str(&fold_header("Content-Transfer-Encoding", encoding.as_str()));
+        }
+        out.push_str("\r\n");
+
+        let mut bytes = out.into_bytes();
+        bytes.extend_from_slice(&body);
+        bytes
+    }
+
+    /// Serialize as a complete message, adding MIME-Version if it was not
+    /// set.
+    pub fn to_bytes(&self) -> Vec<u8> {
+        self.serialize(true)
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::message::Message;
+
+    /// Build `part` into a one-part multipart and parse it back, so the
+    /// body is followed by a delimiter as it is in a real message.
+    fn round_trip(part: Part) -> (Vec<u8>, Message) {
+        let bytes = Part::multipart("mixed", vec![part]).unwrap().to_bytes();
+        let msg = Message::parse(&bytes).unwrap().subpart(0).unwrap();
+        (bytes, msg)
+    }
+
+    #[test]
+    fn bodies_round_trip() {
+        let long_line = "x".repeat(200) + "\r\n";
+        let cases: &[(&str, &[u8], &str)] = &[
+            ("text/plain", b"plain\r\nlines\r\n", "7bit"),
+            ("text/plain", b"no final line ending", "base64"),
+            ("text/plain", b"caf\xc3\xa9 =\r\ntrailing space \r\n", "quotedprintable"),
+            ("text/plain", long_line.as_bytes(), "quotedprintable"),
+            ("text/plain", b"bare\nline feed\n", "base64"),
+            ("application/octet-stream", b"\x00\x01\x02\xff\xfe", "base64"),
+            ("application/octet-stream", b"", "7bit"),
+        ];
+        for &(mime_type, data, encoding) in cases {
+            let (_, msg) = round_trip(Part::new(mime_type, data.to_vec()).unwrap());
+            assert_eq!(msg.body_encoding(), encoding, "{:?}", data);
+            assert_eq!(msg.body_raw().unwrap(), data);
+        }
+    }
+
+    #[test]
+    fn forced_encodings() {
+        let cases: &[(TransferEncoding, &[u8], &str)] = &[
+            (TransferEncoding::SevenBit, b"fits\r\n", "7bit"),
+            (TransferEncoding::SevenBit, b"no final line ending", "base64"),
+            (TransferEncoding::SevenBit, b"caf\xc3\xa9\r\n", "quotedprintable"),
+            (TransferEncoding::EightBit, b"caf\xc3\xa9\r\n", "8bit"),
+            (TransferEncoding::EightBit, b"caf\xc3\xa9", "base64"),
+            (TransferEncoding::EightBit, b"nul\x00\r\n", "quotedprintable"),
+            (TransferEncoding::Base64, b"fits\r\n", "base64"),
+            (TransferEncoding::QuotedPrintable, b"\xff\xfe\r\n", "quotedprintable"),
+        ];
+        for &(forced, data, encoding) in cases {
+            let mut part = Part::new("text/plain", data.to_vec()).unwrap();
+            part.encoding(forced);
+            let (_, msg) = round_trip(part);
+            assert_eq!(msg.body_encoding(), encoding, "{:?}", data);
+            assert_eq!(msg.body_raw().unwrap(), data);
+        }
+    }
+
+    #[test]
+    fn params_round_trip() {
+        let long = "a".repeat(150) + ".txt";
+        let long_utf8 = "\u{fc}ber ".repeat(20) + ".pdf";
+        for filename in ["plain.txt", "with space.txt", "quote\"d.txt", long.as_str(),
+                         "r\u{e9}sum\u{e9}.pdf", long_utf8.as_str()] {
+            let part = Part::attachment(filename, "application/pdf", b"%PDF\r\n".to_vec()).unwrap();
+            let (_, msg) = round_trip(part);
+            assert_eq!(msg.param("name").map(String::as_str), Some(filename));
+            assert_eq!(msg.filename().as_deref(), Some(filename));
+            assert_eq!(msg.body_raw().unwrap(), b"%PDF\r\n");
+        }
+    }
+
+    #[test]
+    fn nested_multiparts_round_trip() {
+        let inner = Part::multipart("alternative", vec![
+            Part::text("plain", "Hello\n").unwrap(),
+            Part::text("html", "<p>Hello</p>").unwrap(),
+        ]).unwrap();
+        let mut message = Part::text("plain", "Inner\n").unwrap();
+        message.header("Subject", "inner").unwrap();
+        let mut root = Part::multipart("mixed", vec![
+            inner,
+            Part::attachment("data.bin", "application/octet-stream", vec![0, 1, 2]).unwrap(),
+            Part::message(&message),
+        ]).unwrap();
+        root.header("Subject", "Gr\u{fc}\u{df}e aus K\u{f6}ln").unwrap();
+        let bytes = root.to_bytes();
+
+        let msg = Message::parse(&bytes).unwrap();
+        assert_eq!(msg.headers().decoded("Subject", false), vec!["Gr\u{fc}\u{df}e aus K\u{f6}ln"]);
+        assert_eq!(msg.subpart_count(), 3);
+
+        let alternative = msg.subpart(0).unwrap();
+        assert_eq!(alternative.mime_type(), "multipart/alternative");
+        assert_eq!(alternative.subpart(0).unwrap().body_raw().unwrap(), b"Hello\r\n");
+        assert_eq!(alternative.subpart(1).unwrap().body_raw().unwrap(), b"<p>Hello</p>");
+        assert_eq!(msg.subpart(1).unwrap().body_raw().unwrap(), vec![0, 1, 2]);
+
+        let embedded = msg.subpart(2).unwrap().embedded_message().unwrap();
+        assert_eq!(embedded.headers().first("Subject").as_deref(), Some("inner"));
+        assert_eq!(embedded.body_raw().unwrap(), b"Inner\r\n");
+
+        // Serializing is deterministic, so building again gives the same
+        // bytes.
+        assert_eq!(root.to_bytes(), bytes);
+    }
+
+    #[test]
+    fn control_characters_are_rejected() {
+        let mut part = Part::text("plain", "hi").unwrap();
+        assert!(part.header("X-Bad\r\nBcc", "x").is_err());
+        assert!(part.header("X-Bad:", "x").is_err());
+        assert!(part.header("", "x").is_err());
+        assert!(part.param("name\r\nX-Injected: 1", "x").is_err());
+        assert!(part.param("na me", "x").is_err());
+        assert!(Part::new("text/plain\r\nBcc: victim@example.com", Vec::new()).is_err());
+        assert!(Part::new("text", Vec::new()).is_err());
+        assert!(Part::new("text/", Vec::new()).is_err());
+        assert!(Part::new("text/plain/x", Vec::new()).is_err());
+        assert!(Part::text("plain\r\nBcc: victim@example.com", "hi").is_err());
+        assert!(Part::multipart("mixed; boundary=x", Vec::new()).is_err());
+        assert!(Part::attachment("a.txt", "text/plain\n", Vec::new()).is_err());
+        assert!(part.disposition("inline\r\nX-Injected: yes", None).is_err());
+        assert!(part.disposition("", None).is_err());
+        part.disposition("inline", Some("a\r\nb.txt")).unwrap();
+
+        let injected = Mailbox { name: None, addr: "a@example.com\r\nBcc: b@example.com".to_string() };
+        assert!(part.address_header("To", &[injected]).is_err());
+        let bad_name = Mailbox { name: Some("A".to_string()), addr: "a@example.com".to_string() };
+        assert!(part.address_header("To\n", &[bad_name]).is_err());
+
+        // Values are encoded rather than rejected.
+        part.header("Subject", "line\r\nBcc: b@example.com").unwrap();
+        let evil = Mailbox { name: Some("A\r\nBcc: b@example.com".to_string()), addr: "a@example.com".to_string() };
+        part.address_header("To", &[evil]).unwrap();
+        part.param("format", "a\r\nb").unwrap();
+        let msg = Message::parse(&part.to_bytes()).unwrap();
+        assert!(msg.headers().all("Bcc").is_empty());
+        assert!(msg.headers().all("X-Injected").is_empty());
+        assert_eq!(msg.filename().as_deref(), Some("a\r\nb.txt"));
+        assert_eq!(msg.headers().decoded("Subject", false), vec!["line\r\nBcc: b@example.com"]);
+        assert_eq!(msg.param("format").map(String::as_str), Some("a\r\nb"));
+    }
+}
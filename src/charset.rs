@@ -0,0 +1,344 @@
+use std::borrow::Cow;
+
+use base64::Engine;
+use encoding_rs::Encoding;
+
+
+/// Labels seen in mail that the WHATWG Encoding Standard does not know,
+/// mapped to the WHATWG label of the closest superset.
+const EXTRA_LABELS: &[(&str, &str)] = &[
+    ("iso-2022-jp-1", "iso-2022-jp"),
+    ("iso-2022-jp-2", "iso-2022-jp"),
+    ("iso-2022-jp-3", "iso-2022-jp"),
+    ("iso-2022-jp-2004", "iso-2022-jp"),
+    ("cp932", "shift_jis"),
+    ("ms932", "shift_jis"),
+    ("windows-31j", "shift_jis"),
+    ("cp936", "gbk"),
+    ("ms936", "gbk"),
+    ("cp949", "euc-kr"),
+    ("ms949", "euc-kr"),
+    ("uhc", "euc-kr"),
+    ("cp950", "big5"),
+    ("big5-hkscs", "big5"),
+    ("cp850", "windows-1252"),
+    ("cp437", "windows-1252"),
+    ("ansi_x3.4-1968", "windows-1252"),
+    ("ascii", "windows-1252"),
+    ("iso-8859-1:1987", "windows-1252"),
+    ("latin-1", "windows-1252"),
+    ("iso8859-1", "windows-1252"),
+    ("iso8859-15", "iso-8859-15"),
+    ("tis-620", "windows-874"),
+    ("cp874", "windows-874"),
+    ("cp1250", "windows-1250"),
+    ("cp1251", "windows-1251"),
+    ("cp1253", "windows-1253"),
+    ("cp1254", "windows-1254"),
+    ("cp1255", "windows-1255"),
+    ("cp1256", "windows-1256"),
+    ("cp1257", "windows-1257"),
+    ("cp1258", "windows-1258"),
+    ("cp866", "ibm866"),
+    ("koi8", "koi8-r"),
+    ("utf8", "utf-8"),
+    ("utf-8bit", "utf-8"),
+];
+
+
+/// A charset that text can be decoded from.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Charset {
+    Whatwg(&'static Encoding),
+    Utf7,
+}
+
+
+impl Charset {
+    /// Canonical name of the charset, e.g. "windows-1252".
+    pub fn name(&self) -> &'static str {
+        match self {
+            Charset::Whatwg(e) => e.name(),
+            Charset::Utf7 => "UTF-7",
+        }
+    }
+
+    /// Decode `bytes`, or None if they are not valid in this charset.
+    fn decode_strict<'a>(&self, bytes: &'a [u8]) -> Option<Cow<'a, str>> {
+        match self {
+            Charset::Whatwg(e) => e.decode_without_bom_handling_and_without_replacement(bytes),
+            Charset::Utf7 => {
+                let cs = charset::Charset::for_label(b"utf-7")?;
+                match cs.decode_without_bom_handling(bytes) {
+                    (s, false) => Some(s),
+                    (_, true) => None,
+                }
+            }
+        }
+    }
+
+    /// Decode `bytes`, replacing malformed sequences with U+FFFD.
+    fn decode_lossy<'a>(&self, bytes: &'a [u8]) -> Cow<'a, str> {
+        match self {
+            Charset::Whatwg(e) => e.decode_without_bom_handling(bytes).0,
+            Charset::Utf7 => match charset::Charset::for_label(b"utf-7") {
+                Some(cs) => cs.decode_without_bom_handling(bytes).0,
+                None => String::from_utf8_lossy(bytes),
+            }
+        }
+    }
+}
+
+
+/// Resolve a charset label from a Content-Type parameter or encoded-word.
+/// Quotes, whitespace and RFC 2231 language suffixes ("utf-8*en") are
+/// ignored. Labels that denote no charset at all ("unknown-8bit",
+/// "x-unknown") give None.
+pub fn lookup(label: &str) -> Option<Charset> {
+    let label = label.trim().trim_matches('"').trim();
+    let label = match label.find('*') {
+        Some(i) => &label[..i],
+        None => label
+    };
+    let lower = label.to_ascii_lowercase();
+
+    if lower == "utf-7" || lower == "utf7" || lower == "unicode-1-1-utf-7" {
+        return Some(Charset::Utf7);
+    }
+
+    if let Some(e) = Encoding::for_label_no_replacement(lower.as_bytes()) {
+        return Some(Charset::Whatwg(e));
+    }
+
+    for (extra, whatwg) in EXTRA_LABELS {
+        if lower == *extra {
+            return Encoding::for_label(whatwg.as_bytes()).map(Charset::Whatwg);
+        }
+    }
+
+    // "windows1252", "iso8859_2" and other separator typos.
+    let squashed: String = lower.chars().filter(|c| c.is_ascii_alphanumeric()).collect();
+    for candidate in [
+        squashed.replacen("windows", "windows-", 1),
+        squashed.replacen("iso8859", "iso-8859-", 1),
+    ] {
+        if let Some(e) = Encoding::for_label_no_replacement(candidate.as_bytes()) {
+            return Some(Charset::Whatwg(e));
+        }
+    }
+
+    None
+}
+
+
+/// Whether `label` names a 7-bit charset that shifts state with escape
+/// sequences (ISO-2022-*, HZ, UTF-7). Text in these is pure ASCII on the
+/// wire but not ASCII text, whether or not we can decode it.
+fn is_stateful(label: &str) -> bool {
+    match lookup(label) {
+        Some(Charset::Utf7) => return true,
+        Some(Charset::Whatwg(e)) if e == encoding_rs::ISO_2022_JP => return true,
+        _ => {}
+    }
+    let lower = label.trim().trim_matches('"').trim().to_ascii_lowercase();
+    lower.starts_with("iso-2022-") || lower.starts_with("iso2022") ||
+        lower.starts_with("csiso2022") || lower == "hz" || lower.starts_with("hz-")
+}
+
+
+/// Guess the charset of `bytes`, for text with no usable declaration.
+pub fn detect(bytes: &[u8]) -> Charset {
+    if std::str::from_utf8(bytes).is_ok() {
+        return Charset::Whatwg(encoding_rs::UTF_8);
+    }
+
+    let mut detector = chardetng::EncodingDetector::new();
+    detector.feed(bytes, true);
+    Charset::Whatwg(detector.guess(None, true))
+}
+
+
+#[derive(Clone, Debug)]
+pub struct DecodedText {
+    pub text: String,
+    /// Name of the charset the text was actually decoded from.
+    pub charset: &'static str,
+    /// True if the charset was guessed rather than taken from the
+    /// declaration.
+    pub detected: bool,
+}
+
+
+/// Decode `bytes` from the `declared` charset label.
+///
+/// Text that is pure ASCII decodes the same in every charset except the
+/// stateful 7-bit ones, and is never sniffed. Otherwise, with `sniff` set, the detector is consulted
+/// when no charset is declared, the label is unknown, or the text is not
+/// valid in the declared charset. Without it, undeclared and unknown
+/// charsets decode as windows-1252 (the WHATWG reading of "us-ascii", the
+/// RFC 2045 default) and invalid sequences become U+FFFD.
+pub fn decode(bytes: &[u8], declared: Option<&str>, sniff: bool) -> DecodedText {
+    let charset = declared.and_then(lookup);
+
+    if bytes.is_ascii() && !declared.is_some_and(is_stateful) {
+        return DecodedText {
+            // ASCII is valid UTF-8.
+            text: String::from_utf8_lossy(bytes).into_owned(),
+            charset: charset.map(|c| c.name()).unwrap_or("us-ascii"),
+            detected: false,
+        };
+    }
+
+    if let Some(cs) = charset {
+        if let Some(text) = cs.decode_strict(bytes) {
+            return DecodedText {
+                text: text.into_owned(),
+                charset: cs.name(),
+                detected: false,
+            };
+        }
+    }
+
+    if sniff {
+        let cs = detect(bytes);
+        return DecodedText {
+            text: cs.decode_lossy(bytes).into_owned(),
+            charset: cs.name(),
+            detected: true,
+        };
+    }
+
+    let cs = charset.unwrap_or(Charset::Whatwg(encoding_rs::WINDOWS_1252));
+    DecodedText {
+        text: cs.decode_lossy(bytes).into_owned(),
+        charset: cs.name(),
+        detected: false,
+    }
+}
+
+
+/// Decode the payload of one encoded-word, or None if it is malformed.
+fn decode_word(charset: &str, encoding: &str, text: &str) -> Option<String> {
+    let bytes = match encoding {
+        "b" | "B" => {
+            let clean: String = text.chars().filter(|c| !c.is_whitespace()).collect();
+            let clean = clean.trim_end_matches('=');
+            base64::engine::general_purpose::STANDARD_NO_PAD.decode(clean).ok()?
+        },
+        "q" | "Q" => quoted_printable::decode(
+            text.replace('_', " "),
+            quoted_printable::ParseMode::Robust
+        ).ok()?,
+        _ => return None
+    };
+
+    let cs = lookup(charset).unwrap_or(Charset::Whatwg(encoding_rs::WINDOWS_1252));
+    Some(cs.decode_lossy(&bytes).into_owned())
+}
+
+
+/// Split "=?charset?enc?text?=" at the start of `s`, returning the decoded
+/// word and the number of bytes it occupied.
+fn encoded_word(s: &str) -> Option<(String, usize)> {
+    let rest = s.strip_prefix("=?")?;
+    let q1 = rest.find('?')?;
+    let charset = &rest[..q1];
+    let rest2 = &rest[q1+1..];
+    let q2 = rest2.find('?')?;
+    let encoding = &rest2[..q2];
+    let rest3 = &rest2[q2+1..];
+    let end = rest3.find("?=")?;
+    let text = &rest3[..end];
+
+    if charset.is_empty() || text.contains(char::is_whitespace) && encoding.eq_ignore_ascii_case("q") {
+        return None;
+    }
+
+    let word = decode_word(charset, encoding, text)?;
+    Some((word, 2 + q1 + 1 + q2 + 1 + end + 2))
+}
+
+
+/// Decode a raw (possibly folded) header value: unfold it, decode RFC 2047
+/// encoded-words in any supported charset, and interpret raw 8-bit bytes as
+/// UTF-8 if valid, else as the sniffed charset (`sniff`) or windows-1252.
+pub fn decode_header(raw: &[u8], sniff: bool) -> String {
+    let unfolded: Vec<u8> = raw.iter()
+        .filter(|&&b| b != b'\r' && b != b'\n')
+        .cloned()
+        .collect();
+
+    let value = match std::str::from_utf8(&unfolded) {
+        Ok(s) => s.to_string(),
+        Err(_) => decode(&unfolded, None, sniff).text
+    };
+
+    let mut out = String::with_capacity(value.len());
+    let mut pending_ws = String::new();
+    let mut last_was_word = false;
+    let mut rest = value.as_str();
+
+    while !rest.is_empty() {
+        if rest.starts_with("=?") {
+            if let Some((word, used)) = encoded_word(rest) {
+                // Whitespace between adjacent encoded-words is dropped.
+                if !last_was_word {
+                    out.push_str(&pending_ws);
+                }
+                pending_ws.clear();
+                out.push_str(&word);
+                last_was_word = true;
+                rest = &rest[used..];
+                continue;
+            }
+        }
+
+        let c = rest.chars().next().unwrap();
+        if c == ' ' || c == '\t' {
+            pending_ws.push(c);
+        } else {
+            out.push_str(&pending_ws);
+            pending_ws.clear();
+            out.push(c);
+            last_was_word = false;
+        }
+        rest = &rest[c.len_utf8()..];
+    }
+
+    out.push_str(&pending_ws);
+    out.trim().to_string()
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn ascii_fast_path() {
+        let d = decode(b"plain text", Some("iso-8859-1"), true);
+        assert_eq!(d.text, "plain text");
+        assert_eq!(d.charset, "windows-1252");
+        assert!(!d.detected);
+
+        let d = decode(b"plain text", None, true);
+        assert_eq!(d.charset, "us-ascii");
+    }
+
+    #[test]
+    fn stateful_charsets_are_decoded() {
+        // "日本" in ISO-2022-JP.
+        let d = decode(b"\x1b$BF|K\\\x1b(B", Some("ISO-2022-JP"), false);
+        assert_eq!(d.text, "\u{65e5}\u{672c}");
+        assert_eq!(d.charset, "ISO-2022-JP");
+
+        let d = decode(b"Hi Mom -+Jjo--!", Some("utf-7"), false);
+        assert_eq!(d.text, "Hi Mom -\u{263a}-!");
+        assert_eq!(d.charset, "UTF-7");
+
+        assert!(is_stateful("iso-2022-kr"));
+        assert!(is_stateful("HZ-GB-2312"));
+        assert!(is_stateful("csISO2022JP"));
+        assert!(!is_stateful("utf-8"));
+    }
+}
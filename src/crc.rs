@@ -0,0 +1,44 @@
+/// CRC-32 with the reflected polynomial 0xedb88320, as used by zlib, yEnc
+/// and compressed RTF.
+const TABLE: [u32; 256] = {
+    let mut table = [0u32; 256];
+    let mut i = 0;
+    while i < 256 {
+        let mut crc = i as u32;
+        let mut bit = 0;
+        while bit < 8 {
+            crc = if crc & 1 != 0 { (crc >> 1) ^ 0xedb88320 } else { crc >> 1 };
+            bit += 1;
+        }
+        table[i] = crc;
+        i += 1;
+    }
+    table
+};
+
+
+/// Continue `crc` over `data`, without the pre- and post-inversion most
+/// users of the polynomial add: compressed RTF starts from 0 and uses the
+/// result as is.
+pub(crate) fn update(crc: u32, data: &[u8]) -> u32 {
+    data.iter().fold(crc, |crc, &b| TABLE[((crc ^ b as u32) & 0xff) as usize] ^ (crc >> 8))
+}
+
+
+/// The usual CRC-32, as zlib and yEnc compute it.
+pub(crate) fn crc32(data: &[u8]) -> u32 {
+    !update(!0, data)
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn check_values() {
+        assert_eq!(crc32(b"123456789"), 0xcbf43926);
+        assert_eq!(crc32(b""), 0);
+        assert_eq!(update(0, b""), 0);
+    }
+}
@@ -0,0 +1,443 @@
+/// A Date header value broken into its fields.
+#[derive(Clone, Debug, PartialEq)]
+pub struct ParsedDate {
+    pub year: i32,
+    pub month: u32,
+    pub day: u32,
+    pub hour: u32,
+    pub minute: u32,
+    pub second: u32,
+    /// Offset from UTC in seconds, east positive.
+    pub offset: i32,
+    /// True if the value was not valid RFC 5322 syntax and had to be
+    /// interpreted: two-digit years, obsolete or non-standard zone names,
+    /// missing zones, ISO 8601 or asctime() layouts, out of range fields.
+    pub heuristic: bool,
+}
+
+
+const MONTHS: [&str; 12] = [
+    "jan", "feb", "mar", "apr", "may", "jun",
+    "jul", "aug", "sep", "oct", "nov", "dec",
+];
+
+const WEEKDAYS: [&str; 7] = ["mon", "tue", "wed", "thu", "fri", "sat", "sun"];
+
+
+/// Named zones seen in the wild, in minutes east of UTC. Only UT and GMT are
+/// valid (obsolete) RFC 5322 names without being considered heuristic.
+const ZONES: &[(&str, i32)] = &[
+    ("ut", 0), ("gmt", 0), ("utc", 0), ("z", 0), ("wet", 0),
+    ("est", -300), ("edt", -240), ("cst", -360), ("cdt", -300),
+    ("mst", -420), ("mdt", -360), ("pst", -480), ("pdt", -420),
+    ("akst", -540), ("akdt", -480), ("hst", -600),
+    ("bst", 60), ("cet", 60), ("met", 60), ("west", 60),
+    ("cest", 120), ("mest", 120), ("eet", 120), ("eest", 180),
+    ("msk", 180), ("ist", 330), ("sgt", 480), ("hkt", 480),
+    ("awst", 480), ("jst", 540), ("kst", 540), ("acst", 570),
+    ("aest", 600), ("aedt", 660), ("nzst", 720), ("nzdt", 780),
+];
+
+
+fn strip_comments(s: &str) -> String {
+    let mut out = String::with_capacity(s.len());
+    let mut depth = 0;
+    let mut escaped = false;
+
+    for c in s.chars() {
+        if depth > 0 {
+            if escaped {
+                escaped = false;
+            } else if c == '\\' {
+                escaped = true;
+            } else if c == '(' {
+                depth += 1;
+            } else if c == ')' {
+                depth -= 1;
+                if depth == 0 {
+                    out.push(' ');
+                }
+            }
+        } else if c == '(' {
+            depth = 1;
+        } else {
+            out.push(c);
+        }
+    }
+
+    out
+}
+
+
+fn month_number(s: &str) -> Option<u32> {
+    let s = s.to_ascii_lowercase();
+    if s.len() < 3 {
+        return None;
+    }
+    MONTHS.iter().position(|m| s.starts_with(m)).map(|i| i as u32 + 1)
+}
+
+
+fn is_weekday(s: &str) -> bool {
+    let s = s.to_ascii_lowercase();
+    s.len() >= 3 && WEEKDAYS.iter().any(|d| s.starts_with(d))
+}
+
+
+/// Parse "+hhmm", "-hh:mm", "+h" style numeric zones into seconds east,
+/// and whether the form was non-standard.
+fn numeric_zone(s: &str) -> Option<(i32, bool)> {
+    let sign = match s.chars().next()? {
+        '+' => 1,
+        '-' => -1,
+        _ => return None
+    };
+
+    let digits: String = s[1..].chars().filter(|c| *c != ':').collect();
+    if digits.is_empty() || !digits.chars().all(|c| c.is_ascii_digit()) {
+        return None;
+    }
+
+    let (hours, minutes, odd) = match digits.len() {
+        1 | 2 => (digits.parse::<i32>().ok()?, 0, true),
+        3 => (digits[..1].parse::<i32>().ok()?, digits[1..].parse::<i32>().ok()?, true),
+        4 => (digits[..2].parse::<i32>().ok()?, digits[2..].parse::<i32>().ok()?,
+              s.contains(':')),
+        _ => return None
+    };
+
+    if hours > 23 || minutes > 59 {
+        return None;
+    }
+
+    Some((sign * (hours * 3600 + minutes * 60), odd))
+}
+
+
+/// Parse a zone token into seconds east and whether it was heuristic.
+fn zone(s: &str) -> Option<(i32, bool)> {
+    if let Some(z) = numeric_zone(s) {
+        return Some(z);
+    }
+
+    let lower = s.to_ascii_lowercase();
+
+    // "GMT+0100", "UTC-5"
+    for prefix in ["gmt", "utc", "ut"] {
+        if let Some(rest) = lower.strip_prefix(prefix) {
+            if rest.starts_with('+') || rest.starts_with('-') {
+                return numeric_zone(rest).map(|(o, _)| (o, true));
+            }
+        }
+    }
+
+    for (name, minutes) in ZONES {
+        if lower == *name {
+            return Some((minutes * 60, lower != "ut" && lower != "gmt"));
+        }
+    }
+
+    // RFC 5322 4.3: single-letter military zones were defined backwards in
+    // RFC 822 and should be treated as unknown, i.e. UTC.
+    if lower.len() == 1 && lower.chars().all(|c| c.is_ascii_alphabetic()) {
+        return Some((0, true));
+    }
+
+    None
+}
+
+
+/// Parse "hh:mm[:ss]", with an optional fractional second.
+fn time(s: &str) -> Option<(u32, u32, u32)> {
+    let mut it = s.split(':');
+    let hour = it.next()?.parse::<u32>().ok()?;
+    let minute = it.next()?.parse::<u32>().ok()?;
+    let second = match it.next() {
+        None => 0,
+        Some(sec) => sec.split('.').next()?.parse::<u32>().ok()?
+    };
+
+    if it.next().is_some() {
+        return None;
+    }
+
+    Some((hour, minute, second))
+}
+
+
+fn days_in_month(year: i32, month: u32) -> u32 {
+    match month {
+        4 | 6 | 9 | 11 => 30,
+        2 if (year % 4 == 0 && year % 100 != 0) || year % 400 == 0 => 29,
+        2 => 28,
+        _ => 31
+    }
+}
+
+
+/// Days since 1970-01-01 for a proleptic Gregorian date.
+/// http://howardhinnant.github.io/date_algorithms.html#days_from_civil
+fn days_from_civil(year: i32, month: u32, day: u32) -> i64 {
+    let y = if month <= 2 { year as i64 - 1 } else { year as i64 };
+    let era = if y >= 0 { y } else { y - 399 } / 400;
+    let yoe = y - era * 400;
+    let m = month as i64;
+    let doy = (153 * (if m > 2 { m - 3 } else { m + 9 }) + 2) / 5 + day as i64 - 1;
+    let doe = yoe * 365 + yoe / 4 - yoe / 100 + doy;
+    era * 146097 + doe - 719468
+}
+
+
+impl ParsedDate {
+    /// Seconds since the Unix epoch.
+    pub fn timestamp(&self) -> i64 {
+        let days = days_from_civil(self.year, self.month, self.day);
+        days * 86400
+            + (self.hour * 3600 + self.minute * 60 + self.second) as i64
+            - self.offset as i64
+    }
+}
+
+
+/// Year, month and day.
+type Ymd = (i32, u32, u32);
+
+
+/// ISO 8601 "2023-06-05", "2023-06-05T10:00:00Z", "2023-06-05T10:00+02:00".
+fn iso_date(token: &str) -> Option<(Ymd, Option<&str>)> {
+    let (date, rest) = match token.find(['T', 't']) {
+        Some(i) => (&token[..i], Some(&token[i+1..])),
+        None => (token, None)
+    };
+
+    let mut it = date.split('-');
+    let year = it.next()?;
+    if year.len() != 4 {
+        return None;
+    }
+    let year = year.parse::<i32>().ok()?;
+    let month = it.next()?.parse::<u32>().ok()?;
+    let day = it.next()?.parse::<u32>().ok()?;
+    if it.next().is_some() {
+        return None;
+    }
+
+    Some(((year, month, day), rest))
+}
+
+
+/// Leniently parse an RFC 5322 date-time. Returns None if no date could be
+/// recovered at all.
+pub fn parse_date(s: &str) -> Option<ParsedDate> {
+    let cleaned = strip_comments(s);
+    let mut heuristic = false;
+
+    let mut tokens: Vec<String> = cleaned
+        .split(|c: char| c.is_whitespace() || c == ',')
+        .filter(|t| !t.is_empty())
+        .map(|t| t.to_string())
+        .collect();
+
+    let mut year = None;
+    let mut month = None;
+    let mut day = None;
+    let mut hms = None;
+    let mut offset = None;
+    // Whether the day came after the month, as in asctime() output.
+    let mut month_first = false;
+
+    // Split ISO 8601 tokens into their date, time and zone pieces.
+    if let Some(i) = tokens.iter().position(|t| iso_date(t).is_some()) {
+        let ((y, m, d), rest) = iso_date(&tokens[i]).unwrap();
+        year = Some(y);
+        month = Some(m);
+        day = Some(d);
+        heuristic = true;
+
+        if let Some(rest) = rest {
+            let split = rest.find(['+', '-', 'Z', 'z']);
+            let (t, z) = match split {
+                Some(j) => (&rest[..j], Some(&rest[j..])),
+                None => (rest, None)
+            };
+            hms = time(t);
+            if let Some(z) = z {
+                offset = zone(z).map(|(o, _)| o);
+            }
+        }
+        tokens.remove(i);
+    }
+
+    for token in &tokens {
+        if is_weekday(token) && month_number(token).is_none() {
+            continue;
+        }
+
+        if let Some(m) = month_number(token) {
+            if month.is_none() {
+                month = Some(m);
+                month_first = day.is_none();
+                if token.len() != 3 {
+                    heuristic = true;
+                }
+                continue;
+            }
+        }
+
+        if token.contains(':') && hms.is_none() {
+            if let Some(t) = time(token) {
+                hms = Some(t);
+                continue;
+            }
+        }
+
+        if token.chars().all(|c| c.is_ascii_digit()) {
+            let n = token.parse::<i32>().ok()?;
+            if day.is_none() && token.len() <= 2 && (month.is_none() || month_first) {
+                day = Some(n as u32);
+                continue;
+            }
+            if year.is_none() {
+                year = Some(match token.len() {
+                    // RFC 5322 4.3 obs-year interpretation.
+                    1 | 2 => {
+                        heuristic = true;
+                        if n < 50 { 2000 + n } else { 1900 + n }
+                    },
+                    3 => {
+                        heuristic = true;
+                        1900 + n
+                    },
+                    _ => n
+                });
+                continue;
+            }
+        }
+
+        if offset.is_none() {
+            if let Some((o, odd)) = zone(token) {
+                offset = Some(o);
+                heuristic |= odd;
+                continue;
+            }
+        }
+
+        // Unrecognised junk.
+        heuristic = true;
+    }
+
+    if month_first {
+        heuristic = true;
+    }
+
+    let year = year?;
+    let month = month?;
+    let day = day?;
+    // Years outside 1-9999 are not representable by most date types.
+    if !(1..=9999).contains(&year) {
+        return None;
+    }
+    if month == 0 || month > 12 || day == 0 || day > days_in_month(year, month) {
+        return None;
+    }
+
+    let (hour, minute, mut second) = match hms {
+        Some(t) => t,
+        None => {
+            heuristic = true;
+            (0, 0, 0)
+        }
+    };
+
+    if hour > 23 || minute > 59 || second > 60 {
+        return None;
+    }
+
+    if second == 60 {
+        // Leap second; datetime types cannot represent it.
+        second = 59;
+        heuristic = true;
+    }
+
+    let offset = match offset {
+        Some(o) => o,
+        None => {
+            heuristic = true;
+            0
+        }
+    };
+
+    Some(ParsedDate {
+        year: year,
+        month: month,
+        day: day,
+        hour: hour,
+        minute: minute,
+        second: second,
+        offset: offset,
+        heuristic: heuristic,
+    })
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// `(year, month, day, hour, minute, second, offset, heuristic)`.
+    type Fields = (i32, u32, u32, u32, u32, u32, i32, bool);
+
+    /// Inputs and what `parse_date` makes of them; None where no date
+    /// should be recovered.
+    const DATES: &[(&str, Option<Fields>)] = &[
+        ("Mon, 05 Jun 2023 10:20:30 +0200", Some((2023, 6, 5, 10, 20, 30, 7200, false))),
+        // Obsolete syntax RFC 5322 4.3 still defines.
+        ("5 Jun 23 10:20:30 +0000", Some((2023, 6, 5, 10, 20, 30, 0, true))),
+        ("5 Jun 99 10:20:30 +0000", Some((1999, 6, 5, 10, 20, 30, 0, true))),
+        ("5 Jun 2023 10:20:30 UT", Some((2023, 6, 5, 10, 20, 30, 0, false))),
+        ("5 Jun 2023 10:20:30 GMT", Some((2023, 6, 5, 10, 20, 30, 0, false))),
+        ("5 Jun 2023 10:20:30 EST", Some((2023, 6, 5, 10, 20, 30, -18000, true))),
+        ("5 Jun 2023 10:20:30 A", Some((2023, 6, 5, 10, 20, 30, 0, true))),
+        // Seconds are optional, and comments ignored.
+        ("Mon, 5 Jun 2023 10:20 -0700", Some((2023, 6, 5, 10, 20, 0, -25200, false))),
+        ("Mon, 5 Jun 2023 10:20:30 +0000 (UTC)", Some((2023, 6, 5, 10, 20, 30, 0, false))),
+        ("Mon, 5 Jun 2023 10:20:30 +0000 (a (nested) comment)",
+         Some((2023, 6, 5, 10, 20, 30, 0, false))),
+        // Layouts that are not RFC 5322 at all.
+        ("2023-06-05T10:20:30Z", Some((2023, 6, 5, 10, 20, 30, 0, true))),
+        ("2023-06-05T10:20:30+02:00", Some((2023, 6, 5, 10, 20, 30, 7200, true))),
+        ("2023-06-05", Some((2023, 6, 5, 0, 0, 0, 0, true))),
+        ("Mon Jun  5 10:20:30 2023", Some((2023, 6, 5, 10, 20, 30, 0, true))),
+        ("5 June 2023 10:20:30 +0000", Some((2023, 6, 5, 10, 20, 30, 0, true))),
+        ("5 Jun 2023 10:20:30 GMT+0100", Some((2023, 6, 5, 10, 20, 30, 3600, true))),
+        ("5 Jun 2023 10:20:30", Some((2023, 6, 5, 10, 20, 30, 0, true))),
+        ("5 Jun 2023 10:20:30 +0000 junk", Some((2023, 6, 5, 10, 20, 30, 0, true))),
+        ("30 Jun 2023 23:59:60 +0000", Some((2023, 6, 30, 23, 59, 59, 0, true))),
+        // Out of range.
+        ("5 Jun 10000 10:20:30 +0000", None),
+        ("5 Jun 2023 25:00:00 +0000", None),
+        ("5 Jun 2023 10:60:00 +0000", None),
+        ("29 Feb 2023 10:20:30 +0000", None),
+        ("2023-13-05T10:20:30Z", None),
+        ("Jun 2023", None),
+        ("", None),
+    ];
+
+    #[test]
+    fn date_table() {
+        for (input, expected) in DATES {
+            let fields = parse_date(input).map(|d| {
+                (d.year, d.month, d.day, d.hour, d.minute, d.second, d.offset, d.heuristic)
+            });
+            assert_eq!(&fields, expected, "{:?}", input);
+        }
+    }
+
+    #[test]
+    fn timestamp_applies_offset() {
+        let d = parse_date("Thu, 1 Jan 1970 01:00:00 +0100").unwrap();
+        assert_eq!(d.timestamp(), 0);
+        let d = parse_date("29 Feb 2024 12:00:00 -0500").unwrap();
+        assert_eq!(d.timestamp(), 1709226000);
+    }
+}
@@ -0,0 +1,221 @@
+use crate::message::Message;
+use crate::message::slice_offset;
+
+
+/// RFC 5322 3.6 and RFC 2045 fields that may appear at most once.
+const SINGLETON_HEADERS: &[&str] = &[
+    "Date", "From", "Sender", "Reply-To", "To", "Cc", "Bcc", "Message-ID",
+    "In-Reply-To", "References", "Subject", "MIME-Version", "Content-Type",
+    "Content-Transfer-Encoding", "Content-ID", "Content-Disposition",
+];
+
+
+/// RFC 5322 2.1.1 limit, excluding the line ending.
+const MAX_LINE_LENGTH: usize = 998;
+
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum DefectKind {
+    /// A line that cannot start a header field, at which lenient parsing
+    /// ended the part's header block; only produced in lenient mode.
+    UnparseableHeaders,
+    MissingBoundaryTerminator,
+    InvalidBase64,
+    BareCr,
+    EightBitHeader,
+    LongLine,
+    DuplicateHeader,
+}
+
+
+impl DefectKind {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            DefectKind::UnparseableHeaders => "unparseable_headers",
+            DefectKind::MissingBoundaryTerminator => "missing_boundary_terminator",
+            DefectKind::InvalidBase64 => "invalid_base64",
+            DefectKind::BareCr => "bare_cr",
+            DefectKind::EightBitHeader => "8bit_header",
+            DefectKind::LongLine => "long_line",
+            DefectKind::DuplicateHeader => "duplicate_header",
+        }
+    }
+}
+
+
+#[derive(Clone, Debug)]
+pub struct Defect {
+    pub kind: DefectKind,
+    /// Offset into `Message::buffer()` of the first offending byte.
+    pub offset: usize,
+    pub detail: String,
+}
+
+
+impl Defect {
+    pub fn new(kind: DefectKind, offset: usize, detail: String) -> Defect {
+        Defect {
+            kind: kind,
+            offset: offset,
+            detail: detail,
+        }
+    }
+}
+
+
+fn check_lines(bytes: &[u8], base: usize, out: &mut Vec<Defect>) {
+    let mut pos = 0;
+    for line in bytes.split_inclusive(|&b| b == b'\n') {
+        let content = line.strip_suffix(b"\n").unwrap_or(line);
+        let content = content.strip_suffix(b"\r").unwrap_or(content);
+        if content.len() > MAX_LINE_LENGTH {
+            out.push(Defect::new(DefectKind::LongLine, base + pos,
+                                 format!("line of {} octets", content.len())));
+        }
+
+        if let Some(i) = content.iter().position(|&b| b == b'\r') {
+            out.push(Defect::new(DefectKind::BareCr, base + pos + i,
+                                 "CR not followed by LF".to_string()));
+        }
+        pos += line.len();
+    }
+}
+
+
+fn check_headers(msg: &Message, out: &mut Vec<Defect>) {
+    let headers = msg.headers();
+    let fields = headers.fields();
+
+    let block_offset = headers.offset()[0];
+    check_lines(headers.raw_bytes(), block_offset, out);
+
+    for (i, field) in fields.iter().enumerate() {
+        if let Some(j) = field.raw_value.iter().position(|&b| b >= 0x80) {
+            let offset = slice_offset(msg.buffer(), field.raw_value) + j;
+            out.push(Defect::new(DefectKind::EightBitHeader, offset,
+                                 field.name.clone()));
+        }
+
+        let singleton = SINGLETON_HEADERS.iter()
+            .any(|h| h.eq_ignore_ascii_case(&field.name));
+        let repeated = fields[..i].iter()
+            .any(|f| f.name.eq_ignore_ascii_case(&field.name));
+        if singleton && repeated {
+            out.push(Defect::new(DefectKind::DuplicateHeader, field.offset[0],
+                                 field.name.clone()));
+        }
+    }
+}
+
+
+fn check_boundary(msg: &Message, out: &mut Vec<Defect>) {
+    let boundary = match msg.param("boundary") {
+        Some(b) => b,
+        None => return
+    };
+
+    let body = msg.whole_body();
+    let terminator = format!("--{}--", boundary);
+    let found = body.split(|&b| b == b'\n')
+        .any(|line| line.starts_with(terminator.as_bytes()));
+    if !found {
+        let offset = slice_offset(msg.buffer(), body) + body.len();
+        out.push(Defect::new(DefectKind::MissingBoundaryTerminator, offset,
+                             boundary.clone()));
+    }
+}
+
+
+fn check_base64(msg: &Message, out: &mut Vec<Defect>) {
+    let body = msg.body_encoded();
+    let base = slice_offset(msg.buffer(), body);
+
+    let mut count = 0;
+    let mut padding = 0;
+    for (i, &b) in body.iter().enumerate() {
+        let valid = match b {
+            b'A'..=b'Z' | b'a'..=b'z' | b'0'..=b'9' | b'+' | b'/' => padding == 0,
+            b'=' => { padding += 1; padding <= 2 },
+            b' ' | b'\t' | b'\r' | b'\n' => continue,
+            _ => false
+        };
+        if !valid {
+            out.push(Defect::new(DefectKind::InvalidBase64, base + i,
+                                 format!("unexpected byte 0x{:02x}", b)));
+            return;
+        }
+        count += 1;
+    }
+
+    if count % 4 != 0 {
+        out.push(Defect::new(DefectKind::InvalidBase64, base + body.len(),
+                             "truncated input".to_string()));
+    }
+}
+
+
+/// Look for defects in `msg` itself, not its subparts: bare CRs and lines
+/// over 998 octets in its header block or (for leaf parts) its body, 8-bit
+/// header values, repeated singleton fields, a multipart body without a
+/// closing delimiter, and malformed base64.
+pub fn check(msg: &Message) -> Vec<Defect> {
+    let mut out = Vec::new();
+    check_headers(msg, &mut out);
+
+    if msg.mime_type().to_ascii_lowercase().starts_with("multipart/") {
+        check_boundary(msg, &mut out);
+    } else if msg.part().subparts.is_empty() && msg.embedded_message().is_none() {
+        // An embedded message's body is checked as its own parts. Files
+        // decoded out of a text or TNEF body are not parts of the message
+        // as sent, so that body is still checked here.
+        let body = msg.body_encoded();
+        check_lines(body, slice_offset(msg.buffer(), body), &mut out);
+        if msg.body_encoding() == "base64" {
+            check_base64(msg, &mut out);
+        }
+    }
+
+    out
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn kinds(msg: &Message) -> Vec<DefectKind> {
+        msg.defects().iter().map(|d| d.kind).collect()
+    }
+
+    #[test]
+    fn text_with_embedded_files_is_checked() {
+        let buf = b"Content-Type: text/plain\r\n\r\nbare\rcr\r\n\
+begin 644 hi.txt\r\n#:&D*\r\n`\r\nend\r\n";
+        let msg = Message::parse(buf).unwrap();
+        assert_eq!(msg.subpart_count(), 1);
+        let defects = msg.defects();
+        assert_eq!(defects.len(), 1);
+        assert_eq!(defects[0].kind, DefectKind::BareCr);
+        assert_eq!(defects[0].offset, 32);
+    }
+
+    #[test]
+    fn embedded_message_body_is_checked_once() {
+        let buf = b"Content-Type: message/rfc822\r\n\r\nSubject: inner\r\n\r\nbare\rcr\r\n";
+        let msg = Message::parse(buf).unwrap();
+        assert!(kinds(&msg).is_empty());
+        assert_eq!(kinds(&msg.embedded_message().unwrap()), vec![DefectKind::BareCr]);
+    }
+
+    #[test]
+    fn boundary_terminator() {
+        let buf = b"Content-Type: multipart/mixed; boundary=b\r\n\r\n--b\r\n\r\none\r\n--b--\r\n";
+        assert!(kinds(&Message::parse(buf).unwrap()).is_empty());
+
+        let buf = b"Content-Type: multipart/mixed; boundary=b\r\n\r\n--b\r\n\r\none\r\n";
+        let defects = Message::parse(buf).unwrap().defects();
+        assert_eq!(defects.len(), 1);
+        assert_eq!(defects[0].kind, DefectKind::MissingBoundaryTerminator);
+        assert_eq!(defects[0].offset, buf.len());
+    }
+}
@@ -19,6 +19,11 @@ pub struct Deferral {
 
 
 impl DeferralKind {
+    /// Whether the deferred data is a URL, as opposed to CSS text.
+    pub fn is_url(&self) -> bool {
+        !matches!(self, DeferralKind::StyleInline | DeferralKind::StyleAttr)
+    }
+
     pub fn as_str(&self) -> &'static str {
         match self {
             DeferralKind::ImageLink => "ImageLink",
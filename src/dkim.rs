@@ -0,0 +1,603 @@
+//! DKIM signature verification (RFC 6376, with the algorithm updates of
+//! RFC 8301 and RFC 8463). Key lookup is left to a caller-supplied
+//! resolver so that no DNS access happens here.
+
+use std::collections::HashMap;
+use std::time::SystemTime;
+use std::time::UNIX_EPOCH;
+
+use base64::Engine;
+use ed25519_dalek::Verifier;
+use rsa::pkcs1::DecodeRsaPublicKey;
+use rsa::pkcs8::DecodePublicKey;
+use sha2::Digest;
+use sha2::Sha256;
+
+use crate::message::HeaderField;
+use crate::message::Message;
+
+
+/// RFC 8301 3.2: smaller RSA keys must not be accepted.
+const MIN_RSA_BITS: usize = 1024;
+
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum DkimStatus {
+    Pass,
+    Fail,
+    /// The signature or key is unusable (RFC 6376 PERMFAIL).
+    PermError,
+    /// The key could not be retrieved (RFC 6376 TEMPFAIL).
+    TempError,
+}
+
+
+impl DkimStatus {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            DkimStatus::Pass => "pass",
+            DkimStatus::Fail => "fail",
+            DkimStatus::PermError => "permerror",
+            DkimStatus::TempError => "temperror",
+        }
+    }
+}
+
+
+#[derive(Clone, Debug)]
+pub struct DkimResult {
+    pub domain: Option<String>,
+    pub selector: Option<String>,
+    pub algorithm: Option<String>,
+    pub status: DkimStatus,
+    /// Why the signature did not pass, or empty.
+    pub reason: String,
+    /// Span of the DKIM-Signature field in `Message::buffer()`.
+    pub offset: [usize; 2],
+}
+
+
+/// Parse a DKIM tag-list ("v=1; a=rsa-sha256; ...") into tag names and
+/// values with surrounding whitespace removed. Tag names are case-sensitive
+/// (RFC 6376 3.2) and kept as written, so "B=" is not "b=".
+pub fn parse_tags(value: &str) -> Option<HashMap<String, String>> {
+    let mut tags = HashMap::new();
+    for spec in value.split(';') {
+        if spec.trim().is_empty() {
+            continue;
+        }
+        let eq = spec.find('=')?;
+        let name = spec[..eq].trim().to_string();
+        let value = spec[eq+1..].trim().to_string();
+        if name.is_empty() || tags.insert(name, value).is_some() {
+            return None;
+        }
+    }
+    Some(tags)
+}
+
+
+fn strip_whitespace(s: &str) -> String {
+    s.chars().filter(|c| !c.is_whitespace()).collect()
+}
+
+
+/// Split `bytes` into lines on LF, dropping a CR before each LF, so that
+/// mail stored with either line ending canonicalizes the same.
+fn split_lines(bytes: &[u8]) -> Vec<&[u8]> {
+    let mut lines: Vec<&[u8]> = bytes.split(|&b| b == b'\n')
+        .map(|l| l.strip_suffix(b"\r").unwrap_or(l))
+        .collect();
+    if bytes.ends_with(b"\n") {
+        lines.pop();
+    }
+    lines
+}
+
+
+/// Collapse runs of spaces and tabs to a single space.
+fn compress_wsp(line: &[u8]) -> Vec<u8> {
+    let mut out = Vec::with_capacity(line.len());
+    let mut in_wsp = false;
+    for &b in line {
+        if b == b' ' || b == b'\t' {
+            if !in_wsp {
+                out.push(b' ');
+            }
+            in_wsp = true;
+        } else {
+            out.push(b);
+            in_wsp = false;
+        }
+    }
+    out
+}
+
+
+/// RFC 6376 3.4.3 and 3.4.4.
+pub fn canonicalize_body(body: &[u8], relaxed: bool) -> Vec<u8> {
+    let mut lines: Vec<Vec<u8>> = split_lines(body).into_iter()
+        .map(|l| {
+            if relaxed {
+                let mut l = compress_wsp(l);
+                while l.last() == Some(&b' ') {
+                    l.pop();
+                }
+                l
+            } else {
+                l.to_vec()
+            }
+        })
+        .collect();
+
+    while lines.last().is_some_and(|l| l.is_empty()) {
+        lines.pop();
+    }
+
+    if lines.is_empty() {
+        return if relaxed { Vec::new() } else { b"\r\n".to_vec() };
+    }
+
+    let mut out = Vec::with_capacity(body.len() + lines.len());
+    for l in lines {
+        out.extend_from_slice(&l);
+        out.extend_from_slice(b"\r\n");
+    }
+    out
+}
+
+
+/// RFC 6376 3.4.1 and 3.4.2, applied to a whole field as it appears in the
+/// message. The result ends in CRLF.
+pub fn canonicalize_header(raw: &[u8], relaxed: bool) -> Vec<u8> {
+    let lines = split_lines(raw);
+    if !relaxed {
+        let mut out = lines.join(&b"\r\n"[..]);
+        out.extend_from_slice(b"\r\n");
+        return out;
+    }
+
+    let unfolded: Vec<u8> = lines.concat();
+    let colon = unfolded.iter().position(|&b| b == b':').unwrap_or(unfolded.len());
+    let name: Vec<u8> = unfolded[..colon].iter()
+        .filter(|b| !b.is_ascii_whitespace())
+        .map(|b| b.to_ascii_lowercase())
+        .collect();
+    let value = compress_wsp(unfolded.get(colon+1..).unwrap_or(&[]));
+    let value = value.strip_prefix(b" ").unwrap_or(&value);
+    let value = value.strip_suffix(b" ").unwrap_or(value);
+
+    let mut out = name;
+    out.push(b':');
+    out.extend_from_slice(value);
+    out.extend_from_slice(b"\r\n");
+    out
+}
+
+
+/// `raw` (a DKIM-Signature field) with the value of its b= tag removed.
+fn strip_signature(raw: &[u8]) -> Vec<u8> {
+    let colon = match raw.iter().position(|&b| b == b':') {
+        Some(i) => i,
+        None => return raw.to_vec()
+    };
+
+    // The line ending that terminates the field is not part of any tag.
+    let mut end = raw.len();
+    while end > colon + 1 && (raw[end-1] == b'\r' || raw[end-1] == b'\n') {
+        end -= 1;
+    }
+
+    let mut out = raw[..colon+1].to_vec();
+    for (i, spec) in raw[colon+1..end].split(|&b| b == b';').enumerate() {
+        if i > 0 {
+            out.push(b';');
+        }
+        let eq = spec.iter().position(|&b| b == b'=');
+        // Exactly "b", as `parse_tags` reads the signature from; any
+        // other tag, "B=" included, is signed like the rest.
+        let is_b = eq.is_some_and(|eq| spec[..eq].trim_ascii() == b"b");
+        match eq {
+            Some(eq) if is_b => out.extend_from_slice(&spec[..eq+1]),
+            _ => out.extend_from_slice(spec)
+        }
+    }
+
+    out.extend_from_slice(&raw[end..]);
+    out
+}
+
+
+enum PublicKey {
+    Rsa(rsa::RsaPublicKey),
+    Ed25519(ed25519_dalek::VerifyingKey),
+}
+
+
+fn permerror(reason: &str) -> (DkimStatus, String) {
+    (DkimStatus::PermError, reason.to_string())
+}
+
+
+/// Find and parse the key record for `selector` and `domain`.
+fn lookup_key<F>(resolver: &F, selector: &str, domain: &str, algorithm: &str)
+    -> Result<(PublicKey, HashMap<String, String>), (DkimStatus, String)>
+    where F: Fn(&str) -> Result<Vec<String>, String>
+{
+    let name = format!("{}._domainkey.{}", selector, domain);
+    let records = match resolver(&name) {
+        Ok(r) => r,
+        Err(e) => return Err((DkimStatus::TempError, e))
+    };
+
+    let mut found = None;
+    for record in records {
+        if let Some(tags) = parse_tags(&record) {
+            if tags.get("v").is_none_or(|v| v == "DKIM1") {
+                found = Some(tags);
+                break;
+            }
+        }
+    }
+    let tags = match found {
+        Some(t) => t,
+        None => return Err(permerror("no key record"))
+    };
+
+    let key_type = tags.get("k").map(|s| s.as_str()).unwrap_or("rsa");
+    if !algorithm.starts_with(&format!("{}-", key_type)) {
+        return Err(permerror("key type does not match algorithm"));
+    }
+    if let Some(hashes) = tags.get("h") {
+        if !hashes.split(':').any(|h| h.trim() == "sha256") {
+            return Err(permerror("key does not allow sha256"));
+        }
+    }
+
+    let p = strip_whitespace(tags.get("p").map(|s| s.as_str()).unwrap_or(""));
+    if p.is_empty() {
+        return Err(permerror("key revoked"));
+    }
+    let der = match base64::engine::general_purpose::STANDARD.decode(&p) {
+        Ok(d) => d,
+        Err(_) => return Err(permerror("key is not valid base64"))
+    };
+
+    let key = if key_type == "ed25519" {
+        let bytes: [u8; 32] = match der.as_slice().try_into() {
+            Ok(b) => b,
+            Err(_) => return Err(permerror("ed25519 key is not 32 bytes"))
+        };
+        match ed25519_dalek::VerifyingKey::from_bytes(&bytes) {
+            Ok(k) => PublicKey::Ed25519(k),
+            Err(_) => return Err(permerror("invalid ed25519 key"))
+        }
+    } else {
+        let key = rsa::RsaPublicKey::from_public_key_der(&der)
+            .or_else(|_| rsa::RsaPublicKey::from_pkcs1_der(&der));
+        match key {
+            Ok(k) => {
+                if rsa::traits::PublicKeyParts::size(&k) * 8 < MIN_RSA_BITS {
+                    return Err(permerror("RSA key too short"));
+                }
+                PublicKey::Rsa(k)
+            },
+            Err(_) => return Err(permerror("invalid RSA key"))
+        }
+    };
+
+    Ok((key, tags))
+}
+
+
+/// Whether `sub` is `domain` or a subdomain of it.
+fn within_domain(sub: &str, domain: &str) -> bool {
+    let sub = sub.to_ascii_lowercase();
+    let domain = domain.to_ascii_lowercase();
+    sub == domain || sub.ends_with(&format!(".{}", domain))
+}
+
+
+fn field_bytes<'a>(msg: &'a Message, field: &HeaderField) -> &'a [u8] {
+    &msg.buffer()[field.offset[0]..field.offset[0]+field.offset[1]]
+}
+
+
+fn verify_one<F>(msg: &Message, fields: &[HeaderField], index: usize,
+                 tags: &HashMap<String, String>, resolver: &F)
+    -> (DkimStatus, String)
+    where F: Fn(&str) -> Result<Vec<String>, String>
+{
+    for required in ["v", "a", "b", "bh", "d", "h", "s"] {
+        if !tags.contains_key(required) {
+            return permerror(&format!("missing {}= tag", required));
+        }
+    }
+    if tags["v"] != "1" {
+        return permerror("unsupported version");
+    }
+
+    let algorithm = tags["a"].to_ascii_lowercase();
+    if algorithm != "rsa-sha256" && algorithm != "ed25519-sha256" {
+        return permerror("unsupported algorithm");
+    }
+
+    let domain = &tags["d"];
+    if let Some(auid) = tags.get("i") {
+        let auid_domain = auid.rsplit('@').next().unwrap_or("");
+        if !within_domain(auid_domain, domain) {
+            return permerror("i= is not within d=");
+        }
+    }
+
+    let signed: Vec<String> = tags["h"].split(':')
+        .map(|h| h.trim().to_ascii_lowercase())
+        .collect();
+    if !signed.iter().any(|h| h == "from") {
+        return permerror("From is not signed");
+    }
+
+    if let Some(x) = tags.get("x") {
+        let now = SystemTime::now().duration_since(UNIX_EPOCH).map(|d| d.as_secs()).unwrap_or(0);
+        match x.parse::<u64>() {
+            Ok(x) if x < now => return permerror("signature expired"),
+            Ok(_) => {},
+            Err(_) => return permerror("invalid x= tag")
+        }
+    }
+
+    let canon = tags.get("c").map(|c| c.to_ascii_lowercase()).unwrap_or_default();
+    let mut canon = canon.splitn(2, '/');
+    let header_relaxed = match canon.next().unwrap_or("simple") {
+        "" | "simple" => false,
+        "relaxed" => true,
+        _ => return permerror("unknown canonicalization")
+    };
+    let body_relaxed = match canon.next().unwrap_or("simple") {
+        "simple" => false,
+        "relaxed" => true,
+        _ => return permerror("unknown canonicalization")
+    };
+
+    let mut body = canonicalize_body(msg.whole_body(), body_relaxed);
+    if let Some(l) = tags.get("l") {
+        match l.parse::<usize>() {
+            Ok(l) if l <= body.len() => body.truncate(l),
+            _ => return permerror("invalid l= tag")
+        }
+    }
+    let body_hash = base64::engine::general_purpose::STANDARD.encode(Sha256::digest(&body));
+    if body_hash != strip_whitespace(&tags["bh"]) {
+        return (DkimStatus::Fail, "body hash mismatch".to_string());
+    }
+
+    let signature = match base64::engine::general_purpose::STANDARD.decode(strip_whitespace(&tags["b"])) {
+        Ok(s) => s,
+        Err(_) => return permerror("b= is not valid base64")
+    };
+
+    let (key, key_tags) = match lookup_key(resolver, &tags["s"], domain, &algorithm) {
+        Ok(k) => k,
+        Err(e) => return e
+    };
+    if let Some(flags) = key_tags.get("t") {
+        let strict = flags.split(':').any(|f| f.trim() == "s");
+        let auid_domain = tags.get("i").map(|i| i.rsplit('@').next().unwrap_or("").to_string());
+        if strict && auid_domain.is_some_and(|a| !a.eq_ignore_ascii_case(domain)) {
+            return permerror("key requires i= domain to equal d=");
+        }
+    }
+
+    // Signed fields are taken from the bottom up, each instance once.
+    let mut data = Vec::new();
+    let mut used = vec![false; fields.len()];
+    for name in &signed {
+        let pick = (0..fields.len()).rev()
+            .find(|&i| !used[i] && i != index && fields[i].name.eq_ignore_ascii_case(name));
+        if let Some(i) = pick {
+            used[i] = true;
+            data.extend(canonicalize_header(field_bytes(msg, &fields[i]), header_relaxed));
+        }
+    }
+    let own = strip_signature(field_bytes(msg, &fields[index]));
+    let mut own = canonicalize_header(&own, header_relaxed);
+    own.truncate(own.len() - 2);
+    data.extend(own);
+
+    let hash = Sha256::digest(&data);
+    let ok = match key {
+        PublicKey::Rsa(k) => k.verify(rsa::Pkcs1v15Sign::new::<Sha256>(), &hash, &signature).is_ok(),
+        PublicKey::Ed25519(k) => match ed25519_dalek::Signature::from_slice(&signature) {
+            Ok(sig) => k.verify(&hash, &sig).is_ok(),
+            Err(_) => false
+        }
+    };
+
+    if ok {
+        (DkimStatus::Pass, String::new())
+    } else {
+        (DkimStatus::Fail, "signature did not verify".to_string())
+    }
+}
+
+
+/// Verify every DKIM-Signature field of `msg`, in order of appearance.
+/// `resolver` is given a name such as "sel._domainkey.example.com" and
+/// returns the TXT records found there, one string per record with its
+/// character-strings already concatenated (RFC 6376 3.6.2.2), an empty list
+/// if there are none, or an error message for a temporary failure.
+pub fn verify<F>(msg: &Message, resolver: F) -> Vec<DkimResult>
+    where F: Fn(&str) -> Result<Vec<String>, String>
+{
+    let headers = msg.headers();
+    let fields = headers.fields();
+    let mut out = Vec::new();
+
+    for (i, field) in fields.iter().enumerate() {
+        if !field.name.eq_ignore_ascii_case("DKIM-Signature") {
+            continue;
+        }
+
+        let value = String::from_utf8_lossy(field.raw_value);
+        let tags = parse_tags(&value);
+        let (status, reason) = match &tags {
+            Some(tags) => verify_one(msg, &fields, i, tags, &resolver),
+            None => permerror("malformed tag list")
+        };
+
+        let tag = |name: &str| tags.as_ref().and_then(|t| t.get(name).cloned());
+        out.push(DkimResult {
+            domain: tag("d"),
+            selector: tag("s"),
+            algorithm: tag("a"),
+            status: status,
+            reason: reason,
+            offset: field.offset,
+        });
+    }
+
+    out
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // Keys and signatures generated independently of this module.
+    const RSA_RECORD: &str = "v=DKIM1; k=rsa; p=MIGfMA0GCSqGSIb3DQEBAQUAA4GNADCBiQKBgQClN8hzm4TsTd/HP/CSCdP3bR3X/PqYufUkR2JlAZ9DqxTMWujtP+6pJjGJc0aG3lNKZrGRji1+B2UK+GTW4H41opIl1aKy1sUDd4Tlpd5JOarXpSeDDk9lfi40iqexTQURUKVVevK6Y4jqUy5T688EYD/AQcrel1AtzA3HXtUD/QIDAQAB";
+    const ED25519_RECORD: &str = "v=DKIM1; k=ed25519; p=ZMGSJwMoq3mi9nMxAUeRY0PphFxd9TyJxnsbpGdl/00=";
+    const MULTIPART_RECORD: &str = "v=DKIM1; k=ed25519; p=OxbPmtuHwxmpxUVyMQlrRCzkwW71iSwjygGjMTXHl9M=";
+    const RSA_SIMPLE: &str = "DKIM-Signature: v=1; a=rsa-sha256; c=simple/simple; d=example.com; s=rsa;\r\n\th=from:to:subject:date; bh=YXpnUUMpFsqHcGbRWiR/PblkfOqM1ocjwIx0kjRWJUw=;\r\n\tb=MzKb1wvNqn0/Cr/NBTLG2GhDZnUa7G00yCj/R9w4BD71TTgECzFW0fKdUe++PtQY11QhmqO9yjbvUQO6lg/z9GSRQ0QgQdxbDDu7MjCRMyuHrmqY3GpF/2YgsuoGos5cmw3ML7U8Ta++m0d3QJELDOkDCRqhSejWVyiq3BrNQeQ=\r\n";
+    const ED25519_RELAXED: &str = "DKIM-Signature: v=1; a=ed25519-sha256; c=relaxed/relaxed; d=example.com; s=ed;\r\n\th=from:to:subject:date; bh=gf+8TFYKGrM98KPQ6XL68ppNGLhnltuip2z67RwAhzI=;\r\n\tb=UHlIcVKY5M+Xeu9rmr8Kb9T2I9Ti33ajbey/1lC3osCS/K/cNYGZMD3u3PVjfEjN2k8RiEXBYqtbwBIbSnJGDw==\r\n";
+    const RSA_LENGTH: &str = "DKIM-Signature: v=1; a=rsa-sha256; c=relaxed/simple; d=example.com; s=rsa; l=10;\r\n\th=from:to:subject:date; bh=r3qz5VK7YVKXUYs+WTIgyhivaQmRLWp6zzY9yRm4ukA=;\r\n\tb=kTM2mxDiqvR+apdmJRdlRyBa95TINV1jkLuPXdGFUYOdFr0+4C5bJ6436Efhf4oj2EIN5cjoygcT4lWdaRgPVZOK0hXs7LPD2H7itJfTkQfwNWsWfpHvnN6aVKYYHCjIednnIhSdbdQ31aDRMZhbBoxvWeAShOKBYIij/Bg8f4g=\r\n";
+    const MULTIPART_SIGNATURE: &str = "DKIM-Signature: v=1; a=ed25519-sha256; c=relaxed/relaxed; d=example.com; s=mp;\r\n\th=from:to:subject:content-type; bh=gb3v3dy3+BBWIX9jqsPsvJfwECC2PHnjlfCCa4VQfOk=;\r\n\tb=CZ+a72dObs/x1oWP1JpHNmIr7aqEgWocELicdKSDxkLBnu15ppHOjCwDkpkQbgQec/2dvD4d42jEakw2pYFxAQ==\r\n";
+    const MULTIPART: &str = "From: Joe <joe@example.com>\r\nTo: jane@example.net\r\nSubject: Parts\r\nContent-Type: multipart/mixed; boundary=\"b\"\r\n\r\npreamble\r\n--b\r\nContent-Type: text/plain\r\n\r\nfirst\r\n--b\r\nContent-Type: text/plain\r\n\r\nsecond\r\n--b--\r\n";
+    const SIGNED: &str = "From: Joe <joe@example.com>\r\nTo: jane@example.net\r\nSubject: Hello\r\n  there  \r\nDate: Mon, 1 Jan 2024 00:00:00 +0000\r\n\r\nHi  Jane,\r\n\r\nSee you \t soon.  \r\n\r\n\r\n";
+
+    fn resolve(name: &str) -> Result<Vec<String>, String> {
+        match name {
+            "rsa._domainkey.example.com" => Ok(vec![RSA_RECORD.to_string()]),
+            "ed._domainkey.example.com" => Ok(vec![ED25519_RECORD.to_string()]),
+            "mp._domainkey.example.com" => Ok(vec![MULTIPART_RECORD.to_string()]),
+            "down._domainkey.example.com" => Err("SERVFAIL".to_string()),
+            _ => Ok(Vec::new())
+        }
+    }
+
+    fn check(signature: &str, rest: &str) -> (DkimStatus, String) {
+        let buf = format!("{}{}", signature, rest);
+        let results = verify(&Message::parse(buf.as_bytes()).unwrap(), resolve);
+        assert_eq!(results.len(), 1);
+        assert_eq!(results[0].offset, [0, signature.len()]);
+        (results[0].status, results[0].reason.clone())
+    }
+
+    fn pass() -> (DkimStatus, String) {
+        (DkimStatus::Pass, String::new())
+    }
+
+    #[test]
+    fn canonicalization_vectors() {
+        // RFC 6376 3.4.6.
+        let body = b" C \r\nD \t E\r\n\r\n\r\n";
+        assert_eq!(canonicalize_body(body, false), b" C \r\nD \t E\r\n");
+        assert_eq!(canonicalize_body(body, true), b" C\r\nD E\r\n");
+        assert_eq!(canonicalize_header(b"A: X\r\n", true), b"a:X\r\n");
+        assert_eq!(canonicalize_header(b"B : Y\t\r\n\tZ  \r\n", true), b"b:Y Z\r\n");
+        assert_eq!(canonicalize_header(b"B : Y\t\r\n\tZ  \r\n", false), b"B : Y\t\r\n\tZ  \r\n");
+
+        // Empty bodies, and LF line endings read as CRLF.
+        assert_eq!(canonicalize_body(b"", false), b"\r\n");
+        assert_eq!(canonicalize_body(b"\r\n\r\n", true), b"");
+        assert_eq!(canonicalize_body(b"a \nb\n", false), b"a \r\nb\r\n");
+    }
+
+    #[test]
+    fn tag_names_are_case_sensitive() {
+        let tags = parse_tags(" v = 1 ; B=x; b=y ;").unwrap();
+        assert_eq!(tags["v"], "1");
+        assert_eq!(tags["B"], "x");
+        assert_eq!(tags["b"], "y");
+        assert!(parse_tags("b=x; b=y").is_none());
+        assert!(parse_tags("b").is_none());
+
+        // "A=" does not supply the a= tag.
+        let upper = RSA_SIMPLE.replace("a=rsa-sha256", "A=rsa-sha256");
+        assert_eq!(check(&upper, SIGNED), permerror("missing a= tag"));
+    }
+
+    #[test]
+    fn strip_signature_value() {
+        assert_eq!(strip_signature(b"DKIM-Signature: a=x; bh=h; b=sig\r\n  ature\r\n"),
+                   b"DKIM-Signature: a=x; bh=h; b=\r\n");
+        assert_eq!(strip_signature(b"DKIM-Signature:  b = sig; d=x\r\n"),
+                   b"DKIM-Signature:  b =; d=x\r\n");
+        assert_eq!(strip_signature(b"DKIM-Signature: B=sig; b=sig\r\n"),
+                   b"DKIM-Signature: B=sig; b=\r\n");
+    }
+
+    #[test]
+    fn rsa_sha256_simple() {
+        assert_eq!(check(RSA_SIMPLE, SIGNED), pass());
+    }
+
+    #[test]
+    fn ed25519_sha256_relaxed() {
+        assert_eq!(check(ED25519_RELAXED, SIGNED), pass());
+
+        // Relaxed canonicalization tolerates whitespace changes.
+        let rewrapped = SIGNED.replace("Subject: Hello\r\n  there  ", "Subject:  Hello there")
+            .replace("See you \t soon.  ", "See you soon.");
+        assert_eq!(check(ED25519_RELAXED, &rewrapped), pass());
+    }
+
+    #[test]
+    fn tampering_fails() {
+        let body = SIGNED.replace("soon", "later");
+        assert_eq!(check(RSA_SIMPLE, &body), (DkimStatus::Fail, "body hash mismatch".to_string()));
+        assert_eq!(check(ED25519_RELAXED, &body).0, DkimStatus::Fail);
+
+        let subject = SIGNED.replace("Hello", "Goodbye");
+        assert_eq!(check(RSA_SIMPLE, &subject),
+                   (DkimStatus::Fail, "signature did not verify".to_string()));
+
+        // Simple canonicalization does not tolerate whitespace changes.
+        let spaced = SIGNED.replace("See you \t soon.  ", "See you soon.");
+        assert_eq!(check(RSA_SIMPLE, &spaced).0, DkimStatus::Fail);
+    }
+
+    #[test]
+    fn multipart_body() {
+        // The body hash covers every part, not just the preamble.
+        assert_eq!(check(MULTIPART_SIGNATURE, MULTIPART), pass());
+
+        let tampered = MULTIPART.replace("second", "Second");
+        assert_eq!(check(MULTIPART_SIGNATURE, &tampered),
+                   (DkimStatus::Fail, "body hash mismatch".to_string()));
+    }
+
+    #[test]
+    fn body_length_limit() {
+        assert_eq!(check(RSA_LENGTH, SIGNED), pass());
+        let appended = format!("{}Appended by a list.\r\n", SIGNED);
+        assert_eq!(check(RSA_LENGTH, &appended), pass());
+        let changed = SIGNED.replace("Jane", "John");
+        assert_eq!(check(RSA_LENGTH, &changed).0, DkimStatus::Fail);
+    }
+
+    #[test]
+    fn expiry_and_errors() {
+        let expired = RSA_SIMPLE.replace("s=rsa;", "s=rsa; x=1;");
+        assert_eq!(check(&expired, SIGNED), permerror("signature expired"));
+
+        let unknown = RSA_SIMPLE.replace("s=rsa;", "s=none;");
+        assert_eq!(check(&unknown, SIGNED), permerror("no key record"));
+
+        let down = RSA_SIMPLE.replace("s=rsa;", "s=down;");
+        assert_eq!(check(&down, SIGNED), (DkimStatus::TempError, "SERVFAIL".to_string()));
+
+        let mismatch = RSA_SIMPLE.replace("s=rsa;", "s=ed;");
+        assert_eq!(check(&mismatch, SIGNED), permerror("key type does not match algorithm"));
+    }
+}
@@ -0,0 +1,482 @@
+//! Editing a parsed message without re-serializing it. Changes are
+//! recorded against part paths and spliced into the buffer the message was
+//! parsed from, so every byte outside the edited regions is emitted as it
+//! was and signatures over untouched parts and headers stay valid.
+
+use crate::builder;
+use crate::message::Message;
+use crate::security::canonicalize_crlf;
+
+
+#[derive(Clone, Debug)]
+enum Change {
+    AddHeader(String, String),
+    RemoveHeader(String),
+    SetHeader(String, String),
+    ReplaceBody(Vec<u8>),
+    ReplacePart(builder::Part),
+    RemovePart,
+    AppendPart(builder::Part),
+}
+
+
+impl Change {
+    fn is_header(&self) -> bool {
+        matches!(self, Change::AddHeader(..) | Change::RemoveHeader(_) | Change::SetHeader(..))
+    }
+}
+
+
+/// Replace `end - start` bytes of the buffer at `start` with `data`.
+struct Splice {
+    start: usize,
+    end: usize,
+    data: Vec<u8>,
+}
+
+
+/// A set of changes to a message. Paths are relative to the message the
+/// editor was created for; nothing is checked until `to_bytes`.
+#[derive(Clone)]
+pub struct Editor {
+    message: Message,
+    changes: Vec<(Vec<usize>, Change)>,
+}
+
+
+/// Start of the line that ends just before `pos`.
+fn previous_line(buf: &[u8], pos: usize) -> Option<usize> {
+    if pos == 0 || buf[pos-1] != b'\n' {
+        return None;
+    }
+    match buf[..pos-1].iter().rposition(|&b| b == b'\n') {
+        Some(i) => Some(i + 1),
+        None => Some(0)
+    }
+}
+
+
+fn is_multipart(msg: &Message) -> bool {
+    msg.mime_type().to_ascii_lowercase().starts_with("multipart/")
+}
+
+
+impl Editor {
+    pub fn new(message: &Message) -> Editor {
+        Editor {
+            message: message.clone(),
+            changes: Vec::new(),
+        }
+    }
+
+    /// Add a header field at the end of the part's header block. The value
+    /// is RFC 2047 encoded if it is not ASCII.
+    pub fn add_header(&mut self, path: &[usize], name: &str, value: &str) -> &mut Editor {
+        self.changes.push((path.to_vec(), Change::AddHeader(name.to_string(), value.to_string())));
+        self
+    }
+
+    /// Remove every field called `name`.
+    pub fn remove_header(&mut self, path: &[usize], name: &str) -> &mut Editor {
+        self.changes.push((path.to_vec(), Change::RemoveHeader(name.to_string())));
+        self
+    }
+
+    /// Replace the first field called `name` where it stands and remove the
+    /// others, or add one if there is none.
+    pub fn set_header(&mut self, path: &[usize], name: &str, value: &str) -> &mut Editor {
+        self.changes.push((path.to_vec(), Change::SetHeader(name.to_string(), value.to_string())));
+        self
+    }
+
+    /// Replace the body of a leaf part, keeping its headers. The data is
+    /// encoded with the part's existing transfer encoding; 7bit and 8bit
+    /// bodies must be text that fits that encoding. Like those, binary data
+    /// gets a final line ending if it has none.
+    pub fn replace_body(&mut self, path: &[usize], data: &[u8]) -> &mut Editor {
+        self.changes.push((path.to_vec(), Change::ReplaceBody(data.to_vec())));
+        self
+    }
+
+    /// Replace a part, headers and all.
+    pub fn replace_part(&mut self, path: &[usize], part: &builder::Part) -> &mut Editor {
+        self.changes.push((path.to_vec(), Change::ReplacePart(part.clone())));
+        self
+    }
+
+    /// Remove a child of a multipart, along with its delimiter line.
+    pub fn remove_part(&mut self, path: &[usize]) -> &mut Editor {
+        self.changes.push((path.to_vec(), Change::RemovePart));
+        self
+    }
+
+    /// Add a part after the last child of the multipart at `path`.
+    pub fn append_part(&mut self, path: &[usize], part: &builder::Part) -> &mut Editor {
+        self.changes.push((path.to_vec(), Change::AppendPart(part.clone())));
+        self
+    }
+
+    /// The line ending the message uses, which inserted text follows.
+    fn eol(&self) -> &'static [u8] {
+        let raw = self.message.raw_bytes();
+        match raw.iter().position(|&b| b == b'\n') {
+            Some(i) if i == 0 || raw[i-1] != b'\r' => b"\n",
+            _ => b"\r\n"
+        }
+    }
+
+    /// Convert generated CRLF text to the message's line ending.
+    fn lines(&self, data: Vec<u8>) -> Vec<u8> {
+        if self.eol() == b"\r\n" {
+            return data;
+        }
+        let mut out = Vec::with_capacity(data.len());
+        for (i, &b) in data.iter().enumerate() {
+            if !(b == b'\r' && data.get(i+1) == Some(&b'\n')) {
+                out.push(b);
+            }
+        }
+        out
+    }
+
+    fn locate(&self, path: &[usize]) -> Result<Message, String> {
+        let part = match self.message.descendant(path) {
+            Some(p) => p,
+            None => return Err(format!("no part at {:?}", path))
+        };
+        if !std::ptr::eq(part.buffer(), self.message.buffer()) {
+            return Err(format!("part {:?} was decoded out of its parent and cannot be edited", path));
+        }
+        Ok(part)
+    }
+
+    fn header_line(&self, name: &str, value: &str) -> Vec<u8> {
+        self.lines(builder::fold_header(name, &builder::encode_unstructured(value)).into_bytes())
+    }
+
+    /// Header changes to one part, applied in order to its fields.
+    fn header_splices(&self, part: &Message, changes: &[&Change]) -> Result<Vec<Splice>, String> {
+        let headers = part.headers();
+        let fields = headers.fields();
+        // None while a field is unchanged, then its new text (empty once
+        // removed).
+        let mut replaced: Vec<Option<Vec<u8>>> = vec![None; fields.len()];
+        let mut added: Vec<Option<(String, String)>> = Vec::new();
+
+        for change in changes {
+            match change {
+                Change::AddHeader(name, _) | Change::SetHeader(name, _) => builder::check_header_name(name)?,
+                _ => {}
+            }
+            match change {
+                Change::AddHeader(name, value) => added.push(Some((name.clone(), value.clone()))),
+                Change::RemoveHeader(name) => {
+                    for (i, f) in fields.iter().enumerate() {
+                        if f.name.eq_ignore_ascii_case(name) {
+                            replaced[i] = Some(Vec::new());
+                        }
+                    }
+                    added.retain(|a| !matches!(a, Some((n, _)) if n.eq_ignore_ascii_case(name)));
+                },
+                Change::SetHeader(name, value) => {
+                    let mut done = false;
+                    for (i, f) in fields.iter().enumerate() {
+                        let removed = matches!(&replaced[i], Some(t) if t.is_empty());
+                        if f.name.eq_ignore_ascii_case(name) && !removed {
+                            replaced[i] = Some(if done { Vec::new() } else { self.header_line(name, value) });
+                            done = true;
+                        }
+                    }
+                    for a in added.iter_mut() {
+                        if matches!(a, Some((n, _)) if n.eq_ignore_ascii_case(name)) {
+                            *a = if done { None } else { Some((name.clone(), value.clone())) };
+                            done = true;
+                        }
+                    }
+                    if !done {
+                        added.push(Some((name.clone(), value.clone())));
+                    }
+                },
+                _ => {}
+            }
+        }
+
+        let mut out = Vec::new();
+        for (f, text) in fields.iter().zip(replaced) {
+            if let Some(data) = text {
+                out.push(Splice { start: f.offset[0], end: f.offset[0] + f.offset[1], data: data });
+            }
+        }
+
+        let mut data = Vec::new();
+        for (name, value) in added.into_iter().flatten() {
+            data.extend_from_slice(&self.header_line(&name, &value));
+        }
+        if !data.is_empty() {
+            let buf = part.buffer();
+            let at = match fields.last() {
+                Some(f) => f.offset[0] + f.offset[1],
+                None => part.offset()[0]
+            };
+            if at > 0 && buf[at-1] != b'\n' && !fields.is_empty() {
+                data.splice(0..0, self.eol().iter().copied());
+            }
+            out.push(Splice { start: at, end: at, data: data });
+        }
+        Ok(out)
+    }
+
+    fn encode_body(&self, part: &Message, data: &[u8]) -> Result<Vec<u8>, String> {
+        match part.body_encoding() {
+            "base64" => Ok(self.lines(builder::encode_base64(data))),
+            "quotedprintable" => Ok(self.lines(builder::encode_quoted_printable(data))),
+            "binary" => {
+                // The line ending before the next delimiter is part of the
+                // body span being replaced.
+                let mut body = data.to_vec();
+                if !body.is_empty() && !body.ends_with(b"\n") {
+                    body.extend_from_slice(self.eol());
+                }
+                Ok(body)
+            },
+            encoding => {
+                let mut body = canonicalize_crlf(data);
+                if !body.is_empty() && !body.ends_with(b"\r\n") {
+                    body.extend_from_slice(b"\r\n");
+                }
+                if !builder::is_line_safe(&body) || (encoding == "7bit" && !body.is_ascii()) {
+                    return Err(format!("part {:?}: data cannot be sent as {}; replace the part instead",
+                                       part.path(), encoding));
+                }
+                Ok(self.lines(body))
+            }
+        }
+    }
+
+    fn remove_splice(&self, part: &Message, path: &[usize]) -> Result<Splice, String> {
+        let parent = match part.parent() {
+            Some(p) if !path.is_empty() && is_multipart(&p) => p,
+            _ => return Err(format!("part {:?} is not the child of a multipart", path))
+        };
+        let boundary = match parent.param("boundary") {
+            Some(b) => format!("--{}", b),
+            None => return Err(format!("part {:?}: parent has no boundary", path))
+        };
+
+        let buf = part.buffer();
+        let [start, len] = part.offset();
+        match previous_line(buf, start) {
+            Some(line) if buf[line..].starts_with(boundary.as_bytes()) => {
+                Ok(Splice { start: line, end: start + len, data: Vec::new() })
+            },
+            _ => Err(format!("part {:?}: delimiter line not found", path))
+        }
+    }
+
+    fn append_splice(&self, part: &Message, path: &[usize], child: &builder::Part)
+        -> Result<Splice, String>
+    {
+        let boundary = match part.param("boundary") {
+            Some(b) if is_multipart(part) => format!("--{}", b),
+            _ => return Err(format!("part {:?} is not a multipart", path))
+        };
+        let close = format!("{}--", boundary);
+
+        let body = part.whole_body();
+        let base = part.body_offset()[0];
+        let mut pos = 0;
+        for line in body.split_inclusive(|&b| b == b'\n') {
+            if line.starts_with(close.as_bytes()) {
+                let mut data = boundary.into_bytes();
+                data.extend_from_slice(self.eol());
+                data.extend_from_slice(&self.lines(child.serialize(false)));
+                return Ok(Splice { start: base + pos, end: base + pos, data: data });
+            }
+            pos += line.len();
+        }
+        Err(format!("part {:?}: closing delimiter not found", path))
+    }
+
+    fn splices(&self) -> Result<Vec<Splice>, String> {
+        let mut out = Vec::new();
+
+        // Header changes are grouped per part so that later ones see the
+        // effect of earlier ones.
+        let mut header_changes: Vec<(&[usize], Vec<&Change>)> = Vec::new();
+        for (path, change) in &self.changes {
+            if !change.is_header() {
+                continue;
+            }
+            match header_changes.iter_mut().find(|(p, _)| p == path) {
+                Some((_, list)) => list.push(change),
+                None => header_changes.push((path, vec![change]))
+            }
+        }
+        for (path, changes) in &header_changes {
+            let part = self.locate(path)?;
+            out.extend(self.header_splices(&part, changes)?);
+        }
+
+        for (path, change) in &self.changes {
+            let part = self.locate(path)?;
+            match change {
+                Change::ReplaceBody(data) => {
+                    if is_multipart(&part) {
+                        return Err(format!("part {:?} is a multipart; edit its children instead", path));
+                    }
+                    let [start, len] = part.body_offset();
+                    out.push(Splice { start: start, end: start + len, data: self.encode_body(&part, data)? });
+                },
+                Change::ReplacePart(new) => {
+                    let [start, len] = part.offset();
+                    let data = self.lines(new.serialize(path.is_empty()));
+                    out.push(Splice { start: start, end: start + len, data: data });
+                },
+                Change::RemovePart => out.push(self.remove_splice(&part, path)?),
+                Change::AppendPart(child) => out.push(self.append_splice(&part, path, child)?),
+                _ => {}
+            }
+        }
+
+        Ok(out)
+    }
+
+    /// The message with all changes applied. Fails if a change cannot be
+    /// made or two of them touch the same bytes (say, a header added to a
+    /// part that is also removed).
+    pub fn to_bytes(&self) -> Result<Vec<u8>, String> {
+        let mut splices = self.splices()?;
+        splices.sort_by_key(|s| (s.start, s.end));
+        for pair in splices.windows(2) {
+            if pair[1].start < pair[0].end {
+                return Err(format!("edits at bytes {}..{} and {}..{} overlap",
+                                   pair[0].start, pair[0].end, pair[1].start, pair[1].end));
+            }
+        }
+
+        let buf = self.message.buffer();
+        let [start, len] = self.message.offset();
+        let mut out = Vec::with_capacity(len);
+        let mut pos = start;
+        for s in &splices {
+            out.extend_from_slice(&buf[pos..s.start]);
+            out.extend_from_slice(&s.data);
+            pos = s.end;
+        }
+        out.extend_from_slice(&buf[pos..start+len]);
+        Ok(out)
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const HEADERS: &str = "Subject: hello\r\nX-Empty:\r\nX-Folded: a\r\n b\r\nX-Last: end\r\n\r\nbody\r\n";
+
+    const MULTIPART: &str = "Content-Type: multipart/mixed; boundary=b\r\n\r\npre\r\n\
+--b\r\nContent-Type: text/plain\r\n\r\none\r\n\
+--b\r\nContent-Type: application/octet-stream\r\nContent-Transfer-Encoding: binary\r\n\r\ntwo\r\n\
+--b--\r\nepilogue\r\n";
+
+    /// `text` with the line endings of the message under test.
+    fn eol(text: &str, lf: bool) -> String {
+        if lf { text.replace("\r\n", "\n") } else { text.to_string() }
+    }
+
+    /// Apply `edit` to `original` and check the result is `original` with
+    /// `from` replaced by `to` and nothing else changed.
+    fn check<F>(original: &str, edit: F, from: &str, to: &str)
+        where F: Fn(&mut Editor)
+    {
+        for lf in [false, true] {
+            let buf = eol(original, lf);
+            let msg = Message::parse(buf.as_bytes()).unwrap();
+            let mut editor = Editor::new(&msg);
+            edit(&mut editor);
+            let out = editor.to_bytes().unwrap();
+
+            let from = eol(from, lf);
+            if from.is_empty() {
+                assert_eq!(String::from_utf8(out).unwrap(), buf);
+                continue;
+            }
+            assert_eq!(buf.matches(from.as_str()).count(), 1, "{:?}", from);
+            assert_eq!(String::from_utf8(out).unwrap(), buf.replacen(from.as_str(), &eol(to, lf), 1));
+        }
+    }
+
+    #[test]
+    fn remove_headers() {
+        check(HEADERS, |e| { e.remove_header(&[], "x-empty"); }, "X-Empty:\r\n", "");
+        check(HEADERS, |e| { e.remove_header(&[], "X-Folded"); }, "X-Folded: a\r\n b\r\n", "");
+        check(HEADERS, |e| { e.remove_header(&[], "X-Last"); }, "X-Last: end\r\n", "");
+        check(HEADERS, |e| { e.remove_header(&[], "X-None"); }, "", "");
+    }
+
+    #[test]
+    fn set_headers() {
+        check(HEADERS, |e| { e.set_header(&[], "X-Empty", "now set"); },
+              "X-Empty:\r\n", "X-Empty: now set\r\n");
+        check(HEADERS, |e| { e.set_header(&[], "X-Folded", "c"); },
+              "X-Folded: a\r\n b\r\n", "X-Folded: c\r\n");
+        check(HEADERS, |e| { e.set_header(&[], "X-Last", "changed"); },
+              "X-Last: end\r\n", "X-Last: changed\r\n");
+        check(HEADERS, |e| { e.set_header(&[], "X-New", "added"); },
+              "X-Last: end\r\n", "X-Last: end\r\nX-New: added\r\n");
+    }
+
+    #[test]
+    fn add_headers() {
+        check(HEADERS, |e| { e.add_header(&[], "X-Empty", "again"); },
+              "X-Last: end\r\n", "X-Last: end\r\nX-Empty: again\r\n");
+        check(HEADERS, |e| { e.add_header(&[], "X-A", "1").add_header(&[], "X-B", "2"); },
+              "X-Last: end\r\n", "X-Last: end\r\nX-A: 1\r\nX-B: 2\r\n");
+        check(HEADERS, |e| { e.add_header(&[], "X-A", "1").remove_header(&[], "X-A"); }, "", "");
+
+        let msg = Message::parse(HEADERS.as_bytes()).unwrap();
+        assert!(Editor::new(&msg).add_header(&[], "X-A\r\nBcc", "1").to_bytes().is_err());
+        assert!(Editor::new(&msg).set_header(&[], "X-A:", "1").to_bytes().is_err());
+    }
+
+    #[test]
+    fn remove_parts() {
+        check(MULTIPART, |e| { e.remove_part(&[0]); },
+              "--b\r\nContent-Type: text/plain\r\n\r\none\r\n", "");
+        check(MULTIPART, |e| { e.remove_part(&[1]); },
+              "--b\r\nContent-Type: application/octet-stream\r\nContent-Transfer-Encoding: binary\r\n\r\ntwo\r\n", "");
+    }
+
+    #[test]
+    fn append_parts() {
+        let part = builder::Part::text("plain", "three\n").unwrap();
+        check(MULTIPART, |e| { e.append_part(&[], &part); }, "--b--\r\n",
+              "--b\r\nContent-Type: text/plain; charset=utf-8\r\nContent-Transfer-Encoding: 7bit\r\n\r\nthree\r\n--b--\r\n");
+    }
+
+    #[test]
+    fn replace_parts_and_bodies() {
+        let part = builder::Part::text("html", "<p>1</p>\n").unwrap();
+        check(MULTIPART, |e| { e.replace_part(&[0], &part); },
+              "Content-Type: text/plain\r\n\r\none\r\n",
+              "Content-Type: text/html; charset=utf-8\r\nContent-Transfer-Encoding: 7bit\r\n\r\n<p>1</p>\r\n");
+        check(MULTIPART, |e| { e.replace_body(&[0], b"uno"); }, "one\r\n", "uno\r\n");
+        check(MULTIPART, |e| { e.replace_body(&[0], b"uno\r\ndos\n"); }, "one\r\n", "uno\r\ndos\r\n");
+
+        let msg = Message::parse(MULTIPART.as_bytes()).unwrap();
+        assert!(Editor::new(&msg).replace_body(&[], b"x").to_bytes().is_err());
+    }
+
+    #[test]
+    fn binary_bodies_end_with_a_line_ending() {
+        check(MULTIPART, |e| { e.replace_body(&[1], b"\x00\x01"); }, "two\r\n", "\x00\x01\r\n");
+        check(MULTIPART, |e| { e.replace_body(&[1], b"\x00\n"); }, "two\r\n", "\x00\n");
+
+        let msg = Message::parse(MULTIPART.as_bytes()).unwrap();
+        let out = Editor::new(&msg).replace_body(&[1], b"\x00\x01").to_bytes().unwrap();
+        let edited = Message::parse(&out).unwrap();
+        assert_eq!(edited.subpart_count(), 2);
+        assert_eq!(edited.subpart(1).unwrap().body_raw().unwrap(), b"\x00\x01\r\n");
+    }
+}
@@ -0,0 +1,233 @@
+use std::fs;
+use std::io;
+use std::io::Write;
+use std::path::Path;
+use std::path::PathBuf;
+use std::sync::atomic::AtomicUsize;
+use std::sync::atomic::Ordering;
+use std::time::SystemTime;
+use std::time::UNIX_EPOCH;
+
+
+static DELIVERY_COUNTER: AtomicUsize = AtomicUsize::new(0);
+
+
+/// A message file found in `cur/` or `new/`.
+#[derive(Clone, Debug)]
+pub struct MaildirEntry {
+    pub path: PathBuf,
+    /// "cur" or "new".
+    pub subdir: &'static str,
+    /// Unique part of the filename, without the info suffix.
+    pub id: String,
+    /// Flag letters from a ":2," info suffix, e.g. "FRS". Empty for messages
+    /// in new/, which carry no info.
+    pub flags: String,
+}
+
+
+pub struct Maildir {
+    root: PathBuf,
+}
+
+
+/// Split a Maildir filename into its unique part and flags. Only version 2
+/// info (":2,<flags>") carries flags; anything else after the colon is
+/// dropped from the id and ignored.
+pub fn parse_info(filename: &str) -> (&str, String) {
+    match filename.rfind(':') {
+        None => (filename, String::new()),
+        Some(i) => {
+            let info = &filename[i+1..];
+            let flags = match info.strip_prefix("2,") {
+                Some(f) => f.chars().filter(|c| c.is_ascii_alphabetic()).collect(),
+                None => String::new()
+            };
+            (&filename[..i], flags)
+        }
+    }
+}
+
+
+fn hostname() -> String {
+    let name = match fs::read_to_string("/proc/sys/kernel/hostname") {
+        Ok(s) => s.trim().to_string(),
+        Err(_) => std::env::var("HOSTNAME").unwrap_or_default()
+    };
+
+    if name.is_empty() {
+        return "localhost".to_string();
+    }
+
+    // http://cr.yp.to/proto/maildir.html: '/' and ':' must not appear.
+    name.replace('/', "\\057").replace(':', "\\072")
+}
+
+
+/// Generate a unique name following the Courier/Dovecot convention of
+/// "<secs>.M<usecs>P<pid>Q<counter>.<host>".
+fn unique_name() -> String {
+    let now = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap_or_default();
+
+    format!(
+        "{}.M{}P{}Q{}.{}",
+        now.as_secs(),
+        now.subsec_micros(),
+        std::process::id(),
+        DELIVERY_COUNTER.fetch_add(1, Ordering::Relaxed),
+        hostname()
+    )
+}
+
+
+impl Maildir {
+    pub fn new<P: AsRef<Path>>(root: P) -> Maildir {
+        Maildir {
+            root: root.as_ref().to_path_buf()
+        }
+    }
+
+    /// Open `root`, creating it and its `cur/`, `new/` and `tmp/`
+    /// subdirectories if they do not exist.
+    pub fn create<P: AsRef<Path>>(root: P) -> io::Result<Maildir> {
+        let maildir = Maildir::new(root);
+        for sub in ["cur", "new", "tmp"] {
+            fs::create_dir_all(maildir.root.join(sub))?;
+        }
+        Ok(maildir)
+    }
+
+    pub fn path(&self) -> &Path {
+        &self.root
+    }
+
+    /// List messages in `new/` followed by `cur/`, each sorted by filename.
+    /// Dotfiles are skipped, as required by the format.
+    pub fn list(&self) -> io::Result<Vec<MaildirEntry>> {
+        let mut entries = Vec::new();
+
+        for subdir in ["new", "cur"] {
+            let mut found = Vec::new();
+            for dirent in fs::read_dir(self.root.join(subdir))? {
+                let dirent = dirent?;
+                // The lossy name is only for sorting and flags; the entry's
+                // own path stays valid for names that are not UTF-8.
+                let name = dirent.file_name().to_string_lossy().into_owned();
+                if name.starts_with('.') {
+                    continue;
+                }
+
+                // metadata() follows symlinks, so linked messages are listed
+                // and dangling links are skipped.
+                let path = dirent.path();
+                match fs::metadata(&path) {
+                    Ok(m) if m.is_file() => found.push((name, path)),
+                    _ => continue
+                }
+            }
+            found.sort();
+
+            for (name, path) in found {
+                let (id, flags) = parse_info(&name);
+                entries.push(MaildirEntry {
+                    path: path,
+                    subdir: subdir,
+                    id: id.to_string(),
+                    flags: flags,
+                });
+            }
+        }
+
+        Ok(entries)
+    }
+
+    /// Deliver `data` as a new message: write and sync it under `tmp/`, then
+    /// rename it into `new/` so readers never observe a partial file.
+    pub fn deliver(&self, data: &[u8]) -> io::Result<MaildirEntry> {
+        let name = unique_name();
+        let tmp_path = self.root.join("tmp").join(&name);
+        let new_path = self.root.join("new").join(&name);
+
+        let result = fs::OpenOptions::new()
+            .write(true)
+            .create_new(true)
+            .open(&tmp_path)
+            .and_then(|mut file| {
+                file.write_all(data)?;
+                file.sync_all()
+            })
+            .and_then(|_| fs::rename(&tmp_path, &new_path));
+
+        if let Err(e) = result {
+            let _ = fs::remove_file(&tmp_path);
+            return Err(e);
+        }
+
+        Ok(MaildirEntry {
+            path: new_path,
+            subdir: "new",
+            id: name,
+            flags: String::new(),
+        })
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn scratch(name: &str) -> Maildir {
+        let root = std::env::temp_dir().join(format!("mailpar-{}-{}", name, std::process::id()));
+        let _ = fs::remove_dir_all(&root);
+        Maildir::create(root).unwrap()
+    }
+
+    #[test]
+    fn parse_info_flags() {
+        assert_eq!(parse_info("123.abc:2,FRS"), ("123.abc", "FRS".to_string()));
+        assert_eq!(parse_info("123.abc:1,xyz"), ("123.abc", String::new()));
+        assert_eq!(parse_info("123.abc"), ("123.abc", String::new()));
+    }
+
+    #[test]
+    fn list_sorts_and_skips_dotfiles() {
+        let md = scratch("list");
+        fs::write(md.path().join("cur/2.b:2,S"), b"x").unwrap();
+        fs::write(md.path().join("cur/1.a:2,RS"), b"x").unwrap();
+        fs::write(md.path().join("cur/.hidden"), b"x").unwrap();
+        fs::create_dir(md.path().join("cur/dir")).unwrap();
+        let delivered = md.deliver(b"Subject: hi\r\n\r\n").unwrap();
+
+        let entries = md.list().unwrap();
+        let ids: Vec<&str> = entries.iter().map(|e| e.id.as_str()).collect();
+        assert_eq!(ids, vec![delivered.id.as_str(), "1.a", "2.b"]);
+        assert_eq!(entries[1].flags, "RS");
+        assert_eq!(entries[0].subdir, "new");
+        fs::remove_dir_all(md.path()).unwrap();
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn list_keeps_non_utf8_and_symlinked_files() {
+        use std::ffi::OsStr;
+        use std::os::unix::ffi::OsStrExt;
+
+        let md = scratch("names");
+        let raw = OsStr::from_bytes(b"1.\xff\xfe:2,S");
+        fs::write(md.path().join("cur").join(raw), b"raw").unwrap();
+        fs::write(md.path().join("target"), b"linked").unwrap();
+        std::os::unix::fs::symlink(md.path().join("target"), md.path().join("cur/2.link:2,F")).unwrap();
+        std::os::unix::fs::symlink(md.path().join("missing"), md.path().join("cur/3.dangling")).unwrap();
+
+        let entries = md.list().unwrap();
+        assert_eq!(entries.len(), 2);
+        assert_eq!(fs::read(&entries[0].path).unwrap(), b"raw");
+        assert_eq!(entries[0].flags, "S");
+        assert_eq!(fs::read(&entries[1].path).unwrap(), b"linked");
+        assert_eq!(entries[1].flags, "F");
+        fs::remove_dir_all(md.path()).unwrap();
+    }
+}
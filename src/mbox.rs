@@ -0,0 +1,328 @@
+use std::borrow::Cow;
+
+
+/// Mailbox flavours understood by `MboxReader`. See
+/// https://www.loc.gov/preservation/digital/formats/fdd/fdd000383.shtml
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum MboxFormat {
+    /// Body lines beginning "From " were quoted as ">From "; only that single
+    /// level of quoting is undone.
+    Mboxo,
+    /// Body lines matching /^>*From / were quoted by prepending ">"; one
+    /// level of quoting is removed from every such line.
+    Mboxrd,
+    /// Messages are framed by their Content-Length header and never quoted.
+    /// Falls back to "From " separators when the header is missing or wrong.
+    Mboxcl2,
+}
+
+
+impl MboxFormat {
+    pub fn from_name(s: &str) -> Option<MboxFormat> {
+        match s.to_ascii_lowercase().as_str() {
+            "mboxo" => Some(MboxFormat::Mboxo),
+            "mboxrd" => Some(MboxFormat::Mboxrd),
+            "mboxcl2" => Some(MboxFormat::Mboxcl2),
+            _ => None
+        }
+    }
+}
+
+
+pub struct MboxMessage<'a> {
+    /// Offset of the message's "From " line within the mailbox.
+    pub offset: usize,
+    /// The "From " separator line including its line ending, or empty if the
+    /// mailbox did not begin with one.
+    pub from_line: &'a [u8],
+    /// Message bytes with the separator removed and quoting undone. Borrowed
+    /// from the mailbox unless unquoting had to rewrite it.
+    pub data: Cow<'a, [u8]>,
+}
+
+
+pub struct MboxReader<'a> {
+    buf: &'a [u8],
+    pos: usize,
+    format: MboxFormat,
+}
+
+
+fn line_end(buf: &[u8], start: usize) -> usize {
+    match buf[start..].iter().position(|&b| b == b'\n') {
+        Some(i) => start + i + 1,
+        None => buf.len()
+    }
+}
+
+
+/// Find the start of the next line beginning "From " at or after `start`,
+/// which must itself be the start of a line.
+fn next_separator(buf: &[u8], start: usize) -> usize {
+    let mut pos = start;
+    while pos < buf.len() {
+        if buf[pos..].starts_with(b"From ") {
+            return pos;
+        }
+        pos = line_end(buf, pos);
+    }
+    buf.len()
+}
+
+
+/// Writers append an empty line after each message; drop it again.
+fn strip_trailing_blank(data: &[u8]) -> &[u8] {
+    if data.ends_with(b"\r\n\r\n") {
+        &data[..data.len() - 2]
+    } else if data.ends_with(b"\n\n") {
+        &data[..data.len() - 1]
+    } else {
+        data
+    }
+}
+
+
+fn is_quoted_from(line: &[u8], format: MboxFormat) -> bool {
+    match format {
+        MboxFormat::Mboxo => line.starts_with(b">From "),
+        MboxFormat::Mboxrd => {
+            let n = line.iter().take_while(|&&b| b == b'>').count();
+            n > 0 && line[n..].starts_with(b"From ")
+        },
+        MboxFormat::Mboxcl2 => false,
+    }
+}
+
+
+fn unquote(data: &[u8], format: MboxFormat) -> Cow<'_, [u8]> {
+    let mut pos = 0;
+    let mut quoted = false;
+    while pos < data.len() {
+        if is_quoted_from(&data[pos..], format) {
+            quoted = true;
+            break;
+        }
+        pos = line_end(data, pos);
+    }
+
+    if !quoted {
+        return Cow::Borrowed(data);
+    }
+
+    let mut out = Vec::with_capacity(data.len());
+    let mut pos = 0;
+    while pos < data.len() {
+        let end = line_end(data, pos);
+        if is_quoted_from(&data[pos..end], format) {
+            out.extend_from_slice(&data[pos+1..end]);
+        } else {
+            out.extend_from_slice(&data[pos..end]);
+        }
+        pos = end;
+    }
+
+    Cow::Owned(out)
+}
+
+
+/// Return the end of the header block starting at `start`, and the value of
+/// its Content-Length header if one is present and well formed.
+fn content_length(buf: &[u8], start: usize) -> (usize, Option<usize>) {
+    let mut pos = start;
+    let mut length = None;
+
+    while pos < buf.len() {
+        let end = line_end(buf, pos);
+        let line = &buf[pos..end];
+        if line == b"\n" || line == b"\r\n" {
+            return (end, length);
+        }
+
+        if line.len() > 15 && line[..15].eq_ignore_ascii_case(b"content-length:") {
+            length = std::str::from_utf8(&line[15..])
+                .ok()
+                .and_then(|s| s.trim().parse::<usize>().ok());
+        }
+        pos = end;
+    }
+
+    (buf.len(), length)
+}
+
+
+impl<'a> MboxReader<'a> {
+    pub fn new(buf: &'a [u8], format: MboxFormat) -> MboxReader<'a> {
+        MboxReader {
+            buf: buf,
+            pos: 0,
+            format: format,
+        }
+    }
+
+    /// Offset of the next message to be returned.
+    pub fn position(&self) -> usize {
+        self.pos
+    }
+
+    /// Resume reading at `pos`, which should be a value previously returned
+    /// by `position()`.
+    pub fn set_position(&mut self, pos: usize) {
+        self.pos = pos;
+    }
+
+    /// Use the Content-Length header to find the end of the message body,
+    /// provided the result lands on the end of the mailbox or a separator
+    /// at the start of a line.
+    fn framed_end(&self, body_start: usize) -> Option<usize> {
+        let (header_end, length) = content_length(self.buf, body_start);
+        let end = header_end.checked_add(length?)?;
+        if end > self.buf.len() {
+            return None;
+        }
+
+        let mut next = end;
+        while next < self.buf.len() && (self.buf[next] == b'\r' ||
+                                        self.buf[next] == b'\n') {
+            next += 1;
+        }
+
+        if next == self.buf.len() {
+            return Some(end);
+        }
+        let line_start = next == 0 || self.buf[next - 1] == b'\n';
+        if line_start && self.buf[next..].starts_with(b"From ") {
+            Some(end)
+        } else {
+            None
+        }
+    }
+}
+
+
+impl<'a> Iterator for MboxReader<'a> {
+    type Item = MboxMessage<'a>;
+
+    fn next(&mut self) -> Option<MboxMessage<'a>> {
+        if self.pos >= self.buf.len() {
+            return None;
+        }
+
+        let offset = self.pos;
+        let body_start = if self.buf[offset..].starts_with(b"From ") {
+            line_end(self.buf, offset)
+        } else {
+            offset
+        };
+        let from_line = &self.buf[offset..body_start];
+
+        if self.format == MboxFormat::Mboxcl2 {
+            if let Some(end) = self.framed_end(body_start) {
+                self.pos = next_separator(self.buf, end);
+                return Some(MboxMessage {
+                    offset: offset,
+                    from_line: from_line,
+                    data: Cow::Borrowed(&self.buf[body_start..end]),
+                });
+            }
+        }
+
+        let end = next_separator(self.buf, body_start);
+        self.pos = end;
+
+        let data = strip_trailing_blank(&self.buf[body_start..end]);
+        Some(MboxMessage {
+            offset: offset,
+            from_line: from_line,
+            data: unquote(data, self.format),
+        })
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn read(buf: &[u8], format: MboxFormat) -> Vec<Vec<u8>> {
+        MboxReader::new(buf, format).map(|m| m.data.into_owned()).collect()
+    }
+
+    const QUOTED: &[u8] = b"From a@example.com Mon Jun  5 10:00:00 2023\n\
+Subject: one\n\
+\n\
+>From here\n\
+>>From there\n\
+\n\
+From b@example.com Mon Jun  5 11:00:00 2023\n\
+Subject: two\n\
+\n\
+body\n";
+
+    #[test]
+    fn mboxo_unquotes_one_level_of_from() {
+        let messages = read(QUOTED, MboxFormat::Mboxo);
+        assert_eq!(messages.len(), 2);
+        assert_eq!(messages[0], b"Subject: one\n\nFrom here\n>>From there\n");
+        assert_eq!(messages[1], b"Subject: two\n\nbody\n");
+    }
+
+    #[test]
+    fn mboxrd_unquotes_every_quoted_from() {
+        let messages = read(QUOTED, MboxFormat::Mboxrd);
+        assert_eq!(messages[0], b"Subject: one\n\nFrom here\n>From there\n");
+    }
+
+    #[test]
+    fn from_line_and_offsets() {
+        let mut reader = MboxReader::new(QUOTED, MboxFormat::Mboxrd);
+        let first = reader.next().unwrap();
+        assert_eq!(first.offset, 0);
+        assert_eq!(first.from_line, b"From a@example.com Mon Jun  5 10:00:00 2023\n");
+        assert!(matches!(first.data, Cow::Owned(_)));
+        let second = reader.next().unwrap();
+        assert_eq!(&QUOTED[second.offset..second.offset + 7], b"From b@");
+        assert!(matches!(second.data, Cow::Borrowed(_)));
+        assert!(reader.next().is_none());
+    }
+
+    #[test]
+    fn mboxcl2_frames_by_content_length() {
+        let buf = b"From a@example.com Mon Jun  5 10:00:00 2023\n\
+Content-Length: 16\n\
+\n\
+From the start.\n\
+\n\
+From b@example.com Mon Jun  5 11:00:00 2023\n\
+Content-Length: 3\n\
+\n\
+hi\n";
+        let messages = read(buf, MboxFormat::Mboxcl2);
+        assert_eq!(messages, vec![
+            b"Content-Length: 16\n\nFrom the start.\n".to_vec(),
+            b"Content-Length: 3\n\nhi\n".to_vec(),
+        ]);
+    }
+
+    #[test]
+    fn mboxcl2_falls_back_to_separators() {
+        // A Content-Length that does not end at a separator is ignored, and
+        // unquoted "From " lines in the body then split the message.
+        let buf = b"From a@example.com Mon Jun  5 10:00:00 2023\n\
+Content-Length: 5\n\
+\n\
+From the start.\n";
+        let messages = read(buf, MboxFormat::Mboxcl2);
+        assert_eq!(messages, vec![
+            b"Content-Length: 5\n".to_vec(),
+            b"".to_vec(),
+        ]);
+
+        // Nor is one that ends on "From " in the middle of a line.
+        let buf = b"From a@example.com Mon Jun  5 10:00:00 2023\n\
+Content-Length: 2\n\
+\n\
+x From y\n";
+        let messages = read(buf, MboxFormat::Mboxcl2);
+        assert_eq!(messages, vec![b"Content-Length: 2\n\nx From y\n".to_vec()]);
+    }
+}
@@ -0,0 +1,1303 @@
+use std::collections::BTreeMap;
+use std::collections::HashMap;
+use std::fmt;
+use std::path::Path;
+use std::sync::Arc;
+use std::sync::Mutex;
+
+use mailparse::body::Body::Base64;
+use mailparse::body::Body::Binary;
+use mailparse::body::Body::EightBit;
+use mailparse::body::Body::QuotedPrintable;
+use mailparse::body::Body::SevenBit;
+use mailparse::DispositionType::Attachment;
+use mailparse::DispositionType::Extension;
+use mailparse::DispositionType::FormData;
+use mailparse::DispositionType::Inline;
+use mailparse::MailHeaderMap;
+use mailparse::MailParseError;
+use owning_ref::OwningHandle;
+
+use crate::address;
+use crate::address::Address;
+use crate::authres;
+use crate::authres::ArcChain;
+use crate::authres::AuthResults;
+use crate::blocks;
+use crate::blocks::EncodedBlock;
+use crate::charset;
+use crate::charset::DecodedText;
+use crate::date;
+use crate::date::ParsedDate;
+use crate::defect;
+use crate::defect::Defect;
+use crate::defect::DefectKind;
+use crate::maildir::MaildirEntry;
+use crate::msgid::parse_msgids;
+use crate::mbox::MboxMessage;
+use crate::received;
+use crate::received::Hop;
+use crate::security;
+use crate::security::SecurePart;
+use crate::tnef;
+
+
+#[derive(Debug)]
+pub enum Error {
+    Io(std::io::Error),
+    Parse(MailParseError),
+}
+
+
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Error::Io(e) => e.fmt(f),
+            Error::Parse(e) => e.fmt(f),
+        }
+    }
+}
+
+
+impl std::error::Error for Error {}
+
+
+impl From<std::io::Error> for Error {
+    fn from(e: std::io::Error) -> Error {
+        Error::Io(e)
+    }
+}
+
+
+impl From<MailParseError> for Error {
+    fn from(e: MailParseError) -> Error {
+        Error::Parse(e)
+    }
+}
+
+
+pub(crate) fn slice_offset(parent: &[u8], child: &[u8]) -> usize {
+    (child.as_ptr() as usize) - (parent.as_ptr() as usize)
+}
+
+
+/// The bytes a message was parsed from. Mmap and External variants let a
+/// message borrow memory it does not own instead of copying it.
+pub enum Backing {
+    Owned(Vec<u8>),
+    Mmap(memmap2::Mmap),
+    /// Memory owned elsewhere, such as a Python buffer. It must not move or
+    /// change for as long as the value is alive.
+    External(Box<dyn AsRef<[u8]> + Send + Sync>),
+}
+
+
+impl Backing {
+    pub fn as_slice(&self) -> &[u8] {
+        match self {
+            Backing::Owned(v) => v.as_slice(),
+            Backing::Mmap(m) => &m[..],
+            Backing::External(e) => (**e).as_ref(),
+        }
+    }
+}
+
+
+/// How deeply message/rfc822 and message/global parts are parsed into
+/// nested messages by default.
+pub const DEFAULT_MAX_DEPTH: usize = 8;
+
+
+/// Options for `Message::parse_with` and `Message::from_backing_with`.
+#[derive(Clone, Debug)]
+pub struct ParseOptions {
+    /// Recover from a malformed header block instead of failing, in the
+    /// message and in the messages embedded in it; see
+    /// `Message::parse_lenient`.
+    pub lenient: bool,
+    /// Maximum nesting of message/rfc822 and message/global parts that are
+    /// parsed into child messages. 0 leaves them as opaque bodies.
+    pub max_depth: usize,
+}
+
+
+impl Default for ParseOptions {
+    fn default() -> ParseOptions {
+        ParseOptions {
+            lenient: false,
+            max_depth: DEFAULT_MAX_DEPTH,
+        }
+    }
+}
+
+
+/// A part that mailparse does not itself produce, attached as an extra
+/// child of a leaf part. Its bytes are either a slice of the backing or, if
+/// they had to be decoded first, owned by `data`.
+struct VirtualPart {
+    parsed: mailparse::ParsedMail<'static>,
+    /// Repairs lenient parsing made to `data`, with offsets into it.
+    defects: Vec<Defect>,
+    // Declared after `parsed` so that it is dropped last.
+    data: Option<Box<[u8]>>,
+}
+
+
+struct ParsedTree {
+    root: mailparse::ParsedMail<'static>,
+    /// Virtual children by the path of the part they belong to, parsed the
+    /// first time they are needed; see `_virtual_parts`. They are numbered
+    /// after the part's real subparts. Entries are never removed or
+    /// changed, so their elements stay where they are when the map grows.
+    virtual_parts: Mutex<HashMap<Vec<usize>, Vec<VirtualPart>>>,
+    max_depth: usize,
+    /// Whether embedded messages that fail to parse are repaired, as
+    /// `ParseOptions::lenient` does for the message itself.
+    lenient: bool,
+    /// The repaired copy of the backing that `root` was parsed from, if
+    /// lenient parsing had to repair it. Declared after `root` and
+    /// `virtual_parts` so that it is dropped last.
+    repaired: Option<Box<[u8]>>,
+}
+
+
+fn is_message_type(mime_type: &str) -> bool {
+    mime_type.eq_ignore_ascii_case("message/rfc822") ||
+    mime_type.eq_ignore_ascii_case("message/global")
+}
+
+
+/// Parse `data` into a virtual part that owns it.
+fn owned_part(data: Vec<u8>) -> Option<VirtualPart> {
+    let data = data.into_boxed_slice();
+    // The heap allocation does not move when the Box does, and VirtualPart
+    // drops `parsed` before `data`.
+    let slice: &'static [u8] = unsafe {
+        std::slice::from_raw_parts(data.as_ptr(), data.len())
+    };
+    mailparse::parse_mail(slice).ok().map(|parsed| VirtualPart {
+        parsed: parsed,
+        defects: Vec::new(),
+        data: Some(data),
+    })
+}
+
+
+/// `data`, which does not parse, repaired as `repair` does a whole message
+/// and parsed into a virtual part that owns the repaired copy.
+fn repaired_part(data: &[u8]) -> Option<VirtualPart> {
+    let mut defects = Vec::new();
+    let data = repair(data, 0, &mut defects);
+    let mut part = owned_part(data.into_vec())?;
+    part.defects = defects;
+    Some(part)
+}
+
+
+/// Parse the body of a message/rfc822 or message/global part. Bodies in
+/// identity encodings are parsed in place; others are decoded into a copy.
+/// If `lenient`, one that does not parse is parsed from a repaired copy
+/// instead.
+fn parse_embedded(pm: &mailparse::ParsedMail, lenient: bool) -> Option<VirtualPart> {
+    let raw = match pm.get_body_encoded() {
+        SevenBit(tb) => tb.get_raw(),
+        EightBit(tb) => tb.get_raw(),
+        Binary(bb) => bb.get_raw(),
+        Base64(_) | QuotedPrintable(_) => {
+            let data = pm.get_body_raw().ok()?;
+            if lenient && mailparse::parse_mail(&data).is_err() {
+                return repaired_part(&data);
+            }
+            return owned_part(data);
+        }
+    };
+
+    // `raw` borrows from the same buffer as `pm`, which get_body_encoded()
+    // does not let the signature say.
+    let raw: &'static [u8] = unsafe {
+        std::slice::from_raw_parts(raw.as_ptr(), raw.len())
+    };
+    match mailparse::parse_mail(raw) {
+        Ok(parsed) => Some(VirtualPart {
+            parsed: parsed,
+            defects: Vec::new(),
+            data: None,
+        }),
+        Err(_) if lenient => repaired_part(raw),
+        Err(_) => None
+    }
+}
+
+
+/// Decode a TNEF part into one virtual part per body and attachment.
+fn parse_tnef(pm: &mailparse::ParsedMail) -> Vec<VirtualPart> {
+    let data = match pm.get_body_raw() {
+        Ok(d) => d,
+        Err(_) => return Vec::new()
+    };
+
+    match tnef::parse(&data) {
+        Some(t) => tnef::to_mime(&t).into_iter().filter_map(owned_part).collect(),
+        None => Vec::new()
+    }
+}
+
+
+/// Decode the files embedded in a text/plain part into one virtual part
+/// each.
+fn parse_blocks(pm: &mailparse::ParsedMail) -> Vec<VirtualPart> {
+    let text = match pm.get_body_raw() {
+        Ok(t) => t,
+        Err(_) => return Vec::new()
+    };
+
+    blocks::find_blocks(&text).iter()
+        .map(|b| tnef::attachment_entity(b.filename.as_deref(), None, None, &b.data))
+        .filter_map(owned_part)
+        .collect()
+}
+
+
+pub(crate) fn part_filename(pm: &mailparse::ParsedMail) -> Option<String> {
+    match pm.get_content_disposition().params.get("filename") {
+        Some(s) => Some(s.clone()),
+        None => pm.ctype.params.get("name").cloned()
+    }
+}
+
+
+/// Parse the contents of leaf part `pm` into virtual children: the message
+/// inside a message/rfc822 or message/global part, unless `depth` such
+/// messages already enclose it (repaired if need be when `lenient`), the
+/// contents of a TNEF part, or the uuencoded, yEnc and BinHex files in a
+/// text/plain part.
+fn embedded_children(pm: &mailparse::ParsedMail, depth: usize, max_depth: usize, lenient: bool)
+    -> Vec<VirtualPart>
+{
+    if !pm.subparts.is_empty() {
+        return Vec::new();
+    }
+
+    let mime_type = &pm.ctype.mimetype;
+    if is_message_type(mime_type) {
+        if depth >= max_depth {
+            return Vec::new();
+        }
+        parse_embedded(pm, lenient).into_iter().collect()
+    } else if tnef::is_tnef(mime_type, part_filename(pm).as_deref()) {
+        parse_tnef(pm)
+    } else if mime_type.eq_ignore_ascii_case("text/plain") {
+        parse_blocks(pm)
+    } else {
+        Vec::new()
+    }
+}
+
+
+/// Like mailparse's own search for a boundary: the first occurrence of
+/// `key` at or after `from` that starts a line, or starts at `from`.
+fn find_line_prefix(raw: &[u8], from: usize, key: &[u8]) -> Option<usize> {
+    let mut start = from;
+    while let Some(i) = raw[start..].windows(key.len()).position(|w| w == key) {
+        let ix = start + i;
+        if ix == from || raw[ix - 1] == b'\n' {
+            return Some(ix);
+        }
+        start = ix + 1;
+    }
+    None
+}
+
+
+/// Offset in `raw` of the line that makes `mailparse::parse_mail(raw)`
+/// fail: one that cannot start a header field, in the header block of
+/// `raw` or of any multipart subpart mailparse would recurse into.
+fn find_unparseable(raw: &[u8]) -> Option<usize> {
+    let mut ix = 0;
+    while ix < raw.len() {
+        match raw[ix] {
+            b'\n' => break,
+            b'\r' if raw.get(ix + 1) == Some(&b'\n') => break,
+            b'\r' => return Some(ix),
+            _ => {}
+        }
+        match mailparse::parse_header(&raw[ix..]) {
+            Ok((_, n)) => ix += n,
+            Err(_) => return Some(ix)
+        }
+    }
+
+    let (headers, ix_body) = mailparse::parse_headers(raw).ok()?;
+    let ctype = mailparse::parse_content_type(&headers.get_first_value("Content-Type")?);
+    let boundary = match ctype.params.get("boundary") {
+        Some(b) if ctype.mimetype.starts_with("multipart/") && raw.len() > ix_body => {
+            format!("--{}", b)
+        },
+        _ => return None
+    };
+    let boundary = boundary.as_bytes();
+
+    let mut end = find_line_prefix(raw, ix_body, boundary)? + boundary.len();
+    while let Some(start) = raw[end..].iter().position(|&b| b == b'\n').map(|i| end + i + 1) {
+        let stop = find_line_prefix(raw, start, boundary).unwrap_or(raw.len());
+        if let Some(i) = find_unparseable(&raw[start..stop]) {
+            return Some(start + i);
+        }
+        end = stop + boundary.len();
+        if end + 2 > raw.len() || raw[end..].starts_with(b"--") {
+            break;
+        }
+    }
+    None
+}
+
+
+/// A copy of `buf` in which the first byte of each line that stops
+/// `buf[skip..]` from parsing is replaced by a line feed, ending the header
+/// block of the part it is in. The copy is as long as `buf`, so offsets
+/// into one are offsets into the other. Each repair is recorded in
+/// `defects`.
+fn repair(buf: &[u8], skip: usize, defects: &mut Vec<Defect>) -> Box<[u8]> {
+    let mut data = buf.to_vec().into_boxed_slice();
+    loop {
+        let err = match mailparse::parse_mail(&data[skip..]) {
+            Ok(_) => return data,
+            Err(e) => e
+        };
+        let i = match find_unparseable(&data[skip..]) {
+            Some(i) => skip + i,
+            None => return data
+        };
+        defects.push(Defect::new(DefectKind::UnparseableHeaders, i, err.to_string()));
+        data[i] = b'\n';
+    }
+}
+
+
+pub(crate) struct MailStorage {
+    handle: OwningHandle<
+        Box<Backing>,
+        Box<ParsedTree>
+    >,
+    from_line: Box<Vec<u8>>,
+    flags: Option<String>,
+    /// Defects found while parsing, as opposed to by `defect::check`.
+    defects: Vec<Defect>
+}
+
+
+/// A parsed message, or one part of it. Parts share the storage of the
+/// message they belong to and are addressed by their path of subpart
+/// indices from the root.
+#[derive(Clone)]
+pub struct Message {
+    storage: Arc<MailStorage>,
+    path: Vec<usize>
+}
+
+
+/// One header field as it appears in the message.
+pub struct HeaderField<'a> {
+    /// Decoded field name.
+    pub name: String,
+    pub raw_key: &'a [u8],
+    /// The value exactly as stored, including any folding.
+    pub raw_value: &'a [u8],
+    /// The value unfolded and with RFC 2047 encoded-words decoded.
+    pub value: String,
+    /// Span of the whole field in the buffer, from the first byte of the name
+    /// through the line ending that terminates it.
+    pub offset: [usize; 2],
+}
+
+
+/// The header block of a `Message` part.
+#[derive(Clone)]
+pub struct MessageHeaders {
+    storage: Arc<MailStorage>,
+    path: Vec<usize>
+}
+
+
+/// A located part: its mailparse node, the buffer its offsets are relative
+/// to (the backing, or the decoded body of the nearest enclosing virtual
+/// part that had to be decoded), and how many embedded messages enclose it.
+#[derive(Clone, Copy)]
+pub(crate) struct Node<'a> {
+    pub pm: &'a mailparse::ParsedMail<'a>,
+    pub buf: &'a [u8],
+    depth: usize,
+}
+
+
+fn _locate<'a>(storage: &'a MailStorage, path: &[usize]) -> Node<'a> {
+    let tree: &ParsedTree = &storage.handle;
+    let mut node = Node {
+        pm: &tree.root,
+        buf: _root_buffer(storage),
+        depth: 0,
+    };
+
+    for (depth, &i) in path.iter().enumerate() {
+        node = _child(tree, &path[..depth], node, i);
+    }
+
+    node
+}
+
+
+/// Child `i` of `node`, the part at `path`.
+fn _child<'a>(tree: &'a ParsedTree, path: &[usize], node: Node<'a>, i: usize)
+    -> Node<'a>
+{
+    let n = node.pm.subparts.len();
+    if i < n {
+        return Node { pm: &(node.pm.subparts[i]), ..node };
+    }
+
+    let vp = &_virtual_parts(tree, path, node)[i - n];
+    let depth = if is_message_type(&node.pm.ctype.mimetype) {
+        node.depth + 1
+    } else {
+        node.depth
+    };
+    match &vp.data {
+        Some(data) => Node { pm: &vp.parsed, buf: data, depth: depth },
+        None => Node { pm: &vp.parsed, buf: node.buf, depth: depth }
+    }
+}
+
+
+/// The virtual children of `node`, the part at `path`, parsing them on
+/// first use so that messages nobody descends into cost nothing extra.
+fn _virtual_parts<'a>(tree: &'a ParsedTree, path: &[usize], node: Node)
+    -> &'a [VirtualPart]
+{
+    let mut map = tree.virtual_parts.lock().unwrap_or_else(|e| e.into_inner());
+    let parts: &[VirtualPart] = map.entry(path.to_vec())
+        .or_insert_with(|| embedded_children(node.pm, node.depth, tree.max_depth, tree.lenient));
+    // The elements are heap allocated and stay in place, unchanged, for as
+    // long as the tree lives.
+    unsafe { std::slice::from_raw_parts(parts.as_ptr(), parts.len()) }
+}
+
+
+/// The repairs lenient parsing made to `buf`: the root buffer, or the copy
+/// of an embedded message that had to be repaired.
+fn _repairs(storage: &MailStorage, buf: &[u8]) -> Vec<Defect> {
+    if std::ptr::eq(buf, _root_buffer(storage)) {
+        return storage.defects.clone();
+    }
+    let map = storage.handle.virtual_parts.lock().unwrap_or_else(|e| e.into_inner());
+    map.values().flatten()
+        .find(|vp| vp.data.as_deref().is_some_and(|d| std::ptr::eq(d, buf)))
+        .map(|vp| vp.defects.clone())
+        .unwrap_or_default()
+}
+
+
+/// The buffer the root was parsed from: the backing, or its repaired copy.
+fn _root_buffer(storage: &MailStorage) -> &[u8] {
+    match &storage.handle.repaired {
+        Some(data) => data,
+        None => storage.handle.as_owner().as_slice()
+    }
+}
+
+
+fn _part<'a>(storage: &'a MailStorage, path: &[usize])
+    -> &'a mailparse::ParsedMail<'a>
+{
+    _locate(storage, path).pm
+}
+
+
+fn _subpath(path: &Vec<usize>, i: usize) -> Vec<usize> {
+    let mut new = path.clone();
+    new.push(i);
+    new
+}
+
+
+/// The header fields of `pm`, with offsets relative to `buf`; see
+/// `MessageHeaders::fields`.
+pub(crate) fn header_fields<'a>(pm: &'a mailparse::ParsedMail<'a>, buf: &'a [u8])
+    -> Vec<HeaderField<'a>>
+{
+    let raw = pm.raw_bytes;
+    let limit = slice_offset(buf, raw) + raw.len();
+    let mut out = Vec::new();
+
+    for header in &pm.headers {
+        let key = header.get_key_raw();
+        let value = header.get_value_raw();
+        let start = slice_offset(buf, key);
+
+        // An empty value does not say where on the line it is, so start
+        // from the name and scan to the end of the line, then over any
+        // continuation lines.
+        let mut end = if value.is_empty() {
+            start + key.len()
+        } else {
+            slice_offset(buf, value) + value.len()
+        };
+        loop {
+            match buf[end..limit].iter().position(|&b| b == b'\n') {
+                Some(i) => end += i + 1,
+                None => {
+                    end = limit;
+                    break;
+                }
+            }
+            if !matches!(buf.get(end), Some(b' ') | Some(b'\t')) || end >= limit {
+                break;
+            }
+        }
+
+        out.push(HeaderField {
+            name: header.get_key(),
+            raw_key: key,
+            raw_value: value,
+            value: header.get_value(),
+            offset: [start, end - start],
+        });
+    }
+
+    out
+}
+
+
+pub(crate) fn disposition_name(pm: &mailparse::ParsedMail) -> String {
+    match pm.get_content_disposition().disposition {
+        Inline => "inline".to_string(),
+        Attachment => "attachment".to_string(),
+        FormData => "formdata".to_string(),
+        Extension(s) => s,
+    }
+}
+
+
+pub(crate) fn encoding_name(pm: &mailparse::ParsedMail) -> &'static str {
+    match pm.get_body_encoded() {
+        Base64(_) => "base64",
+        QuotedPrintable(_) => "quotedprintable",
+        SevenBit(_) => "7bit",
+        EightBit(_) => "8bit",
+        Binary(_) => "binary",
+    }
+}
+
+
+/// The body of `pm` before transfer decoding.
+pub(crate) fn encoded_body<'a>(pm: &'a mailparse::ParsedMail<'a>) -> &'a [u8] {
+    match pm.get_body_encoded() {
+        Base64(eb) => eb.get_raw(),
+        QuotedPrintable(eb) => eb.get_raw(),
+        SevenBit(tb) => tb.get_raw(),
+        EightBit(tb) => tb.get_raw(),
+        Binary(bb) => bb.get_raw(),
+    }
+}
+
+
+pub(crate) fn read_off_from_line(buf: &[u8]) -> (&[u8], &[u8])
+{
+    if !buf.starts_with("From ".as_bytes()) {
+        return (&[], buf);
+    }
+
+    match buf.iter().position(|&b| b == b'\n') {
+        Some(i) => (&buf[..i+1], &buf[i+1..]),
+        None => (&[], buf)
+    }
+}
+
+
+impl MessageHeaders {
+    /// The part these headers belong to.
+    pub fn part(&self) -> &mailparse::ParsedMail<'_> {
+        _part(&self.storage, &self.path)
+    }
+
+    pub fn offset(&self) -> [usize; 2] {
+        let sl = self.raw_bytes();
+        return [slice_offset(self.buffer(), sl), sl.len()];
+    }
+
+    /// The buffer offsets are relative to; see `Message::buffer`.
+    pub fn buffer(&self) -> &[u8] {
+        _locate(&self.storage, &self.path).buf
+    }
+
+    pub fn raw_bytes(&self) -> &[u8] {
+        let headers = self.part().get_headers();
+        headers.get_raw_bytes()
+    }
+
+    /// Every header field in its original order.
+    pub fn fields(&self) -> Vec<HeaderField<'_>> {
+        header_fields(self.part(), self.buffer())
+    }
+
+    pub fn fromline(&self) -> Option<&[u8]> {
+        let pm = self.part();
+        if pm.headers.len() == 0 {
+            return None;
+        }
+
+        let key = pm.headers[0].get_key_raw();
+        if key.starts_with(b"From ") {
+            return Some(key);
+        }
+
+        None
+    }
+
+    pub fn first(&self, key: &str) -> Option<String> {
+        let headers = self.part().get_headers();
+        headers.get_first_value(key)
+    }
+
+    pub fn all(&self, key: &str) -> Vec<String> {
+        let headers = self.part().get_headers();
+        headers.get_all_values(key)
+    }
+
+    pub fn names(&self) -> Vec<String> {
+        let headers = self.part().get_headers();
+        headers.into_iter().map(|h| h.get_key()).collect()
+    }
+
+    /// Every `key` header, decoded with `charset::decode_header` rather than
+    /// mailparse's narrower charset table. With `sniff`, undeclared 8-bit
+    /// values are run through the charset detector.
+    pub fn decoded(&self, key: &str, sniff: bool) -> Vec<String> {
+        let headers = self.part().get_headers();
+        headers.get_all_headers(key).iter()
+            .map(|h| charset::decode_header(h.get_value_raw(), sniff))
+            .collect()
+    }
+
+    /// Addresses from the first `key` header, or an empty list if there is
+    /// no such header.
+    pub fn first_address(&self, key: &str) -> Result<Vec<Address>, MailParseError> {
+        let headers = self.part().get_headers();
+        match headers.get_first_header(key) {
+            None => Ok(Vec::new()),
+            Some(header) => address::parse_header(header)
+        }
+    }
+
+    /// Addresses from every `key` header, in order.
+    pub fn addresses(&self, key: &str) -> Result<Vec<Address>, MailParseError> {
+        let headers = self.part().get_headers();
+        let mut out = Vec::new();
+        for header in headers.get_all_headers(key) {
+            out.extend(address::parse_header(header)?);
+        }
+        Ok(out)
+    }
+
+    /// msg-ids from every `key` header (Message-ID, In-Reply-To,
+    /// References, ...), in order and without angle brackets.
+    pub fn message_ids(&self, key: &str) -> Vec<String> {
+        let mut ids = Vec::new();
+        for value in self.all(key) {
+            ids.extend(parse_msgids(&value));
+        }
+        ids
+    }
+
+    /// Leniently parse the first `key` header as a date. Ok(None) means the
+    /// header is absent.
+    pub fn date(&self, key: &str) -> Result<Option<ParsedDate>, MailParseError> {
+        match self.first(key) {
+            None => Ok(None),
+            Some(value) => match date::parse_date(&value) {
+                Some(d) => Ok(Some(d)),
+                None => Err(MailParseError::Generic("unparseable date"))
+            }
+        }
+    }
+
+    /// The Received fields as relay hops in delivery order, oldest first.
+    pub fn received_hops(&self) -> Vec<Hop> {
+        received::hops(&self.all("Received"))
+    }
+
+    /// Each Authentication-Results field parsed per RFC 8601, in order; a
+    /// field that cannot be parsed gives Err with a description.
+    pub fn authentication_results(&self) -> Vec<Result<AuthResults, String>> {
+        self.all("Authentication-Results").iter()
+            .map(|v| authres::parse_authentication_results(v))
+            .collect()
+    }
+
+    /// The ARC sets in these headers and any structural problems with the
+    /// chain. Seals and signatures are not verified.
+    pub fn arc_chain(&self) -> ArcChain {
+        authres::arc_chain(self)
+    }
+
+    /// Every address header listed in `address::ADDRESS_HEADERS`, as
+    /// `(name, addresses)` pairs in the order they appear.
+    pub fn all_addresses(&self) -> Result<Vec<(String, Vec<Address>)>, MailParseError> {
+        let headers = self.part().get_headers();
+        let mut out = Vec::new();
+        for header in headers {
+            let key = header.get_key();
+            if address::ADDRESS_HEADERS.iter().any(|h| h.eq_ignore_ascii_case(&key)) {
+                out.push((key, address::parse_header(header)?));
+            }
+        }
+        Ok(out)
+    }
+}
+
+
+impl ParsedTree {
+    fn new(root: mailparse::ParsedMail<'static>, repaired: Option<Box<[u8]>>,
+           options: &ParseOptions) -> Box<ParsedTree>
+    {
+        Box::new(ParsedTree {
+            root: root,
+            virtual_parts: Mutex::new(HashMap::new()),
+            max_depth: options.max_depth,
+            lenient: options.lenient,
+            repaired: repaired,
+        })
+    }
+}
+
+
+impl Message {
+    /// Parse a copy of `buf`, which may begin with an mbox "From " line.
+    ///
+    /// Offsets from every constructor are relative to the buffer the caller
+    /// supplied, including any "From " line: `buf` here, the backing or file
+    /// for `from_backing` and `from_path`, the file for Maildir entries, and
+    /// the message's separator line followed by its data for mbox messages.
+    pub fn parse(buf: &[u8]) -> Result<Message, MailParseError> {
+        Message::parse_with(buf, &ParseOptions::default())
+    }
+
+    /// Like `parse`, but never fails. A line that cannot start a header
+    /// field ends the header block of the part it is in, and is read as the
+    /// start of that part's body; the part gets an `UnparseableHeaders`
+    /// defect and the rest of the tree is parsed as usual. The message is
+    /// then parsed from a copy of the buffer in which the first byte of
+    /// each such line is a line feed, and `buffer()` returns that copy.
+    pub fn parse_lenient(buf: &[u8]) -> Message {
+        let options = ParseOptions { lenient: true, ..ParseOptions::default() };
+        Message::parse_with(buf, &options).expect("lenient parse failed")
+    }
+
+    pub fn parse_with(buf: &[u8], options: &ParseOptions) -> Result<Message, MailParseError> {
+        let from_line = read_off_from_line(buf).0.to_vec();
+        Message::build(Backing::Owned(buf.to_vec()), from_line.len(), from_line, None, options)
+    }
+
+    /// Parse `backing` in place without copying it. Offsets are relative to
+    /// the start of `backing`, including any leading "From " line.
+    pub fn from_backing(backing: Backing) -> Result<Message, MailParseError> {
+        Message::from_backing_with(backing, &ParseOptions::default())
+    }
+
+    /// `from_backing` with the recovery of `parse_lenient`.
+    pub fn from_backing_lenient(backing: Backing) -> Message {
+        let options = ParseOptions { lenient: true, ..ParseOptions::default() };
+        Message::from_backing_with(backing, &options).expect("lenient parse failed")
+    }
+
+    pub fn from_backing_with(backing: Backing, options: &ParseOptions)
+        -> Result<Message, MailParseError>
+    {
+        let from_line = read_off_from_line(backing.as_slice()).0.to_vec();
+        Message::build(backing, from_line.len(), from_line, None, options)
+    }
+
+    /// Parse the file at `path` via a read-only memory map. The file must not
+    /// be truncated while the result is alive.
+    pub fn from_path<P: AsRef<Path>>(path: P) -> Result<Message, Error> {
+        Message::from_path_with(path, &ParseOptions::default())
+    }
+
+    /// `from_path` with the recovery of `parse_lenient`; only I/O can fail.
+    pub fn from_path_lenient<P: AsRef<Path>>(path: P) -> Result<Message, Error> {
+        let options = ParseOptions { lenient: true, ..ParseOptions::default() };
+        Message::from_path_with(path, &options)
+    }
+
+    pub fn from_path_with<P: AsRef<Path>>(path: P, options: &ParseOptions)
+        -> Result<Message, Error>
+    {
+        let file = std::fs::File::open(path)?;
+        let mmap = unsafe { memmap2::Mmap::map(&file)? };
+        Ok(Message::from_backing_with(Backing::Mmap(mmap), options)?)
+    }
+
+    /// Parse a message read from a mailbox. Offsets count from the start of
+    /// its "From " line, so adding `MboxMessage::offset` gives a position in
+    /// the mailbox unless unquoting changed the message's length.
+    pub fn from_mbox_message(msg: MboxMessage) -> Result<Message, MailParseError> {
+        let from_line = msg.from_line.to_vec();
+        let mut buf = Vec::with_capacity(from_line.len() + msg.data.len());
+        buf.extend_from_slice(&from_line);
+        buf.extend_from_slice(&msg.data);
+        Message::build(Backing::Owned(buf), from_line.len(), from_line, None,
+                       &ParseOptions::default())
+    }
+
+    /// Read and parse a Maildir message, recording its flags.
+    pub fn from_maildir_entry(entry: &MaildirEntry) -> Result<Message, Error> {
+        let buf = std::fs::read(&entry.path)?;
+        let from_line = read_off_from_line(&buf).0.to_vec();
+        Ok(Message::build(Backing::Owned(buf), from_line.len(), from_line,
+                          Some(entry.flags.clone()), &ParseOptions::default())?)
+    }
+
+    /// Parse `backing`, skipping its first `skip` bytes (a "From " line that
+    /// is kept separately as `from_line`). If `options.lenient`, a parse
+    /// failure is retried on a repaired copy of the backing (see `repair`),
+    /// then on an empty slice at the end of it, which cannot fail.
+    fn build(backing: Backing, skip: usize, from_line: Vec<u8>,
+             flags: Option<String>, options: &ParseOptions)
+        -> Result<Message, MailParseError>
+    {
+        let mut defects = Vec::new();
+        let handle = OwningHandle::try_new(
+            Box::new(backing),
+            unsafe {
+                |x| {
+                    let buf = (*x).as_slice();
+                    match mailparse::parse_mail(&buf[skip..]) {
+                        Ok(pm) => return Ok(ParsedTree::new(pm, None, options)),
+                        Err(_) if options.lenient => {},
+                        Err(e) => return Err(e)
+                    };
+
+                    let data = repair(buf, skip, &mut defects);
+                    // The heap allocation does not move when the Box does,
+                    // and ParsedTree drops `root` before `repaired`.
+                    let copy: &'static [u8] = std::slice::from_raw_parts(data.as_ptr(), data.len());
+                    mailparse::parse_mail(&copy[skip..])
+                        .or_else(|_| mailparse::parse_mail(&copy[copy.len()..]))
+                        .map(|pm| ParsedTree::new(pm, Some(data), options))
+                }
+            }
+        )?;
+
+        Ok(Message {
+            storage: Arc::new(
+                MailStorage {
+                    handle: handle,
+                    from_line: Box::new(from_line),
+                    flags: flags,
+                    defects: defects
+                }
+            ),
+            path: vec![]
+        })
+    }
+
+    /// The underlying mailparse part.
+    pub fn part(&self) -> &mailparse::ParsedMail<'_> {
+        _part(&self.storage, &self.path)
+    }
+
+    /// The buffer this part's offsets are relative to: normally the
+    /// complete buffer the message was parsed from, but for parts decoded
+    /// out of another (a transfer-encoded message/global, TNEF contents),
+    /// or an embedded message lenient parsing had to repair, a buffer of
+    /// their own.
+    pub fn buffer(&self) -> &[u8] {
+        _locate(&self.storage, &self.path).buf
+    }
+
+    /// Whether `buffer()` is the buffer the message was parsed from (or
+    /// its repaired copy, which has the same length), so offsets can be used
+    /// to index into it.
+    pub fn in_original_buffer(&self) -> bool {
+        std::ptr::eq(self.buffer(), _root_buffer(&self.storage))
+    }
+
+    pub fn offset(&self) -> [usize; 2] {
+        let sl = self.part().raw_bytes;
+        return [slice_offset(self.buffer(), sl), sl.len()];
+    }
+
+    pub fn from_line(&self) -> &[u8] {
+        (*self.storage.from_line).as_slice()
+    }
+
+    pub fn raw_bytes(&self) -> &[u8] {
+        self.part().raw_bytes
+    }
+
+    /// Maildir flags of the file the message was read from, or None if it
+    /// did not come from a Maildir.
+    pub fn flags(&self) -> Option<&str> {
+        self.storage.flags.as_deref()
+    }
+
+    pub fn body_offset(&self) -> [usize; 2] {
+        let sl = self.body_encoded();
+        return [slice_offset(self.buffer(), sl), sl.len()];
+    }
+
+    /// Number of children, including the embedded message of a
+    /// message/rfc822 or message/global part, which is its only child, and
+    /// the bodies and attachments decoded from a TNEF part, and the files
+    /// embedded in a text/plain part (see `encoded_blocks`).
+    pub fn subpart_count(&self) -> usize {
+        self.child_count(_locate(&self.storage, &self.path))
+    }
+
+    /// The message embedded in a message/rfc822 or message/global part, if
+    /// it was parsed.
+    pub fn embedded_message(&self) -> Option<Message> {
+        if is_message_type(self.mime_type()) && self.part().subparts.is_empty() {
+            self.subpart(0)
+        } else {
+            None
+        }
+    }
+
+    pub fn mime_type(&self) -> &String {
+        &(self.part().ctype.mimetype)
+    }
+
+    pub fn charset(&self) -> &String {
+        &(self.part().ctype.charset)
+    }
+
+    pub fn params(&self) -> &BTreeMap<String, String> {
+        &self.part().ctype.params
+    }
+
+    pub fn param(&self, k: &str) -> Option<&String> {
+        self.part().ctype.params.get(k)
+    }
+
+    pub fn content_disposition(&self) -> String {
+        disposition_name(self.part())
+    }
+
+    pub fn filename(&self) -> Option<String> {
+        part_filename(self.part())
+    }
+
+    pub fn path(&self) -> &[usize] {
+        &self.path
+    }
+
+    pub fn headers(&self) -> MessageHeaders {
+        MessageHeaders {
+            storage: self.storage.clone(),
+            path: self.path.clone()
+        }
+    }
+
+    pub fn subpart(&self, i: usize) -> Option<Message> {
+        if i >= self.subpart_count() {
+            return None;
+        }
+
+        Some(Message {
+            storage: self.storage.clone(),
+            path: _subpath(&self.path, i)
+        })
+    }
+
+    /// The part containing this one, or None for the root.
+    pub fn parent(&self) -> Option<Message> {
+        if self.path.is_empty() {
+            return None;
+        }
+
+        Some(Message {
+            storage: self.storage.clone(),
+            path: self.path[..self.path.len()-1].to_vec()
+        })
+    }
+
+    pub fn subparts(&self) -> Vec<Message> {
+        (0..self.subpart_count())
+            .filter_map(|i| self.subpart(i))
+            .collect()
+    }
+
+    /// Look up a descendant part by its path relative to this one.
+    pub fn descendant(&self, path: &[usize]) -> Option<Message> {
+        let mut part = self.clone();
+        for &i in path {
+            part = part.subpart(i)?;
+        }
+        Some(part)
+    }
+
+    pub fn body(&self) -> Result<String, MailParseError> {
+        self.part().get_body()
+    }
+
+    pub fn body_raw(&self) -> Result<Vec<u8>, MailParseError> {
+        self.part().get_body_raw()
+    }
+
+    /// uuencoded, yEnc and BinHex files embedded in this part's body, if it
+    /// is text/plain. They are also its subparts, in the same order.
+    pub fn encoded_blocks(&self) -> Vec<EncodedBlock> {
+        if !self.mime_type().eq_ignore_ascii_case("text/plain") ||
+           !self.part().subparts.is_empty() {
+            return Vec::new();
+        }
+        match self.body_raw() {
+            Ok(text) => blocks::find_blocks(&text),
+            Err(_) => Vec::new()
+        }
+    }
+
+    /// PGP/MIME, S/MIME and inline PGP structure in this part and its
+    /// descendants; see `security::detect`.
+    pub fn security(&self) -> Vec<SecurePart> {
+        security::detect(self)
+    }
+
+    /// The charset parameter exactly as declared, unlike `charset()` which
+    /// falls back to "us-ascii".
+    pub fn declared_charset(&self) -> Option<&String> {
+        self.part().ctype.params.get("charset")
+    }
+
+    /// The transfer-decoded body decoded as text through the WHATWG encoding
+    /// set; see `charset::decode`. `DecodedText::charset` reports the
+    /// charset that was actually used.
+    pub fn body_text(&self, sniff: bool) -> Result<DecodedText, MailParseError> {
+        let raw = self.body_raw()?;
+        Ok(charset::decode(&raw, self.declared_charset().map(|s| s.as_str()), sniff))
+    }
+
+    /// Defects in this part (not its subparts), including any recovered from
+    /// in lenient parsing. See `defect::check`.
+    pub fn defects(&self) -> Vec<Defect> {
+        // A repair ends the header block it is in, so each one lies in the
+        // header block of exactly one part.
+        let [start, len] = self.headers().offset();
+        let mut out: Vec<Defect> = _repairs(&self.storage, self.buffer()).into_iter()
+            .filter(|d| d.offset >= start && d.offset < start + len)
+            .collect();
+        out.extend(defect::check(self));
+        out
+    }
+
+    pub fn body_encoding(&self) -> &'static str {
+        encoding_name(self.part())
+    }
+
+    pub fn body_encoded(&self) -> &[u8] {
+        encoded_body(self.part())
+    }
+
+    /// The body before transfer decoding, up to the end of the part. For a
+    /// multipart `body_encoded` is only the preamble; this includes the
+    /// delimiters and subparts too.
+    pub(crate) fn whole_body(&self) -> &[u8] {
+        let raw = self.part().raw_bytes;
+        &raw[slice_offset(raw, self.body_encoded())..]
+    }
+
+    /// The located node of this part, for traversals that walk the tree
+    /// themselves.
+    pub(crate) fn node(&self) -> Node<'_> {
+        _locate(&self.storage, &self.path)
+    }
+
+    /// `subpart_count`, given this part's node.
+    pub(crate) fn child_count(&self, node: Node) -> usize {
+        if !node.pm.subparts.is_empty() {
+            return node.pm.subparts.len();
+        }
+        _virtual_parts(&self.storage.handle, &self.path, node).len()
+    }
+
+    /// Subpart `i` together with its node, given this part's own, so that a
+    /// traversal holding it need not look each child up again from the
+    /// root.
+    pub(crate) fn child_node<'a>(&'a self, node: Node<'a>, i: usize) -> (Message, Node<'a>) {
+        let sub = Message {
+            storage: self.storage.clone(),
+            path: _subpath(&self.path, i)
+        };
+        (sub, _child(&self.storage.handle, &self.path, node, i))
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn spans(buf: &[u8]) -> Vec<(String, Vec<u8>)> {
+        let msg = Message::parse(buf).unwrap();
+        let headers = msg.headers();
+        headers.fields().iter()
+            .map(|f| (f.name.clone(), buf[f.offset[0]..f.offset[0]+f.offset[1]].to_vec()))
+            .collect()
+    }
+
+    #[test]
+    fn field_spans_cover_whole_lines() {
+        let buf = b"Subject:\r\nX-Blank: \r\nX-Folded: one\r\n\ttwo\r\n three\r\nX-Empty-Folded:\r\n \r\nTo: a@b.c\r\n\r\nbody\r\n";
+        let fields = spans(buf);
+        let expected: Vec<(&str, &[u8])> = vec![
+            ("Subject", b"Subject:\r\n"),
+            ("X-Blank", b"X-Blank: \r\n"),
+            ("X-Folded", b"X-Folded: one\r\n\ttwo\r\n three\r\n"),
+            ("X-Empty-Folded", b"X-Empty-Folded:\r\n \r\n"),
+            ("To", b"To: a@b.c\r\n"),
+        ];
+        assert_eq!(fields.len(), expected.len());
+        for ((name, span), (want_name, want_span)) in fields.iter().zip(expected) {
+            assert_eq!(name, want_name);
+            assert_eq!(span.as_slice(), want_span);
+        }
+    }
+
+    #[test]
+    fn field_spans_with_bare_lf_and_from_line() {
+        let buf = b"From x@y Mon Jan  1 00:00:00 2024\nSubject:\nX-A: 1\n\nbody";
+        let fields = spans(buf);
+        assert_eq!(fields[0].1, b"Subject:\n");
+        assert_eq!(fields[1].1, b"X-A: 1\n");
+    }
+
+    #[test]
+    fn field_spans_in_subparts_stop_at_part_end() {
+        let buf = b"Content-Type: multipart/mixed; boundary=b\r\n\r\n--b\r\nX-Last:\r\n\r\nbody\r\n--b--\r\n";
+        let msg = Message::parse(buf).unwrap();
+        let sub = msg.subpart(0).unwrap();
+        let headers = sub.headers();
+        let fields = headers.fields();
+        let [start, len] = fields[0].offset;
+        assert_eq!(&buf[start..start+len], b"X-Last:\r\n");
+    }
+
+    fn unparseable(msg: &Message) -> Vec<usize> {
+        msg.defects().iter()
+            .filter(|d| d.kind == DefectKind::UnparseableHeaders)
+            .map(|d| d.offset)
+            .collect()
+    }
+
+    #[test]
+    fn lenient_recovers_the_failing_subpart() {
+        let buf = b"Subject: s\r\nContent-Type: multipart/mixed; boundary=b\r\n\r\n--b\r\n\
+Content-Type: text/plain\r\n\r\none\r\n--b\r\n \
+folded too early\r\nContent-Type: text/html\r\n\r\ntwo\r\n--b\r\n\
+Content-Type: text/plain\r\nX-A: a\r\n\rbad\r\n\r\nthree\r\n--b--\r\n";
+        assert!(Message::parse(buf).is_err());
+
+        let msg = Message::parse_lenient(buf);
+        assert_eq!(msg.buffer().len(), buf.len());
+        assert!(msg.in_original_buffer());
+        assert_eq!(msg.headers().first("Subject").as_deref(), Some("s"));
+        assert_eq!(msg.subpart_count(), 3);
+        assert!(unparseable(&msg).is_empty());
+
+        let first = msg.subpart(0).unwrap();
+        assert!(unparseable(&first).is_empty());
+        assert_eq!(first.body_raw().unwrap(), b"one\r\n");
+
+        // No headers: the part is default text/plain and its body starts
+        // with the rest of the line that broke it.
+        let second = msg.subpart(1).unwrap();
+        let bad = find(buf, b" folded");
+        assert_eq!(unparseable(&second), vec![bad]);
+        assert_eq!(second.mime_type(), "text/plain");
+        assert_eq!(second.body_offset()[0], bad + 1);
+
+        // Fields before the bad line are kept.
+        let third = msg.subpart(2).unwrap();
+        assert_eq!(unparseable(&third), vec![find(buf, b"\rbad")]);
+        assert_eq!(third.headers().first("X-A").as_deref(), Some("a"));
+        assert_eq!(third.offset(), [find(buf, b"Content-Type: text/plain\r\nX-A"), 49]);
+    }
+
+    #[test]
+    fn lenient_root_keeps_its_offsets() {
+        let buf = b"From a@b Mon Jan  1 00:00:00 2024\n folded\nSubject: s\n\nbody\n";
+        let msg = Message::parse_lenient(buf);
+        assert_eq!(unparseable(&msg), vec![34]);
+        assert!(msg.headers().first("Subject").is_none());
+        assert_eq!(msg.body_offset(), [35, buf.len() - 35]);
+    }
+
+    #[test]
+    fn lenient_repairs_embedded_messages() {
+        let buf = b"Content-Type: multipart/mixed; boundary=b\r\n\r\n--b\r\n\
+Content-Type: message/rfc822\r\n\r\nSubject: inner\r\n\rbad\r\n\r\nbody\r\n--b\r\n\
+Content-Type: message/rfc822\r\nContent-Transfer-Encoding: base64\r\n\r\n\
+IGZvbGRlZApTdWJqZWN0OiB4Cgpib2R5Cg==\r\n--b--\r\n";
+        let msg = Message::parse(buf).unwrap();
+        assert!(msg.subpart(0).unwrap().embedded_message().is_none());
+        assert!(msg.subpart(1).unwrap().embedded_message().is_none());
+
+        let msg = Message::parse_lenient(buf);
+        let inner = msg.subpart(0).unwrap().embedded_message().unwrap();
+        assert_eq!(inner.headers().first("Subject").as_deref(), Some("inner"));
+        assert!(!inner.in_original_buffer());
+        assert_eq!(unparseable(&inner), vec![16]);
+        assert_eq!(&inner.buffer()[..16], b"Subject: inner\r\n");
+        assert!(unparseable(&msg.subpart(0).unwrap()).is_empty());
+
+        // " folded\nSubject: x\n\nbody\n", decoded first.
+        let inner = msg.subpart(1).unwrap().embedded_message().unwrap();
+        assert_eq!(unparseable(&inner), vec![0]);
+        assert!(inner.headers().first("Subject").is_none());
+    }
+
+    fn parsed_virtual(msg: &Message) -> usize {
+        msg.storage.handle.virtual_parts.lock().unwrap().len()
+    }
+
+    #[test]
+    fn virtual_parts_are_parsed_on_demand() {
+        let buf = b"Content-Type: multipart/mixed; boundary=m\r\n\r\n--m\r\n\
+Content-Type: text/plain\r\n\r\nsee attached\r\n\
+begin 644 hi.txt\r\n#:&D*\r\n`\r\nend\r\n--m\r\n\
+Content-Type: message/rfc822\r\n\r\nSubject: inner\r\n\r\ninner body\r\n--m--\r\n";
+        let msg = Message::parse(buf).unwrap();
+        assert_eq!(msg.subpart_count(), 2);
+        assert_eq!(parsed_virtual(&msg), 0);
+
+        let text = msg.subpart(0).unwrap();
+        assert_eq!(text.subpart_count(), 1);
+        assert_eq!(parsed_virtual(&msg), 1);
+        let file = text.subpart(0).unwrap();
+        assert_eq!(file.filename().as_deref(), Some("hi.txt"));
+        assert_eq!(file.body_raw().unwrap(), b"hi\n");
+
+        let inner = msg.descendant(&[1, 0]).unwrap();
+        assert_eq!(inner.headers().first("Subject").as_deref(), Some("inner"));
+        assert_eq!(parsed_virtual(&msg), 2);
+    }
+
+    #[test]
+    fn max_depth_limits_lazy_embedding() {
+        let buf = b"Content-Type: message/rfc822\r\n\r\n\
+Content-Type: message/rfc822\r\n\r\nSubject: deepest\r\n\r\nbody\r\n";
+        let options = ParseOptions { max_depth: 1, ..ParseOptions::default() };
+        let msg = Message::parse_with(buf, &options).unwrap();
+        let inner = msg.embedded_message().unwrap();
+        assert_eq!(inner.mime_type(), "message/rfc822");
+        assert_eq!(inner.subpart_count(), 0);
+
+        let msg = Message::parse(buf).unwrap();
+        let deepest = msg.descendant(&[0, 0]).unwrap();
+        assert_eq!(deepest.headers().first("Subject").as_deref(), Some("deepest"));
+    }
+
+    fn find(buf: &[u8], needle: &[u8]) -> usize {
+        buf.windows(needle.len()).position(|w| w == needle).unwrap()
+    }
+}
@@ -0,0 +1,46 @@
+/// Extract the msg-ids from a Message-ID, In-Reply-To or References value,
+/// in order and without their angle brackets. Comments and phrases between
+/// ids (as some mailers put in In-Reply-To) are skipped. A value containing
+/// no bracketed id at all is accepted as a single bare id if it looks like
+/// one.
+pub fn parse_msgids(s: &str) -> Vec<String> {
+    let mut ids = Vec::new();
+    let mut depth = 0;
+    let mut quoted = false;
+    let mut current: Option<String> = None;
+
+    for c in s.chars() {
+        if let Some(id) = current.as_mut() {
+            match c {
+                '>' => {
+                    let id = current.take().unwrap();
+                    if !id.is_empty() {
+                        ids.push(id);
+                    }
+                },
+                // A stray '<' restarts the id rather than nesting.
+                '<' => id.clear(),
+                c if c.is_whitespace() => {},
+                c => id.push(c),
+            }
+            continue;
+        }
+
+        match c {
+            '"' if depth == 0 => quoted = !quoted,
+            '(' if !quoted => depth += 1,
+            ')' if !quoted && depth > 0 => depth -= 1,
+            '<' if !quoted && depth == 0 => current = Some(String::new()),
+            _ => {}
+        }
+    }
+
+    if ids.is_empty() {
+        let bare = s.trim();
+        if bare.contains('@') && !bare.contains(char::is_whitespace) {
+            ids.push(bare.trim_matches(|c| c == '<' || c == '>').to_string());
+        }
+    }
+
+    ids
+}
@@ -0,0 +1,257 @@
+use mailparse::MailHeaderMap;
+
+use crate::message;
+use crate::message::Message;
+use crate::msgid::parse_msgids;
+use crate::tnef;
+
+
+/// How a part is presented to a reader.
+///
+/// The rules, applied in order:
+///
+///  1. multipart/* parts, and TNEF parts ("winmail.dat") that were decoded
+///     into subparts, are `Container`s.
+///  2. "Content-Disposition: attachment" makes a part an `Attachment`.
+///  3. A part with a Content-ID directly inside multipart/related, other
+///     than the related root itself, is an `Inline` resource of that root
+///     (typically an image referenced as `cid:` from HTML), whether or not
+///     it has a filename.
+///  4. text/plain and text/html parts without a filename are `Body` text.
+///  5. Everything else is an `Attachment`. This includes nameless non-text
+///     parts, text parts of other types (text/calendar, text/x-vcard),
+///     message/rfc822, and "Content-Disposition: inline" parts that carry a
+///     filename, since mailers use that for attachments they would like
+///     displayed but readers still need to be able to save.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum PartKind {
+    Container,
+    Body,
+    Inline,
+    Attachment,
+}
+
+
+impl PartKind {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            PartKind::Container => "container",
+            PartKind::Body => "body",
+            PartKind::Inline => "inline",
+            PartKind::Attachment => "attachment",
+        }
+    }
+}
+
+
+#[derive(Clone, Debug)]
+pub struct PartInfo {
+    pub path: Vec<usize>,
+    pub mime_type: String,
+    pub filename: Option<String>,
+    /// Size after transfer decoding, or of the encoded body if it could not
+    /// be decoded.
+    pub size: usize,
+    /// Content-ID without angle brackets.
+    pub content_id: Option<String>,
+    pub disposition: String,
+    pub kind: PartKind,
+}
+
+
+pub fn content_id(msg: &Message) -> Option<String> {
+    header_content_id(msg.part())
+}
+
+
+/// `content_id` of the parsed part `pm`.
+pub(crate) fn header_content_id(pm: &mailparse::ParsedMail) -> Option<String> {
+    let value = pm.headers.get_first_value("Content-ID")?;
+    match parse_msgids(&value).into_iter().next() {
+        Some(id) => Some(id),
+        None => {
+            let id = value.trim().trim_matches(|c| c == '<' || c == '>');
+            if id.is_empty() { None } else { Some(id.to_string()) }
+        }
+    }
+}
+
+
+/// The root of a multipart/related: the part whose Content-ID matches the
+/// `start` parameter, else the first part (RFC 2387 3.2).
+pub(crate) fn related_root(msg: &Message) -> Option<Message> {
+    let ids: Vec<Option<String>> = msg.subparts().iter().map(content_id).collect();
+    msg.subpart(related_root_index(msg.param("start"), &ids))
+}
+
+
+/// Index of the root among the children of a multipart/related, given its
+/// `start` parameter and the children's Content-IDs.
+pub(crate) fn related_root_index(start: Option<&String>, ids: &[Option<String>]) -> usize {
+    if let Some(start) = start {
+        let start = start.trim().trim_matches(|c| c == '<' || c == '>');
+        if let Some(i) = ids.iter().position(|id| id.as_deref() == Some(start)) {
+            return i;
+        }
+    }
+    0
+}
+
+
+pub(crate) fn is_related_resource(msg: &Message, parent: Option<&Message>) -> bool {
+    let parent = match parent {
+        Some(p) if p.mime_type().eq_ignore_ascii_case("multipart/related") => p,
+        _ => return false
+    };
+    if content_id(msg).is_none() {
+        return false;
+    }
+    match related_root(parent) {
+        Some(root) => root.path() != msg.path(),
+        None => true
+    }
+}
+
+
+pub(crate) fn classify(msg: &Message, parent: Option<&Message>) -> PartKind {
+    kind_of(msg.part(), msg.subpart_count(), is_related_resource(msg, parent))
+}
+
+
+/// The rules of `PartKind` for the parsed part `pm`, which has
+/// `subpart_count` children and is (`related_resource`) or is not an inline
+/// resource of its multipart/related parent.
+pub(crate) fn kind_of(pm: &mailparse::ParsedMail, subpart_count: usize, related_resource: bool)
+    -> PartKind
+{
+    let mime_type = pm.ctype.mimetype.to_ascii_lowercase();
+    if mime_type.starts_with("multipart/") {
+        return PartKind::Container;
+    }
+
+    let filename = message::part_filename(pm);
+    if tnef::is_tnef(&mime_type, filename.as_deref()) && subpart_count > 0 {
+        return PartKind::Container;
+    }
+
+    if message::disposition_name(pm) == "attachment" {
+        return PartKind::Attachment;
+    }
+
+    if related_resource {
+        return PartKind::Inline;
+    }
+
+    if (mime_type == "text/plain" || mime_type == "text/html") && filename.is_none() {
+        return PartKind::Body;
+    }
+
+    PartKind::Attachment
+}
+
+
+/// Classify a single part; see `PartKind` for the rules.
+pub fn part_kind(msg: &Message) -> PartKind {
+    classify(msg, msg.parent().as_ref())
+}
+
+
+fn info(msg: &Message, kind: PartKind) -> PartInfo {
+    let size = match msg.body_raw() {
+        Ok(body) => body.len(),
+        Err(_) => msg.body_encoded().len()
+    };
+
+    PartInfo {
+        path: msg.path().to_vec(),
+        mime_type: msg.mime_type().clone(),
+        filename: msg.filename(),
+        size: size,
+        content_id: content_id(msg),
+        disposition: msg.content_disposition(),
+        kind: kind,
+    }
+}
+
+
+fn _walk(msg: &Message, parent: Option<&Message>, out: &mut Vec<PartInfo>) {
+    let kind = classify(msg, parent);
+    out.push(info(msg, kind));
+
+    for sub in msg.subparts() {
+        _walk(&sub, Some(msg), out);
+    }
+}
+
+
+/// Describe `msg` and all of its descendants in depth-first order.
+pub fn walk(msg: &Message) -> Vec<PartInfo> {
+    let mut out = Vec::new();
+    _walk(msg, msg.parent().as_ref(), &mut out);
+    out
+}
+
+
+/// The parts of `msg` classified as `PartKind::Attachment`.
+pub fn attachments(msg: &Message) -> Vec<PartInfo> {
+    walk(msg).into_iter()
+        .filter(|p| p.kind == PartKind::Attachment)
+        .collect()
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const RELATED: &[u8] = b"Content-Type: multipart/related; boundary=r; start=\"<root@x>\"\r\n\
+\r\n\
+--r\r\n\
+Content-Type: image/png\r\n\
+Content-ID: <logo@x>\r\n\
+Content-Disposition: inline; filename=logo.png\r\n\
+Content-Transfer-Encoding: base64\r\n\
+\r\n\
+iVBORw0KGgo=\r\n\
+--r\r\n\
+Content-Type: text/html\r\n\
+Content-ID: <root@x>\r\n\
+\r\n\
+<img src=\"cid:logo@x\">\r\n\
+--r\r\n\
+Content-Type: application/pdf\r\n\
+Content-Disposition: attachment; filename=a.pdf\r\n\
+Content-ID: <pdf@x>\r\n\
+\r\n\
+%PDF\r\n\
+--r--\r\n";
+
+    #[test]
+    fn related_root_with_content_id_is_body() {
+        let msg = Message::parse(RELATED).unwrap();
+        let kinds: Vec<PartKind> = walk(&msg).iter().map(|p| p.kind).collect();
+        assert_eq!(kinds, vec![PartKind::Container, PartKind::Inline, PartKind::Body, PartKind::Attachment]);
+        assert_eq!(related_root(&msg).unwrap().path(), &[1]);
+    }
+
+    #[test]
+    fn first_part_is_root_without_start() {
+        let buf = b"Content-Type: multipart/related; boundary=r\r\n\r\n--r\r\n\
+Content-Type: text/html\r\nContent-ID: <root@x>\r\n\r\n<p>hi</p>\r\n--r\r\n\
+Content-Type: image/gif\r\nContent-ID: <img@x>\r\n\r\nGIF89a\r\n--r--\r\n";
+        let msg = Message::parse(buf).unwrap();
+        assert_eq!(part_kind(&msg.subpart(0).unwrap()), PartKind::Body);
+        assert_eq!(part_kind(&msg.subpart(1).unwrap()), PartKind::Inline);
+    }
+
+    #[test]
+    fn attachments_and_sizes() {
+        let msg = Message::parse(RELATED).unwrap();
+        let found = attachments(&msg);
+        assert_eq!(found.len(), 1);
+        assert_eq!(found[0].filename.as_deref(), Some("a.pdf"));
+        assert_eq!(found[0].content_id.as_deref(), Some("pdf@x"));
+        let infos = walk(&msg);
+        assert_eq!(infos[1].size, 8);
+    }
+}
@@ -0,0 +1,1201 @@
+use std::collections::BTreeMap;
+
+use pyo3::buffer::PyBuffer;
+use pyo3::create_exception;
+use pyo3::exceptions::PyException;
+use pyo3::exceptions::PyIndexError;
+use pyo3::exceptions::PyValueError;
+use pyo3::prelude::*;
+use pyo3::types::*;
+
+use crate::address::Address;
+use crate::authres::AuthResults;
+use crate::body;
+use crate::builder;
+use crate::builder::TransferEncoding;
+use crate::date::ParsedDate;
+use crate::dkim;
+use crate::edit;
+use crate::address::Mailbox;
+use crate::maildir;
+use crate::mbox;
+use crate::parts;
+use crate::render;
+use crate::security::Protection;
+use crate::smime;
+use crate::threading;
+use crate::tree;
+use crate::message::Backing;
+use crate::message::Error;
+use crate::message::Message;
+use crate::message::MessageHeaders;
+use crate::message::ParseOptions;
+use crate::message::DEFAULT_MAX_DEPTH;
+
+
+create_exception!(mailpar, ParseError, PyException);
+
+
+/// A body's path and the paths of the inline parts it references.
+type BodyPaths = (Vec<usize>, Vec<Vec<usize>>);
+
+
+impl From<Error> for PyErr {
+    fn from(e: Error) -> PyErr {
+        match e {
+            Error::Io(e) => e.into(),
+            Error::Parse(e) => ParseError::new_err(e.to_string()),
+        }
+    }
+}
+
+
+fn parse_error(e: mailparse::MailParseError) -> PyErr {
+    ParseError::new_err(e.to_string())
+}
+
+
+/// A C-contiguous, read-only Python buffer, held for the lifetime of a `Backing`.
+struct BufferView(PyBuffer<u8>);
+
+
+impl AsRef<[u8]> for BufferView {
+    fn as_ref(&self) -> &[u8] {
+        unsafe {
+            // Checked C-contiguous and read-only by from_buffer(); the
+            // exporter must keep the memory in place while the view is held.
+            std::slice::from_raw_parts(self.0.buf_ptr() as *const u8,
+                                       self.0.len_bytes())
+        }
+    }
+}
+
+
+fn mailbox_dict(py: Python, mailbox: &Mailbox) -> PyResult<PyObject> {
+    let dct = pyo3::types::PyDict::new(py);
+    dct.set_item("type", "single")?;
+    dct.set_item("name", mailbox.name.as_deref())?;
+    dct.set_item("addr", mailbox.addr.as_str())?;
+    Ok(dct.into())
+}
+
+
+fn address_list(py: Python, addrs: &[Address]) -> PyResult<PyObject> {
+    let lst = pyo3::types::PyList::empty(py);
+    for addr in addrs {
+        match addr {
+            Address::Single(mailbox) => lst.append(mailbox_dict(py, mailbox)?)?,
+            Address::Group { name, members } => {
+                let dct = pyo3::types::PyDict::new(py);
+                dct.set_item("type", "group")?;
+                dct.set_item("name", name.as_str())?;
+
+                let mlst = pyo3::types::PyList::empty(py);
+                for mailbox in members {
+                    mlst.append(mailbox_dict(py, mailbox)?)?;
+                }
+                dct.set_item("members", mlst)?;
+                lst.append(dct)?;
+            }
+        }
+    }
+    Ok(lst.into())
+}
+
+
+fn py_datetime(py: Python, d: &ParsedDate) -> PyResult<PyObject> {
+    let delta = PyDelta::new(py, 0, d.offset, 0, true)?;
+    let tz = py.import("datetime")?.getattr("timezone")?.call1((delta,))?;
+    let dt = PyDateTime::new(
+        py, d.year, d.month as u8, d.day as u8, d.hour as u8,
+        d.minute as u8, d.second as u8, 0, Some(tz.downcast::<PyTzInfo>()?)
+    )?;
+    Ok(dt.into())
+}
+
+
+fn auth_results_dict(py: Python, ar: &AuthResults) -> PyResult<PyObject> {
+    let dct = pyo3::types::PyDict::new(py);
+    dct.set_item("authserv_id", ar.authserv_id.as_str())?;
+    dct.set_item("version", ar.version.as_deref())?;
+
+    let results = pyo3::types::PyList::empty(py);
+    for r in &ar.results {
+        let rdct = pyo3::types::PyDict::new(py);
+        rdct.set_item("method", r.method.as_str())?;
+        rdct.set_item("version", r.version.as_deref())?;
+        rdct.set_item("result", r.result.as_str())?;
+        rdct.set_item("reason", r.reason.as_deref())?;
+
+        let props = pyo3::types::PyList::empty(py);
+        for p in &r.properties {
+            props.append((p.ptype.as_str(), p.property.as_str(), p.value.as_str()))?;
+        }
+        rdct.set_item("properties", props)?;
+        results.append(rdct)?;
+    }
+    dct.set_item("results", results)?;
+    Ok(dct.into())
+}
+
+
+#[pyclass]
+struct PyParsedMail {
+    msg: Message
+}
+
+
+#[pyclass]
+struct PyHeaders {
+    headers: MessageHeaders
+}
+
+
+#[pymethods]
+impl PyHeaders {
+    fn offset(&self) -> [usize; 2] {
+        self.headers.offset()
+    }
+
+    fn raw_bytes(&self) -> &[u8] {
+        self.headers.raw_bytes()
+    }
+
+    fn fromline(&self) -> Option<&[u8]> {
+        self.headers.fromline()
+    }
+
+    /// Every header in its original order, as dicts of "name", "raw_key",
+    /// "raw_value" (bytes, still folded), "value" (decoded) and "offset"
+    /// (`[offset, length]` of the whole field including its line ending).
+    fn fields(&self, py: Python) -> PyResult<PyObject> {
+        let lst = pyo3::types::PyList::empty(py);
+        for field in self.headers.fields() {
+            let dct = pyo3::types::PyDict::new(py);
+            dct.set_item("name", field.name)?;
+            dct.set_item("raw_key", PyBytes::new(py, field.raw_key))?;
+            dct.set_item("raw_value", PyBytes::new(py, field.raw_value))?;
+            dct.set_item("value", field.value)?;
+            dct.set_item("offset", field.offset)?;
+            lst.append(dct)?;
+        }
+        Ok(lst.into())
+    }
+
+    fn first(&self, key: &str) -> Option<String> {
+        self.headers.first(key)
+    }
+
+    /// `(name, addr)` tuples from the first `key` header. Group members are
+    /// listed individually; see addresses() to keep group structure.
+    fn first_address(&self, py: Python, key: &str) -> PyResult<PyObject> {
+        let addrs = self.headers.first_address(key).map_err(parse_error)?;
+
+        let lst = pyo3::types::PyList::empty(py);
+        for addr in &addrs {
+            for mailbox in addr.mailboxes() {
+                let name = match &mailbox.name {
+                    None => "",
+                    Some(s) => s.as_str(),
+                };
+
+                lst.append((name, mailbox.addr.as_str()))?;
+            }
+        }
+
+        Ok(lst.into())
+    }
+
+    /// Addresses from every `key` header. Each entry is a dict with "type"
+    /// "single" (plus "name" and "addr") or "group" (plus "name" and a list
+    /// of single "members").
+    fn addresses(&self, py: Python, key: &str) -> PyResult<PyObject> {
+        let addrs = self.headers.addresses(key).map_err(parse_error)?;
+        address_list(py, &addrs)
+    }
+
+    /// Addresses from all address headers, as `(name, addresses)` tuples.
+    fn all_addresses(&self, py: Python) -> PyResult<PyObject> {
+        let lst = pyo3::types::PyList::empty(py);
+        for (name, addrs) in self.headers.all_addresses().map_err(parse_error)? {
+            lst.append((name, address_list(py, &addrs)?))?;
+        }
+        Ok(lst.into())
+    }
+
+    /// msg-ids from every `key` header, without angle brackets.
+    fn message_ids(&self, key: &str) -> Vec<String> {
+        self.headers.message_ids(key)
+    }
+
+    /// Parse the first `key` header as a timezone-aware datetime. Returns a
+    /// `(datetime, heuristic)` tuple, where `heuristic` is true if the value
+    /// was not valid RFC 5322 and had to be interpreted, or None if the
+    /// header is absent.
+    #[pyo3(signature = (key="Date"))]
+    fn date(&self, py: Python, key: &str) -> PyResult<Option<(PyObject, bool)>> {
+        let d = match self.headers.date(key).map_err(parse_error)? {
+            None => return Ok(None),
+            Some(d) => d
+        };
+
+        Ok(Some((py_datetime(py, &d)?, d.heuristic)))
+    }
+
+    /// The Received fields as hops in delivery order, oldest first. Each is
+    /// a dict with "from_host", "from_rdns", "from_ip", "by_host", "by_ip",
+    /// "via", "protocol", "id", "envelope_for", "envelope_from", "tls",
+    /// "tls_version", "tls_cipher", "date", "delay" (seconds since the
+    /// previous hop) and "problems".
+    fn received_hops(&self, py: Python) -> PyResult<PyObject> {
+        let lst = pyo3::types::PyList::empty(py);
+        for hop in self.headers.received_hops() {
+            let dct = pyo3::types::PyDict::new(py);
+            dct.set_item("from_host", hop.from_host)?;
+            dct.set_item("from_rdns", hop.from_rdns)?;
+            dct.set_item("from_ip", hop.from_ip.map(|ip| ip.to_string()))?;
+            dct.set_item("by_host", hop.by_host)?;
+            dct.set_item("by_ip", hop.by_ip.map(|ip| ip.to_string()))?;
+            dct.set_item("via", hop.via)?;
+            dct.set_item("protocol", hop.protocol)?;
+            dct.set_item("id", hop.id)?;
+            dct.set_item("envelope_for", hop.envelope_for)?;
+            dct.set_item("envelope_from", hop.envelope_from)?;
+            dct.set_item("tls", hop.tls)?;
+            dct.set_item("tls_version", hop.tls_version)?;
+            dct.set_item("tls_cipher", hop.tls_cipher)?;
+            match &hop.date {
+                Some(d) => dct.set_item("date", py_datetime(py, d)?)?,
+                None => dct.set_item("date", py.None())?
+            }
+            dct.set_item("delay", hop.delay)?;
+            dct.set_item("problems", hop.problems)?;
+            lst.append(dct)?;
+        }
+        Ok(lst.into())
+    }
+
+    fn all(&self, key: &str) -> Vec<String> {
+        self.headers.all(key)
+    }
+
+    fn names(&self) -> Vec<String> {
+        self.headers.names()
+    }
+
+    #[pyo3(signature = (key, sniff=false))]
+    fn decoded(&self, key: &str, sniff: bool) -> Vec<String> {
+        self.headers.decoded(key, sniff)
+    }
+
+    /// Authentication-Results fields as dicts with "authserv_id",
+    /// "version" and "results"; each result has "method", "version",
+    /// "result", "reason" and "properties" as (ptype, property, value)
+    /// tuples. Unparseable fields raise ParseError.
+    fn authentication_results(&self, py: Python) -> PyResult<PyObject> {
+        let lst = pyo3::types::PyList::empty(py);
+        for ar in self.headers.authentication_results() {
+            let ar = ar.map_err(ParseError::new_err)?;
+            lst.append(auth_results_dict(py, &ar)?)?;
+        }
+        Ok(lst.into())
+    }
+
+    /// The ARC chain as a dict with "status" ("none", "pass" or "fail"),
+    /// "problems" and "sets", one per instance with "instance",
+    /// "authentication_results", "message_signature" and "seal". The last
+    /// two are tag dicts. Only the structure is checked.
+    fn arc_chain(&self, py: Python) -> PyResult<PyObject> {
+        let chain = self.headers.arc_chain();
+        let sets = pyo3::types::PyList::empty(py);
+        for set in &chain.sets {
+            let dct = pyo3::types::PyDict::new(py);
+            dct.set_item("instance", set.instance)?;
+            match &set.authentication_results {
+                Some(ar) => dct.set_item("authentication_results", auth_results_dict(py, ar)?)?,
+                None => dct.set_item("authentication_results", py.None())?
+            }
+            dct.set_item("message_signature", set.message_signature.clone())?;
+            dct.set_item("seal", set.seal.clone())?;
+            sets.append(dct)?;
+        }
+
+        let dct = pyo3::types::PyDict::new(py);
+        dct.set_item("status", chain.status)?;
+        dct.set_item("problems", chain.problems)?;
+        dct.set_item("sets", sets)?;
+        Ok(dct.into())
+    }
+}
+
+
+#[pymethods]
+impl PyParsedMail {
+    fn offset(&self) -> [usize; 2] {
+        self.msg.offset()
+    }
+
+    fn from_line(&self, py: Python) -> PyObject {
+        PyBytes::new(py, self.msg.from_line()).into()
+    }
+
+    fn raw_bytes(&self, py: Python) -> PyObject {
+        PyBytes::new(py, self.msg.raw_bytes()).into()
+    }
+
+    /// Maildir flags of the file the message was read from, or None if it
+    /// did not come from a Maildir.
+    fn flags(&self) -> Option<&str> {
+        self.msg.flags()
+    }
+
+    fn body_offset(&self) -> [usize; 2] {
+        self.msg.body_offset()
+    }
+
+    fn subpart_count(&self) -> usize {
+        self.msg.subpart_count()
+    }
+
+    fn mime_type(&self) -> &String {
+        self.msg.mime_type()
+    }
+
+    fn charset(&self) -> &String {
+        self.msg.charset()
+    }
+
+    fn params(&self) -> BTreeMap<String, String> {
+        self.msg.params().clone()
+    }
+
+    fn param(&self, k: &str) -> Option<&String> {
+        self.msg.param(k)
+    }
+
+    fn content_disposition(&self) -> String {
+        self.msg.content_disposition()
+    }
+
+    fn get_filename(&self) -> Option<String> {
+        self.msg.filename()
+    }
+
+    fn path(&self) -> Vec<usize> {
+        self.msg.path().to_vec()
+    }
+
+    fn headers(&self) -> PyHeaders {
+        PyHeaders {
+            headers: self.msg.headers()
+        }
+    }
+
+    fn subpart(&self, i: usize) -> PyResult<PyParsedMail> {
+        match self.msg.subpart(i) {
+            Some(msg) => Ok(PyParsedMail { msg: msg }),
+            None => Err(PyIndexError::new_err(i))
+        }
+    }
+
+    /// The message inside a message/rfc822 or message/global part (also
+    /// available as subpart(0)), or None.
+    fn embedded_message(&self) -> Option<PyParsedMail> {
+        self.msg.embedded_message().map(|msg| PyParsedMail { msg: msg })
+    }
+
+    /// False if this part lies inside a transfer-encoded message/global, in
+    /// which case offsets are relative to that part's decoded body rather
+    /// than the original buffer.
+    fn in_original_buffer(&self) -> bool {
+        self.msg.in_original_buffer()
+    }
+
+    fn body(&self) -> PyResult<String> {
+        self.msg.body().map_err(parse_error)
+    }
+
+    /// Returns (text, charset used, whether the charset was sniffed).
+    #[pyo3(signature = (sniff=false))]
+    fn body_text(&self, sniff: bool) -> PyResult<(String, &'static str, bool)> {
+        let decoded = self.msg.body_text(sniff).map_err(parse_error)?;
+        Ok((decoded.text, decoded.charset, decoded.detected))
+    }
+
+    fn declared_charset(&self) -> Option<String> {
+        self.msg.declared_charset().cloned()
+    }
+
+    fn body_raw(&self, py: Python) -> PyResult<PyObject> {
+        match self.msg.body_raw() {
+            Ok(s) => Ok(PyBytes::new(py, s.as_slice()).into()),
+            Err(e) => Err(parse_error(e))
+        }
+    }
+
+    fn body_encoding(&self) -> &str {
+        self.msg.body_encoding()
+    }
+
+    fn body_encoded(&self) -> &[u8] {
+        self.msg.body_encoded()
+    }
+
+    /// Describe this part and all of its descendants, depth first. Each
+    /// entry is a dict of "path", "mime_type", "filename", "size" (decoded),
+    /// "content_id", "disposition" and "kind" ("container", "body", "inline"
+    /// or "attachment"; see `parts::PartKind` for the rules).
+    fn walk(&self, py: Python) -> PyResult<PyObject> {
+        let infos = py.allow_threads(|| parts::walk(&self.msg));
+        part_info_list(py, &infos)
+    }
+
+    /// Choose the body to display: `(path, related)` where `related` lists
+    /// the paths of inline parts the body references via `cid:`, or None if
+    /// the message has no displayable body. `prefer` is "html" or "text".
+    #[pyo3(signature = (prefer="html"))]
+    fn preferred_body(&self, py: Python, prefer: &str)
+        -> PyResult<Option<BodyPaths>>
+    {
+        let prefer = match body::Preference::from_name(prefer) {
+            Some(p) => p,
+            None => return Err(PyValueError::new_err(prefer.to_string()))
+        };
+
+        let choice = py.allow_threads(|| body::preferred_body(&self.msg, prefer));
+        Ok(choice.map(|c| (c.path, c.related)))
+    }
+
+    /// Render the preferred HTML body like rewrite_html(), adding
+    /// "body_path", "cid_links" (`(deferral index, part path)` for each
+    /// `cid:` URL matched to a part by Content-ID) and "unresolved_cids"
+    /// (`(deferral index, content id)` for those that matched nothing).
+    /// Returns None if the message has no HTML body.
+    fn render_html(&self, py: Python) -> PyResult<Option<PyObject>> {
+        let result = py.allow_threads(|| {
+            render::render_html(&self.msg).map_err(|e| e.to_string())
+        });
+
+        let rendered = match result {
+            Ok(Some(r)) => r,
+            Ok(None) => return Ok(None),
+            Err(e) => return Err(ParseError::new_err(e))
+        };
+
+        let dct = html_output_dict(py, rendered.output)?;
+        dct.set_item("body_path", rendered.body_path)?;
+        dct.set_item("cid_links", rendered.cid_links)?;
+        dct.set_item("unresolved_cids", rendered.unresolved_cids)?;
+        Ok(Some(dct.into()))
+    }
+
+    /// The entries of walk() whose kind is "attachment".
+    fn attachments(&self, py: Python) -> PyResult<PyObject> {
+        let infos = py.allow_threads(|| parts::attachments(&self.msg));
+        part_info_list(py, &infos)
+    }
+
+    /// Verify each DKIM-Signature field of this message. `resolver` is
+    /// called with a name like "sel._domainkey.example.com" and returns the
+    /// TXT records found there: None if there are none, a str for a single
+    /// record, a list of str for the character-strings of a single record
+    /// (which are concatenated, RFC 6376 3.6.2.2), or a list of such lists
+    /// for several records. An exception it raises makes that signature a
+    /// "temperror". Returns a
+    /// list of dicts with "domain", "selector", "algorithm", "status"
+    /// ("pass", "fail", "permerror" or "temperror"), "reason" and "offset".
+    fn verify_dkim(&self, py: Python, resolver: &PyAny) -> PyResult<PyObject> {
+        let lookup = |name: &str| -> Result<Vec<String>, String> {
+            let result = resolver.call1((name,)).map_err(|e| e.to_string())?;
+            if result.is_none() {
+                Ok(Vec::new())
+            } else if let Ok(s) = result.extract::<String>() {
+                Ok(vec![s])
+            } else if let Ok(chunks) = result.extract::<Vec<String>>() {
+                Ok(vec![chunks.concat()])
+            } else {
+                let records = result.extract::<Vec<Vec<String>>>().map_err(|e| e.to_string())?;
+                Ok(records.iter().map(|r| r.concat()).collect())
+            }
+        };
+
+        let results = dkim::verify(&self.msg, lookup);
+        let lst = pyo3::types::PyList::empty(py);
+        for r in &results {
+            let dct = pyo3::types::PyDict::new(py);
+            dct.set_item("domain", r.domain.as_deref())?;
+            dct.set_item("selector", r.selector.as_deref())?;
+            dct.set_item("algorithm", r.algorithm.as_deref())?;
+            dct.set_item("status", r.status.as_str())?;
+            dct.set_item("reason", r.reason.as_str())?;
+            dct.set_item("offset", r.offset)?;
+            lst.append(dct)?;
+        }
+        Ok(lst.into())
+    }
+
+    /// Verify S/MIME signatures (multipart/signed and opaque signed-data)
+    /// offline. `trusted` is a list of CA certificates, each bytes holding
+    /// one DER certificate or any number in PEM. Returns a dict per signer
+    /// with "path", "status" ("valid", "untrusted", "invalid" or "error"),
+    /// "reason", "subject", "emails", "signing_time" (Unix seconds) and
+    /// "from_matches".
+    fn verify_smime(&self, py: Python, trusted: Vec<&PyBytes>) -> PyResult<PyObject> {
+        let mut anchors = Vec::new();
+        for t in trusted {
+            anchors.extend(smime::load_certificates(t.as_bytes()).map_err(PyValueError::new_err)?);
+        }
+
+        let results = py.allow_threads(|| smime::verify(&self.msg, &anchors));
+        let lst = pyo3::types::PyList::empty(py);
+        for r in &results {
+            let dct = pyo3::types::PyDict::new(py);
+            dct.set_item("path", r.path.clone())?;
+            dct.set_item("status", r.status.as_str())?;
+            dct.set_item("reason", r.reason.as_str())?;
+            dct.set_item("subject", r.subject.as_deref())?;
+            dct.set_item("emails", r.emails.clone())?;
+            dct.set_item("signing_time", r.signing_time)?;
+            dct.set_item("from_matches", r.from_matches)?;
+            lst.append(dct)?;
+        }
+        Ok(lst.into())
+    }
+
+    /// uuencoded, yEnc and BinHex files embedded in a text/plain body, as
+    /// dicts with "format", "filename", "mode", "offset" (span within the
+    /// decoded body) and "data". Each is also exposed as a subpart.
+    fn encoded_blocks(&self, py: Python) -> PyResult<PyObject> {
+        let blocks = py.allow_threads(|| self.msg.encoded_blocks());
+        let lst = pyo3::types::PyList::empty(py);
+        for b in &blocks {
+            let dct = pyo3::types::PyDict::new(py);
+            dct.set_item("format", b.format.as_str())?;
+            dct.set_item("filename", b.filename.as_deref())?;
+            dct.set_item("mode", b.mode)?;
+            dct.set_item("offset", b.offset)?;
+            dct.set_item("data", PyBytes::new(py, &b.data))?;
+            lst.append(dct)?;
+        }
+        Ok(lst.into())
+    }
+
+    /// Signed and encrypted structure as a dict with "signed" and
+    /// "encrypted" flags and "parts", each a dict with "path", "scheme"
+    /// ("pgp_mime", "smime" or "inline_pgp"), "protection" ("signed",
+    /// "encrypted" or "compressed"), "protocol", "micalg",
+    /// "signed_offset", "signed_content" (CRLF-canonicalized),
+    /// "signature_path", "signature" and "payload".
+    fn security(&self, py: Python) -> PyResult<PyObject> {
+        let parts = py.allow_threads(|| self.msg.security());
+        let lst = pyo3::types::PyList::empty(py);
+        for p in &parts {
+            let dct = pyo3::types::PyDict::new(py);
+            dct.set_item("path", p.path.clone())?;
+            dct.set_item("scheme", p.scheme.as_str())?;
+            dct.set_item("protection", p.protection.as_str())?;
+            dct.set_item("protocol", p.protocol.as_deref())?;
+            dct.set_item("micalg", p.micalg.as_deref())?;
+            dct.set_item("signed_offset", p.signed_offset)?;
+            dct.set_item("signed_content", p.signed_content.as_ref().map(|b| PyBytes::new(py, b)))?;
+            dct.set_item("signature_path", p.signature_path.clone())?;
+            dct.set_item("signature", p.signature.as_ref().map(|b| PyBytes::new(py, b)))?;
+            dct.set_item("payload", p.payload.as_ref().map(|b| PyBytes::new(py, b)))?;
+            lst.append(dct)?;
+        }
+
+        let dct = pyo3::types::PyDict::new(py);
+        dct.set_item("signed", parts.iter().any(|p| p.protection == Protection::Signed))?;
+        dct.set_item("encrypted", parts.iter().any(|p| p.protection == Protection::Encrypted))?;
+        dct.set_item("parts", lst)?;
+        Ok(dct.into())
+    }
+
+    /// An editor for this message; see `PyEditor`.
+    fn edit(&self) -> PyEditor {
+        PyEditor {
+            editor: edit::Editor::new(&self.msg)
+        }
+    }
+
+    /// This part and all of its descendants as nested dicts, built in one
+    /// pass: path, offsets, mime_type, charset, params, disposition,
+    /// filename, content_id, encoding, encoded_size (before transfer
+    /// decoding), kind, headers and children.
+    /// With `text`, body parts also carry their decoded text.
+    #[pyo3(signature = (text=false))]
+    fn to_dict(&self, py: Python, text: bool) -> PyResult<PyObject> {
+        let value = py.allow_threads(|| {
+            serde_json::to_value(tree::tree(&self.msg, text))
+        });
+        match value {
+            Ok(v) => json_to_py(py, &v),
+            Err(e) => Err(ParseError::new_err(e.to_string()))
+        }
+    }
+
+    /// to_dict() serialized as a JSON string.
+    #[pyo3(signature = (text=false))]
+    fn to_json(&self, py: Python, text: bool) -> String {
+        py.allow_threads(|| tree::to_json(&self.msg, text))
+    }
+
+    /// Defects in this part as a list of {"kind", "offset", "detail"}
+    /// dicts; offsets are relative to the same buffer as offset().
+    fn defects(&self, py: Python) -> PyResult<PyObject> {
+        let defects = py.allow_threads(|| self.msg.defects());
+        let lst = pyo3::types::PyList::empty(py);
+        for d in &defects {
+            let dct = pyo3::types::PyDict::new(py);
+            dct.set_item("kind", d.kind.as_str())?;
+            dct.set_item("offset", d.offset)?;
+            dct.set_item("detail", d.detail.as_str())?;
+            lst.append(dct)?;
+        }
+        Ok(lst.into())
+    }
+}
+
+
+fn json_to_py(py: Python, value: &serde_json::Value) -> PyResult<PyObject> {
+    Ok(match value {
+        serde_json::Value::Null => py.None(),
+        serde_json::Value::Bool(b) => b.into_py(py),
+        serde_json::Value::Number(n) => match n.as_u64() {
+            Some(u) => u.into_py(py),
+            None => match n.as_i64() {
+                Some(i) => i.into_py(py),
+                None => n.as_f64().unwrap_or(0.0).into_py(py)
+            }
+        },
+        serde_json::Value::String(s) => s.into_py(py),
+        serde_json::Value::Array(items) => {
+            let lst = pyo3::types::PyList::empty(py);
+            for item in items {
+                lst.append(json_to_py(py, item)?)?;
+            }
+            lst.into()
+        },
+        serde_json::Value::Object(map) => {
+            let dct = pyo3::types::PyDict::new(py);
+            for (k, v) in map {
+                dct.set_item(k, json_to_py(py, v)?)?;
+            }
+            dct.into()
+        }
+    })
+}
+
+
+fn part_info_list(py: Python, infos: &[parts::PartInfo]) -> PyResult<PyObject> {
+    let lst = pyo3::types::PyList::empty(py);
+    for info in infos {
+        let dct = pyo3::types::PyDict::new(py);
+        dct.set_item("path", info.path.clone())?;
+        dct.set_item("mime_type", info.mime_type.as_str())?;
+        dct.set_item("filename", info.filename.as_deref())?;
+        dct.set_item("size", info.size)?;
+        dct.set_item("content_id", info.content_id.as_deref())?;
+        dct.set_item("disposition", info.disposition.as_str())?;
+        dct.set_item("kind", info.kind.as_str())?;
+        lst.append(dct)?;
+    }
+    Ok(lst.into())
+}
+
+
+/// Parse a copy of `buf`. With `lenient`, a malformed header block ends
+/// at the bad line and is recorded as a defect of its part instead of
+/// raising ParseError. message/rfc822 and
+/// message/global parts are parsed into child messages up to `max_depth`
+/// levels deep. Offsets are relative to the start of `buf`, including any
+/// leading "From " line.
+#[pyfunction]
+#[pyo3(signature = (buf, lenient=false, max_depth=DEFAULT_MAX_DEPTH))]
+fn from_bytes<'a>(py: Python<'a>, buf: &[u8], lenient: bool, max_depth: usize)
+    -> PyResult<PyParsedMail>
+{
+    let options = ParseOptions { lenient: lenient, max_depth: max_depth };
+    py.allow_threads(|| {
+        match Message::parse_with(buf, &options) {
+            Ok(msg) => Ok(PyParsedMail { msg: msg }),
+            Err(e) => Err(parse_error(e))
+        }
+    })
+}
+
+
+/// Parse a message directly from any object supporting the buffer protocol
+/// (bytes, bytearray, memoryview, mmap). Read-only buffers are used without
+/// copying and kept alive for as long as any part of the result exists;
+/// writable ones (bytearray, writable mmap) are copied first, since Python
+/// code could change them while the message is read. Offsets are relative
+/// to the start of the buffer.
+#[pyfunction]
+#[pyo3(signature = (obj, lenient=false, max_depth=DEFAULT_MAX_DEPTH))]
+fn from_buffer(py: Python, obj: &PyAny, lenient: bool, max_depth: usize)
+    -> PyResult<PyParsedMail>
+{
+    let buffer = PyBuffer::<u8>::get(obj)?;
+    if !buffer.is_c_contiguous() {
+        return Err(PyValueError::new_err("buffer must be C-contiguous"));
+    }
+
+    let backing = if buffer.readonly() {
+        Backing::External(Box::new(BufferView(buffer)))
+    } else {
+        Backing::Owned(buffer.to_vec(py)?)
+    };
+
+    let options = ParseOptions { lenient: lenient, max_depth: max_depth };
+    py.allow_threads(|| {
+        match Message::from_backing_with(backing, &options) {
+            Ok(msg) => Ok(PyParsedMail { msg: msg }),
+            Err(e) => Err(parse_error(e))
+        }
+    })
+}
+
+
+/// Parse the file at `path` via a read-only memory map, so the message is
+/// never copied into process memory. Offsets are relative to the start of
+/// the file. The file must not be truncated while the result is alive.
+#[pyfunction]
+#[pyo3(signature = (path, lenient=false, max_depth=DEFAULT_MAX_DEPTH))]
+fn from_path(py: Python, path: &str, lenient: bool, max_depth: usize)
+    -> PyResult<PyParsedMail>
+{
+    let options = ParseOptions { lenient: lenient, max_depth: max_depth };
+    py.allow_threads(|| {
+        let msg = Message::from_path_with(path, &options)?;
+        Ok(PyParsedMail { msg: msg })
+    })
+}
+
+
+fn thread_dict(py: Python, node: &threading::ThreadNode) -> PyResult<PyObject> {
+    let children = pyo3::types::PyList::empty(py);
+    for child in &node.children {
+        children.append(thread_dict(py, child)?)?;
+    }
+
+    let dct = pyo3::types::PyDict::new(py);
+    dct.set_item("index", node.index)?;
+    dct.set_item("message_id", node.message_id.as_deref())?;
+    dct.set_item("children", children)?;
+    Ok(dct.into())
+}
+
+
+/// Thread a list of messages into conversations using the JWZ algorithm.
+/// Returns a list of root dicts with "index" (position in `messages`, or
+/// None for a placeholder), "message_id" and "children".
+#[pyfunction]
+fn thread(py: Python, messages: Vec<PyRef<PyParsedMail>>) -> PyResult<PyObject> {
+    let inputs: Vec<threading::ThreadInput> = messages.iter()
+        .map(|m| threading::ThreadInput::from_message(&m.msg))
+        .collect();
+
+    let roots = py.allow_threads(|| threading::thread(&inputs));
+
+    let lst = pyo3::types::PyList::empty(py);
+    for root in &roots {
+        lst.append(thread_dict(py, root)?)?;
+    }
+    Ok(lst.into())
+}
+
+
+/// Iterator over the messages of an mbox, yielding `(offset, message)`
+/// tuples where `offset` is the position of the message's "From " line.
+#[pyclass]
+struct PyMboxIter {
+    buf: Py<PyBytes>,
+    pos: usize,
+    format: mbox::MboxFormat,
+}
+
+
+#[pymethods]
+impl PyMboxIter {
+    fn __iter__(slf: PyRef<'_, Self>) -> PyRef<'_, Self> {
+        slf
+    }
+
+    fn __next__(&mut self, py: Python) -> PyResult<Option<(usize, PyParsedMail)>> {
+        let buf = self.buf.as_ref(py).as_bytes();
+        let mut reader = mbox::MboxReader::new(buf, self.format);
+        reader.set_position(self.pos);
+
+        let msg = match reader.next() {
+            None => return Ok(None),
+            Some(msg) => msg
+        };
+
+        self.pos = reader.position();
+        let offset = msg.offset;
+        match Message::from_mbox_message(msg) {
+            Ok(msg) => Ok(Some((offset, PyParsedMail { msg: msg }))),
+            Err(e) => Err(parse_error(e))
+        }
+    }
+}
+
+
+/// Iterate over a mailbox, yielding (offset, message) pairs. `offset` is
+/// that of the message's "From " line, which the message's own offsets also
+/// count from.
+#[pyfunction]
+#[pyo3(signature = (buf, format="mboxrd"))]
+fn iter_mbox(buf: &PyBytes, format: &str) -> PyResult<PyMboxIter>
+{
+    let format = match mbox::MboxFormat::from_name(format) {
+        Some(f) => f,
+        None => return Err(PyValueError::new_err(format.to_string()))
+    };
+
+    Ok(PyMboxIter {
+        buf: buf.into(),
+        pos: 0,
+        format: format,
+    })
+}
+
+
+fn entry_dict(py: Python, entry: &maildir::MaildirEntry) -> PyObject {
+    let dct = pyo3::types::PyDict::new(py);
+    dct.set_item("path", entry.path.to_string_lossy()).expect("set item");
+    dct.set_item("subdir", entry.subdir).expect("set item");
+    dct.set_item("id", entry.id.as_str()).expect("set item");
+    dct.set_item("flags", entry.flags.as_str()).expect("set item");
+    dct.into()
+}
+
+
+#[pyclass]
+struct PyMaildir {
+    maildir: maildir::Maildir
+}
+
+
+#[pymethods]
+impl PyMaildir {
+    #[new]
+    #[pyo3(signature = (path, create=false))]
+    fn new(path: &str, create: bool) -> PyResult<PyMaildir> {
+        let maildir = if create {
+            maildir::Maildir::create(path)?
+        } else {
+            maildir::Maildir::new(path)
+        };
+
+        Ok(PyMaildir { maildir: maildir })
+    }
+
+    fn list(&self, py: Python) -> PyResult<PyObject> {
+        let lst = pyo3::types::PyList::empty(py);
+        for entry in self.maildir.list()? {
+            lst.append(entry_dict(py, &entry))?;
+        }
+        Ok(lst.into())
+    }
+
+    fn __iter__(&self) -> PyResult<PyMaildirIter> {
+        Ok(PyMaildirIter {
+            entries: self.maildir.list()?,
+            i: 0
+        })
+    }
+
+    /// Atomically deliver `buf` into new/, returning the new entry.
+    fn deliver(&self, py: Python, buf: &[u8]) -> PyResult<PyObject> {
+        let entry = self.maildir.deliver(buf)?;
+        Ok(entry_dict(py, &entry))
+    }
+}
+
+
+/// Iterator over the messages of a Maildir, yielding `PyParsedMail` objects
+/// whose `flags()` reflect the info suffix of their filename.
+#[pyclass]
+struct PyMaildirIter {
+    entries: Vec<maildir::MaildirEntry>,
+    i: usize
+}
+
+
+#[pymethods]
+impl PyMaildirIter {
+    fn __iter__(slf: PyRef<'_, Self>) -> PyRef<'_, Self> {
+        slf
+    }
+
+    fn __next__(&mut self) -> PyResult<Option<PyParsedMail>> {
+        if self.i >= self.entries.len() {
+            return Ok(None);
+        }
+
+        let entry = &self.entries[self.i];
+        self.i += 1;
+
+        let msg = Message::from_maildir_entry(entry)?;
+        Ok(Some(PyParsedMail { msg: msg }))
+    }
+}
+
+
+/// A MIME part under construction. Mutating methods return the part, so
+/// calls can be chained.
+#[pyclass]
+struct PyPart {
+    part: builder::Part
+}
+
+
+#[pymethods]
+impl PyPart {
+    #[new]
+    #[pyo3(signature = (mime_type, data=None))]
+    fn new(mime_type: &str, data: Option<&[u8]>) -> PyResult<PyPart> {
+        let part = builder::Part::new(mime_type, data.unwrap_or_default().to_vec())
+            .map_err(PyValueError::new_err)?;
+        Ok(PyPart { part: part })
+    }
+
+    /// A UTF-8 text/`subtype` part; line endings become CRLF.
+    #[staticmethod]
+    #[pyo3(signature = (text, subtype="plain"))]
+    fn text(text: &str, subtype: &str) -> PyResult<PyPart> {
+        let part = builder::Part::text(subtype, text).map_err(PyValueError::new_err)?;
+        Ok(PyPart { part: part })
+    }
+
+    #[staticmethod]
+    #[pyo3(signature = (data, filename, mime_type="application/octet-stream"))]
+    fn attachment(data: &[u8], filename: &str, mime_type: &str) -> PyResult<PyPart> {
+        let part = builder::Part::attachment(filename, mime_type, data.to_vec()).map_err(PyValueError::new_err)?;
+        Ok(PyPart { part: part })
+    }
+
+    #[staticmethod]
+    #[pyo3(signature = (subtype="mixed", parts=Vec::new()))]
+    fn multipart(subtype: &str, parts: Vec<PyRef<PyPart>>) -> PyResult<PyPart> {
+        let children = parts.iter().map(|p| p.part.clone()).collect();
+        let part = builder::Part::multipart(subtype, children).map_err(PyValueError::new_err)?;
+        Ok(PyPart { part: part })
+    }
+
+    /// A message/rfc822 part wrapping `message`.
+    #[staticmethod]
+    fn message(message: PyRef<PyPart>) -> PyPart {
+        PyPart { part: builder::Part::message(&message.part) }
+    }
+
+    /// Add a header; non-ASCII values are RFC 2047 encoded.
+    fn header<'a>(mut slf: PyRefMut<'a, Self>, name: &str, value: &str) -> PyResult<PyRefMut<'a, Self>> {
+        slf.part.header(name, value).map_err(PyValueError::new_err)?;
+        Ok(slf)
+    }
+
+    /// Add an address header from `(display name or None, address)` pairs.
+    fn addresses<'a>(mut slf: PyRefMut<'a, Self>, name: &str, mailboxes: Vec<(Option<String>, String)>)
+        -> PyResult<PyRefMut<'a, Self>>
+    {
+        let mailboxes: Vec<Mailbox> = mailboxes.into_iter()
+            .map(|(name, addr)| Mailbox { name: name, addr: addr })
+            .collect();
+        slf.part.address_header(name, &mailboxes).map_err(PyValueError::new_err)?;
+        Ok(slf)
+    }
+
+    fn param<'a>(mut slf: PyRefMut<'a, Self>, name: &str, value: &str) -> PyResult<PyRefMut<'a, Self>> {
+        slf.part.param(name, value).map_err(PyValueError::new_err)?;
+        Ok(slf)
+    }
+
+    #[pyo3(signature = (kind, filename=None))]
+    fn disposition<'a>(mut slf: PyRefMut<'a, Self>, kind: &str, filename: Option<&str>)
+        -> PyResult<PyRefMut<'a, Self>>
+    {
+        slf.part.disposition(kind, filename).map_err(PyValueError::new_err)?;
+        Ok(slf)
+    }
+
+    /// Force "7bit", "8bit", "quoted-printable" or "base64".
+    fn encoding<'a>(mut slf: PyRefMut<'a, Self>, name: &str) -> PyResult<PyRefMut<'a, Self>> {
+        match TransferEncoding::from_name(name) {
+            Some(e) => {
+                slf.part.encoding(e);
+                Ok(slf)
+            },
+            None => Err(PyValueError::new_err(format!("unknown transfer encoding {:?}", name)))
+        }
+    }
+
+    fn attach<'a>(mut slf: PyRefMut<'a, Self>, part: PyRef<PyPart>) -> PyRefMut<'a, Self> {
+        slf.part.attach(part.part.clone());
+        slf
+    }
+
+    fn to_bytes(&self, py: Python) -> PyObject {
+        PyBytes::new(py, &self.part.to_bytes()).into()
+    }
+}
+
+
+/// Changes to a parsed message, by part path. `to_bytes` splices them into
+/// the original bytes, leaving everything else byte-identical, and raises
+/// ValueError if a change cannot be made.
+#[pyclass]
+struct PyEditor {
+    editor: edit::Editor
+}
+
+
+#[pymethods]
+impl PyEditor {
+    fn add_header<'a>(mut slf: PyRefMut<'a, Self>, path: Vec<usize>, name: &str, value: &str)
+        -> PyRefMut<'a, Self>
+    {
+        slf.editor.add_header(&path, name, value);
+        slf
+    }
+
+    fn remove_header<'a>(mut slf: PyRefMut<'a, Self>, path: Vec<usize>, name: &str) -> PyRefMut<'a, Self> {
+        slf.editor.remove_header(&path, name);
+        slf
+    }
+
+    fn set_header<'a>(mut slf: PyRefMut<'a, Self>, path: Vec<usize>, name: &str, value: &str)
+        -> PyRefMut<'a, Self>
+    {
+        slf.editor.set_header(&path, name, value);
+        slf
+    }
+
+    /// Replace a leaf part's body, encoded as the part already is.
+    fn replace_body<'a>(mut slf: PyRefMut<'a, Self>, path: Vec<usize>, data: &[u8]) -> PyRefMut<'a, Self> {
+        slf.editor.replace_body(&path, data);
+        slf
+    }
+
+    fn replace_part<'a>(mut slf: PyRefMut<'a, Self>, path: Vec<usize>, part: PyRef<PyPart>)
+        -> PyRefMut<'a, Self>
+    {
+        slf.editor.replace_part(&path, &part.part);
+        slf
+    }
+
+    fn remove_part<'a>(mut slf: PyRefMut<'a, Self>, path: Vec<usize>) -> PyRefMut<'a, Self> {
+        slf.editor.remove_part(&path);
+        slf
+    }
+
+    /// Add a part at the end of the multipart at `path`.
+    fn append_part<'a>(mut slf: PyRefMut<'a, Self>, path: Vec<usize>, part: PyRef<PyPart>)
+        -> PyRefMut<'a, Self>
+    {
+        slf.editor.append_part(&path, &part.part);
+        slf
+    }
+
+    fn to_bytes(&self, py: Python) -> PyResult<PyObject> {
+        let data = py.allow_threads(|| self.editor.to_bytes()).map_err(PyValueError::new_err)?;
+        Ok(PyBytes::new(py, &data).into())
+    }
+}
+
+
+fn html_output_dict<'a>(py: Python<'a>, output: crate::html::Output) -> PyResult<&'a PyDict>
+{
+    let lst = pyo3::types::PyList::empty(py);
+    for deferral in output.deferrals {
+        //lst.append((name, info.addr.as_str())).expect("append to list");
+        lst.append((deferral.i, deferral.kind as i32, deferral.data))?;
+    }
+
+    let dct = pyo3::types::PyDict::new(py);
+    dct.set_item("html", output.html)?;
+    dct.set_item("page_links", output.page_links)?;
+    dct.set_item("text_content", output.text_content)?;
+    dct.set_item("deferrals", lst)?;
+
+    dct.set_item("doctype_removed", output.st_doctype_removed)?;
+    dct.set_item("comment_removed", output.st_comment_removed)?;
+    dct.set_item("script_removed", output.st_script_removed)?;
+    dct.set_item("invalid_tag_removed", output.st_invalid_tag_removed)?;
+    dct.set_item("invalid_attr_removed", output.st_invalid_attr_removed)?;
+    dct.set_item("link_no_rel_removed", output.st_link_no_rel_removed)?;
+    dct.set_item("link_non_stylesheet_removed", output.st_link_non_stylesheet_removed)?;
+    dct.set_item("link_no_href_removed", output.st_link_no_href_removed)?;
+    dct.set_item("link_non_http_removed", output.st_link_non_http_removed)?;
+    dct.set_item("anchors_rewritten", output.st_anchors_rewritten)?;
+    dct.set_item("inline_style_skipped", output.st_inline_style_skipped)?;
+    dct.set_item("style_attr_skipped", output.st_style_attr_skipped)?;
+
+    Ok(dct)
+}
+
+
+#[pyfunction]
+pub fn rewrite_html(py: Python, s: &str) -> PyResult<PyObject>
+{
+    // Errors are stringified so the result can cross back into the GIL.
+    let result = py.allow_threads(|| {
+        crate::html::rewrite_html(s).map_err(|e| e.to_string())
+    });
+
+    match result {
+        Ok(output) => {
+            Ok(html_output_dict(py, output)?.into())
+            //Ok((output.html, lst).into(py))
+        },
+        Err(e) => Err(ParseError::new_err(e)),
+    }
+}
+
+
+#[pyfunction]
+pub fn rewrite_css(py: Python, s: &str) -> PyResult<PyObject>
+{
+    let lst = pyo3::types::PyList::empty(py);
+
+    let result = py.allow_threads(|| {
+        crate::css::rewrite_css(s, 0).map_err(|e| format!("{:?}", e))
+    });
+
+    match result {
+        Ok(output) => {
+            for deferral in output.deferrals {
+                lst.append((deferral.i, deferral.kind as i32, deferral.data))?;
+            }
+
+            let dct = pyo3::types::PyDict::new(py);
+            dct.set_item("css", output.css)?;
+            dct.set_item("deferrals", lst)?;
+            Ok(dct.into())
+        },
+        Err(e) => Err(ParseError::new_err(e)),
+    }
+}
+
+
+#[pymodule]
+fn mailpar(py: Python, m: &PyModule) -> PyResult<()> {
+    m.add_function(wrap_pyfunction!(from_bytes, m)?)?;
+    m.add_function(wrap_pyfunction!(from_buffer, m)?)?;
+    m.add_function(wrap_pyfunction!(from_path, m)?)?;
+    m.add_function(wrap_pyfunction!(iter_mbox, m)?)?;
+    m.add_function(wrap_pyfunction!(thread, m)?)?;
+    m.add_function(wrap_pyfunction!(rewrite_html, m)?)?;
+    m.add_function(wrap_pyfunction!(rewrite_css, m)?)?;
+    m.add_class::<PyParsedMail>()?;
+    m.add_class::<PyHeaders>()?;
+    m.add_class::<PyMboxIter>()?;
+    m.add_class::<PyMaildir>()?;
+    m.add_class::<PyMaildirIter>()?;
+    m.add_class::<PyPart>()?;
+    m.add_class::<PyEditor>()?;
+    m.add("ParseError", py.get_type::<ParseError>())?;
+    Ok(())
+}
@@ -0,0 +1,413 @@
+//! Received field parsing (RFC 5321 4.4) into relay hops.
+//!
+//! Few MTAs stick to the grammar, so this is best effort: the clauses are
+//! found by keyword and the TCP-info and TLS details are picked out of the
+//! comments in the forms Postfix, Sendmail, Exim, Exchange and Gmail write.
+
+use std::net::IpAddr;
+use std::time::SystemTime;
+use std::time::UNIX_EPOCH;
+
+use crate::date;
+use crate::date::ParsedDate;
+
+
+/// Hops that take longer than this are flagged as implausible; RFC 5321
+/// 4.5.4.1 suggests giving up on delivery after 4-5 days.
+const MAX_PLAUSIBLE_DELAY: i64 = 5 * 86400;
+
+/// Timestamps before this (1990-01-01) are flagged as implausible.
+const MIN_PLAUSIBLE_TIMESTAMP: i64 = 631152000;
+
+/// Timestamps more than this far ahead of the current time are flagged; a
+/// day allows for relays with a wrong time zone setting.
+const MAX_CLOCK_SKEW: i64 = 86400;
+
+
+#[derive(Clone, Debug, Default)]
+pub struct Hop {
+    /// Name the sending host gave in HELO/EHLO.
+    pub from_host: Option<String>,
+    /// Reverse DNS name of the sending host, as looked up by the receiver.
+    pub from_rdns: Option<String>,
+    pub from_ip: Option<IpAddr>,
+    pub by_host: Option<String>,
+    pub by_ip: Option<IpAddr>,
+    pub via: Option<String>,
+    /// "with" protocol, upper-cased: SMTP, ESMTP, ESMTPS, ESMTPSA, LMTP, ...
+    pub protocol: Option<String>,
+    pub id: Option<String>,
+    /// Recipient from the "for" clause, without angle brackets.
+    pub envelope_for: Option<String>,
+    /// Sender, where the receiver records it (Exim's "envelope-from").
+    pub envelope_from: Option<String>,
+    pub tls: bool,
+    pub tls_version: Option<String>,
+    pub tls_cipher: Option<String>,
+    pub date: Option<ParsedDate>,
+    /// Seconds since the previous hop's timestamp.
+    pub delay: Option<i64>,
+    /// Out-of-order and implausible timestamps and the like.
+    pub problems: Vec<String>,
+}
+
+
+enum Token {
+    Word(String),
+    Comment(String),
+}
+
+
+/// Split `value` into words and comments, stopping at the last top-level
+/// ';', which separates the date. Returns the tokens and the date text.
+fn tokenize(value: &str) -> (Vec<Token>, Option<&str>) {
+    let split = {
+        let mut depth = 0;
+        let mut last = None;
+        for (i, c) in value.char_indices() {
+            match c {
+                '(' => depth += 1,
+                ')' if depth > 0 => depth -= 1,
+                ';' if depth == 0 => last = Some(i),
+                _ => {}
+            }
+        }
+        last
+    };
+    let (clauses, date) = match split {
+        Some(i) => (&value[..i], Some(value[i+1..].trim())),
+        None => (value, None)
+    };
+
+    let mut tokens = Vec::new();
+    let mut chars = clauses.chars().peekable();
+    while let Some(&c) = chars.peek() {
+        if c.is_whitespace() {
+            chars.next();
+        } else if c == '(' {
+            chars.next();
+            let mut depth = 1;
+            let mut text = String::new();
+            for c in chars.by_ref() {
+                match c {
+                    '(' => depth += 1,
+                    ')' => {
+                        depth -= 1;
+                        if depth == 0 {
+                            break;
+                        }
+                    },
+                    _ => {}
+                }
+                text.push(c);
+            }
+            tokens.push(Token::Comment(text));
+        } else {
+            let mut word = String::new();
+            while let Some(&c) = chars.peek() {
+                if c.is_whitespace() || c == '(' {
+                    break;
+                }
+                word.push(c);
+                chars.next();
+            }
+            tokens.push(Token::Word(word));
+        }
+    }
+
+    (tokens, date)
+}
+
+
+/// An IP address from "[192.0.2.1]", "[IPv6:2001:db8::1]" or a bare
+/// address.
+fn ip_literal(s: &str) -> Option<IpAddr> {
+    let s = s.trim_end_matches([',', ')']);
+    let s = s.strip_prefix('[').and_then(|s| s.strip_suffix(']')).unwrap_or(s);
+    let s = match s.get(..5) {
+        Some(p) if p.eq_ignore_ascii_case("ipv6:") => &s[5..],
+        _ => s
+    };
+    s.parse().ok()
+}
+
+
+fn unbracket(s: &str) -> String {
+    let s = s.trim_end_matches(',');
+    s.strip_prefix('<').and_then(|s| s.strip_suffix('>')).unwrap_or(s).to_string()
+}
+
+
+/// TCP-info from the comment after "from": "host [ip]", "[ip]",
+/// "unknown [ip]", or Exim's "helo=name port=1234".
+fn from_comment(hop: &mut Hop, text: &str) {
+    for item in text.split(|c: char| c.is_whitespace() || c == ',') {
+        if let Some(helo) = item.strip_prefix("helo=") {
+            hop.from_host.get_or_insert(helo.to_string());
+        } else if let Some(ip) = ip_literal(item) {
+            hop.from_ip.get_or_insert(ip);
+        } else if hop.from_rdns.is_none() && hop.from_ip.is_none() && item.contains('.')
+            && !item.contains('=')
+        {
+            hop.from_rdns = Some(item.trim_end_matches('.').to_string());
+        }
+    }
+}
+
+
+/// Pick TLS details out of the whole field: Postfix's "using TLSv1.3 with
+/// cipher X", Sendmail's and Gmail's "version=TLSv1.3 cipher=X", and
+/// Exim's "(TLS1.3) tls X".
+fn tls_details(hop: &mut Hop, tokens: &[Token]) {
+    let mut items: Vec<&str> = Vec::new();
+    for token in tokens {
+        match token {
+            Token::Word(w) => items.push(w),
+            Token::Comment(c) => items.extend(
+                c.split(|c: char| c.is_whitespace() || c == ',' || c == '(' || c == ')')
+                    .filter(|s| !s.is_empty())
+            ),
+        }
+    }
+
+    let is_version = |s: &str| {
+        let upper = s.to_ascii_uppercase();
+        (upper.starts_with("TLS") || upper.starts_with("SSL"))
+            && upper[3..].trim_start_matches('V').starts_with(|c: char| c.is_ascii_digit())
+    };
+
+    for (i, &item) in items.iter().enumerate() {
+        let next = items.get(i + 1).cloned();
+        if let Some(v) = item.strip_prefix("version=") {
+            hop.tls_version.get_or_insert(v.to_string());
+        } else if let Some(v) = item.strip_prefix("cipher=") {
+            hop.tls_cipher.get_or_insert(v.to_string());
+        } else if (item == "cipher" && i > 0 && items[i-1] == "with") || item == "tls" {
+            if let Some(v) = next {
+                hop.tls_cipher.get_or_insert(v.to_string());
+            }
+        } else if is_version(item) {
+            hop.tls_version.get_or_insert(item.to_string());
+        }
+    }
+}
+
+
+/// Parse one Received field value.
+pub fn parse_received(value: &str) -> Hop {
+    let mut hop = Hop::default();
+    let (tokens, date_text) = tokenize(value);
+
+    let mut clause: Option<String> = None;
+    let mut have_value = false;
+    // Only the first comment after "from" is TCP-info; Postfix follows it
+    // with a TLS comment.
+    let mut have_tcp_info = false;
+    for token in &tokens {
+        match token {
+            Token::Word(w) => {
+                let lower = w.to_ascii_lowercase();
+                if ["from", "by", "via", "with", "id", "for"].contains(&lower.as_str()) {
+                    clause = Some(lower);
+                    have_value = false;
+                    continue;
+                }
+                if have_value {
+                    continue;
+                }
+                have_value = true;
+
+                match clause.as_deref() {
+                    Some("from") => match ip_literal(w) {
+                        Some(ip) => hop.from_ip = Some(ip),
+                        None => hop.from_host = Some(w.clone())
+                    },
+                    Some("by") => match ip_literal(w) {
+                        Some(ip) => hop.by_ip = Some(ip),
+                        None => hop.by_host = Some(w.clone())
+                    },
+                    Some("via") => hop.via = Some(w.clone()),
+                    Some("with") => hop.protocol = Some(w.to_ascii_uppercase()),
+                    Some("id") => hop.id = Some(w.clone()),
+                    Some("for") => hop.envelope_for = Some(unbracket(w)),
+                    _ => have_value = false
+                }
+            },
+            Token::Comment(c) => {
+                if let Some(sender) = c.strip_prefix("envelope-from ") {
+                    hop.envelope_from = Some(unbracket(sender.trim()));
+                } else if clause.as_deref() == Some("from") && !have_tcp_info {
+                    from_comment(&mut hop, c);
+                    have_tcp_info = true;
+                } else if clause.as_deref() == Some("by") && hop.by_ip.is_none() {
+                    hop.by_ip = c.split_whitespace().filter_map(ip_literal).next();
+                }
+            }
+        }
+    }
+
+    tls_details(&mut hop, &tokens);
+    let secure_protocol = match &hop.protocol {
+        Some(p) => (p.contains("SMTP") || p.contains("LMTP"))
+            && (p.ends_with('S') || p.ends_with("SA")),
+        None => false
+    };
+    hop.tls = secure_protocol || hop.tls_version.is_some() || hop.tls_cipher.is_some();
+
+    match date_text {
+        Some(d) => {
+            hop.date = date::parse_date(d);
+            if hop.date.is_none() {
+                hop.problems.push(format!("unparseable timestamp {:?}", d));
+            }
+        },
+        None => hop.problems.push("missing timestamp".to_string())
+    }
+
+    hop
+}
+
+
+/// Parse Received field values, given newest first as they appear in the
+/// header, into hops in delivery order (oldest first). Each hop's delay is
+/// measured from the last earlier hop that had a timestamp; negative
+/// delays, very long ones, timestamps before 1990 and timestamps in the
+/// future are flagged.
+pub fn hops(values: &[String]) -> Vec<Hop> {
+    let now = SystemTime::now().duration_since(UNIX_EPOCH).map(|d| d.as_secs() as i64).unwrap_or(0);
+    hops_at(values, now)
+}
+
+
+/// `hops`, taking `now` (Unix seconds) as the current time.
+fn hops_at(values: &[String], now: i64) -> Vec<Hop> {
+    let mut out: Vec<Hop> = values.iter().rev().map(|v| parse_received(v)).collect();
+
+    let mut previous: Option<i64> = None;
+    for hop in out.iter_mut() {
+        let ts = match &hop.date {
+            Some(d) => d.timestamp(),
+            None => continue
+        };
+
+        if ts < MIN_PLAUSIBLE_TIMESTAMP {
+            hop.problems.push(format!("implausible timestamp {}", ts));
+        } else if ts > now + MAX_CLOCK_SKEW {
+            hop.problems.push(format!("timestamp {} seconds in the future", ts - now));
+        }
+        if let Some(prev) = previous {
+            let delay = ts - prev;
+            hop.delay = Some(delay);
+            if delay < 0 {
+                hop.problems.push(format!("timestamp {} seconds before the previous hop", -delay));
+            } else if delay > MAX_PLAUSIBLE_DELAY {
+                hop.problems.push(format!("implausible delay of {} seconds", delay));
+            }
+        }
+        previous = Some(ts);
+    }
+
+    out
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const POSTFIX: &str = "from mail.example.org (mail.example.org [192.0.2.10]) \
+(using TLSv1.3 with cipher TLS_AES_256_GCM_SHA384 (256/256 bits) \
+key-exchange X25519 server-signature RSA-PSS (2048 bits) server-digest SHA256) \
+(No client certificate requested) by mx.example.net (Postfix) with ESMTPS id 4T1ABC2xyz \
+for <jane@example.net>; Mon,  1 Jan 2024 10:00:05 +0000 (UTC)";
+
+    const EXIM: &str = "from [198.51.100.7] (port=51234 helo=client.example.com) \
+by relay.example.net with esmtps (TLS1.3) tls TLS_AES_128_GCM_SHA256 (Exim 4.96) \
+(envelope-from <bob@example.com>) id 1qXyZa-000ABC-DE for jane@example.net; \
+Mon, 01 Jan 2024 10:00:07 +0000";
+
+    const GMAIL: &str = "from relay.example.net (relay.example.net. [203.0.113.5]) \
+by mx.google.com with ESMTPS id a1si123456.2024.01.01.02.00.09 \
+for <jane@gmail.com> (version=TLS1_3 cipher=TLS_AES_256_GCM_SHA384 bits=256/256); \
+Mon, 01 Jan 2024 02:00:09 -0800 (PST)";
+
+    const GMAIL_INTERNAL: &str = "by 2002:a05:6358:1234:b0:1a2:3b4c:5d6e with SMTP id \
+x12csp345678rwb; Mon, 1 Jan 2024 02:00:10 -0800 (PST)";
+
+    fn ip(s: &str) -> Option<IpAddr> {
+        Some(s.parse().unwrap())
+    }
+
+    #[test]
+    fn postfix() {
+        let hop = parse_received(POSTFIX);
+        assert_eq!(hop.from_host.as_deref(), Some("mail.example.org"));
+        assert_eq!(hop.from_rdns.as_deref(), Some("mail.example.org"));
+        assert_eq!(hop.from_ip, ip("192.0.2.10"));
+        assert_eq!(hop.by_host.as_deref(), Some("mx.example.net"));
+        assert_eq!(hop.protocol.as_deref(), Some("ESMTPS"));
+        assert_eq!(hop.id.as_deref(), Some("4T1ABC2xyz"));
+        assert_eq!(hop.envelope_for.as_deref(), Some("jane@example.net"));
+        assert!(hop.tls);
+        assert_eq!(hop.tls_version.as_deref(), Some("TLSv1.3"));
+        assert_eq!(hop.tls_cipher.as_deref(), Some("TLS_AES_256_GCM_SHA384"));
+        assert_eq!(hop.date.unwrap().timestamp(), 1704103205);
+        assert!(hop.problems.is_empty());
+    }
+
+    #[test]
+    fn exim() {
+        let hop = parse_received(EXIM);
+        assert_eq!(hop.from_host.as_deref(), Some("client.example.com"));
+        assert_eq!(hop.from_rdns, None);
+        assert_eq!(hop.from_ip, ip("198.51.100.7"));
+        assert_eq!(hop.by_host.as_deref(), Some("relay.example.net"));
+        assert_eq!(hop.protocol.as_deref(), Some("ESMTPS"));
+        assert_eq!(hop.id.as_deref(), Some("1qXyZa-000ABC-DE"));
+        assert_eq!(hop.envelope_from.as_deref(), Some("bob@example.com"));
+        assert_eq!(hop.envelope_for.as_deref(), Some("jane@example.net"));
+        assert_eq!(hop.tls_version.as_deref(), Some("TLS1.3"));
+        assert_eq!(hop.tls_cipher.as_deref(), Some("TLS_AES_128_GCM_SHA256"));
+        assert_eq!(hop.date.unwrap().timestamp(), 1704103207);
+    }
+
+    #[test]
+    fn gmail() {
+        let hop = parse_received(GMAIL);
+        assert_eq!(hop.from_host.as_deref(), Some("relay.example.net"));
+        assert_eq!(hop.from_rdns.as_deref(), Some("relay.example.net"));
+        assert_eq!(hop.from_ip, ip("203.0.113.5"));
+        assert_eq!(hop.by_host.as_deref(), Some("mx.google.com"));
+        assert_eq!(hop.envelope_for.as_deref(), Some("jane@gmail.com"));
+        assert_eq!(hop.tls_version.as_deref(), Some("TLS1_3"));
+        assert_eq!(hop.tls_cipher.as_deref(), Some("TLS_AES_256_GCM_SHA384"));
+        assert_eq!(hop.date.unwrap().timestamp(), 1704103209);
+
+        let hop = parse_received(GMAIL_INTERNAL);
+        assert_eq!(hop.from_host, None);
+        assert_eq!(hop.by_ip, ip("2002:a05:6358:1234:b0:1a2:3b4c:5d6e"));
+        assert_eq!(hop.protocol.as_deref(), Some("SMTP"));
+        assert!(!hop.tls);
+    }
+
+    #[test]
+    fn delays_and_future_timestamps() {
+        let values: Vec<String> = [GMAIL_INTERNAL, GMAIL, EXIM, POSTFIX].iter()
+            .map(|s| s.to_string())
+            .collect();
+        let now = 1704103210;
+        let out = hops_at(&values, now);
+        let delays: Vec<Option<i64>> = out.iter().map(|h| h.delay).collect();
+        assert_eq!(delays, vec![None, Some(2), Some(2), Some(1)]);
+        assert!(out.iter().all(|h| h.problems.is_empty()));
+
+        // The same chain seen a day and an hour before it happened.
+        let out = hops_at(&values, now - 90000);
+        assert_eq!(out[0].problems, vec!["timestamp 89995 seconds in the future".to_string()]);
+
+        let late = "by mx.example.net; Mon, 01 Jan 2024 11:00:00 +0000".to_string();
+        let out = hops_at(&[late, POSTFIX.to_string()], now);
+        assert_eq!(out[1].delay, Some(3595));
+        assert!(out[1].problems.is_empty());
+    }
+}
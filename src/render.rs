@@ -0,0 +1,182 @@
+use std::fmt;
+
+use mailparse::MailParseError;
+
+use crate::body::preferred_body;
+use crate::body::Preference;
+use crate::html;
+use crate::message::Message;
+use crate::parts::content_id;
+
+
+#[derive(Debug)]
+pub enum RenderError {
+    Parse(MailParseError),
+    Rewrite(lol_html::errors::RewritingError),
+}
+
+
+impl fmt::Display for RenderError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            RenderError::Parse(e) => e.fmt(f),
+            RenderError::Rewrite(e) => e.fmt(f),
+        }
+    }
+}
+
+
+impl std::error::Error for RenderError {}
+
+
+pub struct RenderedHtml {
+    /// Path of the HTML part that was rendered.
+    pub body_path: Vec<usize>,
+    pub output: html::Output,
+    /// `(deferral index, part path)` for each `cid:` URL that matched the
+    /// Content-ID of a part.
+    pub cid_links: Vec<(usize, Vec<usize>)>,
+    /// `(deferral index, content id)` for each `cid:` URL that matched
+    /// nothing, e.g. an inline image the sender failed to attach.
+    pub unresolved_cids: Vec<(usize, String)>,
+}
+
+
+/// Decode the %hh escapes RFC 2392 allows in cid: URLs.
+fn percent_decode(s: &str) -> String {
+    let bytes = s.as_bytes();
+    let mut out = Vec::with_capacity(bytes.len());
+    let mut i = 0;
+    while i < bytes.len() {
+        if bytes[i] == b'%' && i + 2 < bytes.len() {
+            let hex = std::str::from_utf8(&bytes[i+1..i+3]).unwrap_or("");
+            if let Ok(b) = u8::from_str_radix(hex, 16) {
+                out.push(b);
+                i += 3;
+                continue;
+            }
+        }
+        out.push(bytes[i]);
+        i += 1;
+    }
+    String::from_utf8_lossy(&out).into_owned()
+}
+
+
+/// The content id named by a `cid:` URL, if `url` is one.
+pub fn cid_url(url: &str) -> Option<String> {
+    let url = url.trim();
+    // get() rather than indexing: the first four bytes need not end on a
+    // character boundary.
+    if !url.get(..4).is_some_and(|p| p.eq_ignore_ascii_case("cid:")) {
+        return None;
+    }
+    let cid = percent_decode(&url[4..]);
+    Some(cid.trim_matches(|c| c == '<' || c == '>').to_string())
+}
+
+
+fn find_cid(scope: &Message, cid: &str, out: &mut Option<Vec<usize>>) {
+    if out.is_some() {
+        return;
+    }
+
+    if let Some(id) = content_id(scope) {
+        if id.eq_ignore_ascii_case(cid) {
+            *out = Some(scope.path().to_vec());
+            return;
+        }
+    }
+
+    for sub in scope.subparts() {
+        find_cid(&sub, cid, out);
+    }
+}
+
+
+/// Render the preferred HTML body of `msg` through `html::rewrite_html` and
+/// resolve its `cid:` deferrals. Each content id is looked up first within
+/// the multipart/related tree enclosing the body, then across the whole
+/// message. Returns Ok(None) if the message has no HTML body.
+pub fn render_html(msg: &Message) -> Result<Option<RenderedHtml>, RenderError> {
+    let choice = match preferred_body(msg, Preference::Html) {
+        Some(c) if c.mime_type.eq_ignore_ascii_case("text/html") => c,
+        _ => return Ok(None)
+    };
+
+    let body = msg.descendant(&choice.path[msg.path().len()..]).unwrap();
+    let text = body.body().map_err(RenderError::Parse)?;
+    let output = html::rewrite_html(&text).map_err(RenderError::Rewrite)?;
+
+    let mut related = body.parent();
+    while let Some(p) = &related {
+        if p.mime_type().eq_ignore_ascii_case("multipart/related") {
+            break;
+        }
+        related = p.parent();
+    }
+
+    let mut cid_links = Vec::new();
+    let mut unresolved_cids = Vec::new();
+    for deferral in &output.deferrals {
+        if !deferral.kind.is_url() {
+            continue;
+        }
+        let cid = match cid_url(&deferral.data) {
+            Some(cid) => cid,
+            None => continue
+        };
+
+        let mut found = None;
+        if let Some(scope) = &related {
+            find_cid(scope, &cid, &mut found);
+        }
+        if found.is_none() {
+            find_cid(msg, &cid, &mut found);
+        }
+
+        match found {
+            Some(path) => cid_links.push((deferral.i, path)),
+            None => unresolved_cids.push((deferral.i, cid)),
+        }
+    }
+
+    Ok(Some(RenderedHtml {
+        body_path: choice.path,
+        output: output,
+        cid_links: cid_links,
+        unresolved_cids: unresolved_cids,
+    }))
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn cid_urls() {
+        assert_eq!(cid_url(" CID:%3Clogo@x%3E "), Some("logo@x".to_string()));
+        assert_eq!(cid_url("cid:a%20b"), Some("a b".to_string()));
+        assert_eq!(cid_url("http://x/"), None);
+        assert_eq!(cid_url("ci"), None);
+        assert_eq!(cid_url("abc\u{20ac}.png"), None);
+        assert_eq!(cid_url("\u{e9}\u{e9}"), None);
+    }
+
+    #[test]
+    fn non_ascii_urls_do_not_panic() {
+        let buf = "Content-Type: multipart/related; boundary=r\r\n\r\n--r\r\n\
+Content-Type: text/html; charset=utf-8\r\n\r\n\
+<p style=\"background: url(\u{e9}t\u{e9}.png)\">\
+<img src=\"abc\u{20ac}.png\"><img src=\"cid:logo@x\"><img src=\"cid:gone@x\"></p>\r\n\
+--r\r\nContent-Type: image/png\r\nContent-ID: <logo@x>\r\n\r\nPNG\r\n--r--\r\n";
+        let msg = Message::parse(buf.as_bytes()).unwrap();
+        let rendered = render_html(&msg).unwrap().unwrap();
+        assert_eq!(rendered.body_path, vec![0]);
+        assert_eq!(rendered.cid_links.len(), 1);
+        assert_eq!(rendered.cid_links[0].1, vec![1]);
+        assert_eq!(rendered.unresolved_cids.len(), 1);
+        assert_eq!(rendered.unresolved_cids[0].1, "gone@x");
+    }
+}
@@ -0,0 +1,410 @@
+//! Detection of signed and encrypted structure: PGP/MIME (RFC 3156),
+//! S/MIME (RFC 8551) and inline PGP armor (RFC 4880 7).
+//!
+//! Nothing is verified or decrypted here; the point is to hand a verifier
+//! the exact bytes it needs.
+
+use crate::message::Message;
+use crate::message::slice_offset;
+
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Scheme {
+    PgpMime,
+    Smime,
+    InlinePgp,
+}
+
+
+impl Scheme {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            Scheme::PgpMime => "pgp_mime",
+            Scheme::Smime => "smime",
+            Scheme::InlinePgp => "inline_pgp",
+        }
+    }
+}
+
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Protection {
+    Signed,
+    Encrypted,
+    /// S/MIME compressed-data; neither signed nor encrypted, but opaque.
+    Compressed,
+}
+
+
+impl Protection {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            Protection::Signed => "signed",
+            Protection::Encrypted => "encrypted",
+            Protection::Compressed => "compressed",
+        }
+    }
+}
+
+
+#[derive(Clone, Debug)]
+pub struct SecurePart {
+    /// Path of the multipart/signed, multipart/encrypted, pkcs7-mime or
+    /// text part.
+    pub path: Vec<usize>,
+    pub scheme: Scheme,
+    pub protection: Protection,
+    /// The multipart protocol= parameter, or the S/MIME smime-type.
+    pub protocol: Option<String>,
+    pub micalg: Option<String>,
+    /// For multipart/signed, the span of the first body part (its header
+    /// and body) within the part's `Message::buffer()`; for inline PGP, the
+    /// span of the armored block within the decoded body.
+    pub signed_offset: Option<[usize; 2]>,
+    /// The bytes the signature covers, with line endings canonicalized to
+    /// CRLF. For inline PGP this is the whole armored block.
+    pub signed_content: Option<Vec<u8>>,
+    /// Path of the detached signature part.
+    pub signature_path: Option<Vec<usize>>,
+    /// The detached signature, transfer-decoded.
+    pub signature: Option<Vec<u8>>,
+    /// The encrypted (or opaquely signed) data, transfer-decoded: the
+    /// application/octet-stream part of PGP/MIME, the pkcs7-mime body, or
+    /// an inline PGP MESSAGE block.
+    pub payload: Option<Vec<u8>>,
+}
+
+
+impl SecurePart {
+    fn new(path: &[usize], scheme: Scheme, protection: Protection) -> SecurePart {
+        SecurePart {
+            path: path.to_vec(),
+            scheme: scheme,
+            protection: protection,
+            protocol: None,
+            micalg: None,
+            signed_offset: None,
+            signed_content: None,
+            signature_path: None,
+            signature: None,
+            payload: None,
+        }
+    }
+}
+
+
+/// Convert bare LFs and bare CRs to CRLF.
+pub fn canonicalize_crlf(data: &[u8]) -> Vec<u8> {
+    let mut out = Vec::with_capacity(data.len() + data.len() / 32);
+    let mut i = 0;
+    while i < data.len() {
+        match data[i] {
+            b'\r' => {
+                out.extend_from_slice(b"\r\n");
+                if data.get(i + 1) == Some(&b'\n') {
+                    i += 1;
+                }
+            },
+            b'\n' => out.extend_from_slice(b"\r\n"),
+            b => out.push(b)
+        }
+        i += 1;
+    }
+    out
+}
+
+
+/// Span within `body` of the first body part of a multipart: from after
+/// the first delimiter line to before the line ending that precedes the
+/// next one (RFC 2046 5.1.1: that line ending belongs to the delimiter).
+fn first_part_span(body: &[u8], boundary: &str) -> Option<[usize; 2]> {
+    let delimiter = format!("--{}", boundary);
+    let is_delimiter = |line: &[u8]| {
+        line.strip_prefix(delimiter.as_bytes())
+            .map(|rest| rest.iter().all(|&b| b" \t\r\n-".contains(&b)))
+            .unwrap_or(false)
+    };
+
+    let mut start = None;
+    let mut pos = 0;
+    for line in body.split_inclusive(|&b| b == b'\n') {
+        if is_delimiter(line) {
+            match start {
+                None => start = Some(pos + line.len()),
+                Some(s) => {
+                    let mut end = pos;
+                    if end > s && body[end-1] == b'\n' {
+                        end -= 1;
+                    }
+                    if end > s && body[end-1] == b'\r' {
+                        end -= 1;
+                    }
+                    return Some([s, end - s]);
+                }
+            }
+        }
+        pos += line.len();
+    }
+    None
+}
+
+
+fn lower_param(msg: &Message, key: &str) -> Option<String> {
+    msg.param(key).map(|v| v.to_ascii_lowercase())
+}
+
+
+fn multipart_signed(msg: &Message) -> Option<SecurePart> {
+    let protocol = lower_param(msg, "protocol");
+    let scheme = match protocol.as_deref() {
+        Some("application/pgp-signature") => Scheme::PgpMime,
+        Some("application/pkcs7-signature") | Some("application/x-pkcs7-signature") => Scheme::Smime,
+        _ => return None
+    };
+
+    let mut out = SecurePart::new(msg.path(), scheme, Protection::Signed);
+    out.protocol = protocol;
+    out.micalg = lower_param(msg, "micalg");
+
+    if let Some(boundary) = msg.param("boundary") {
+        let body = msg.whole_body();
+        if let Some([start, len]) = first_part_span(body, boundary) {
+            let base = slice_offset(msg.buffer(), body);
+            out.signed_offset = Some([base + start, len]);
+            out.signed_content = Some(canonicalize_crlf(&body[start..start+len]));
+        }
+    }
+
+    if let Some(sig) = msg.subpart(1) {
+        out.signature = sig.body_raw().ok();
+        out.signature_path = Some(sig.path().to_vec());
+    }
+    Some(out)
+}
+
+
+fn multipart_encrypted(msg: &Message) -> Option<SecurePart> {
+    let protocol = lower_param(msg, "protocol");
+    if protocol.as_deref() != Some("application/pgp-encrypted") {
+        return None;
+    }
+
+    let mut out = SecurePart::new(msg.path(), Scheme::PgpMime, Protection::Encrypted);
+    out.protocol = protocol;
+    out.payload = msg.subpart(1).and_then(|p| p.body_raw().ok());
+    Some(out)
+}
+
+
+fn pkcs7_mime(msg: &Message) -> SecurePart {
+    let smime_type = lower_param(msg, "smime-type");
+    let protection = match smime_type.as_deref() {
+        Some("signed-data") | Some("certs-only") => Protection::Signed,
+        Some("compressed-data") => Protection::Compressed,
+        // enveloped-data, authEnveloped-data, or missing, which in practice
+        // is almost always enveloped-data.
+        _ => Protection::Encrypted
+    };
+
+    let mut out = SecurePart::new(msg.path(), Scheme::Smime, protection);
+    out.protocol = smime_type;
+    out.payload = msg.body_raw().ok();
+    out
+}
+
+
+/// Armored PGP blocks in a decoded text body: clearsigned messages (from
+/// the BEGIN PGP SIGNED MESSAGE line through the END PGP SIGNATURE line)
+/// and PGP MESSAGE blocks.
+fn inline_pgp(msg: &Message, text: &[u8]) -> Vec<SecurePart> {
+    let mut out = Vec::new();
+    let mut pos = 0;
+    let mut open: Option<(usize, Protection, &[u8])> = None;
+
+    for line in text.split_inclusive(|&b| b == b'\n') {
+        let content = line.trim_ascii_end();
+        let next = pos + line.len();
+
+        match open {
+            None => {
+                if content == b"-----BEGIN PGP SIGNED MESSAGE-----" {
+                    open = Some((pos, Protection::Signed, b"-----END PGP SIGNATURE-----"));
+                } else if content == b"-----BEGIN PGP MESSAGE-----" {
+                    // May only be signed, but there is no telling without
+                    // parsing the packets.
+                    open = Some((pos, Protection::Encrypted, b"-----END PGP MESSAGE-----"));
+                }
+            },
+            Some((start, protection, end)) if content == end => {
+                let block = &text[start..next];
+                let mut part = SecurePart::new(msg.path(), Scheme::InlinePgp, protection);
+                part.signed_offset = Some([start, next - start]);
+                match protection {
+                    Protection::Signed => part.signed_content = Some(canonicalize_crlf(block)),
+                    _ => part.payload = Some(block.to_vec())
+                }
+                out.push(part);
+                open = None;
+            },
+            Some(_) => {}
+        }
+        pos = next;
+    }
+
+    out
+}
+
+
+/// Signed and encrypted parts in `msg` and its descendants, outermost
+/// first.
+pub fn detect(msg: &Message) -> Vec<SecurePart> {
+    let mut out = Vec::new();
+    let mime_type = msg.mime_type().to_ascii_lowercase();
+
+    match mime_type.as_str() {
+        "multipart/signed" => out.extend(multipart_signed(msg)),
+        "multipart/encrypted" => out.extend(multipart_encrypted(msg)),
+        "application/pkcs7-mime" | "application/x-pkcs7-mime" => out.push(pkcs7_mime(msg)),
+        "text/plain" if msg.part().subparts.is_empty() => {
+            if let Ok(text) = msg.body_raw() {
+                out.extend(inline_pgp(msg, &text));
+            }
+        },
+        _ => {}
+    }
+
+    for sub in msg.subparts() {
+        out.extend(detect(&sub));
+    }
+    out
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn first_part_span_excludes_the_delimiter_line_ending() {
+        let body = b"preamble\r\n--b\r\nContent-Type: text/plain\r\n\r\nsigned\r\n\r\n--b\r\nsig\r\n--b--\r\n";
+        let [start, len] = first_part_span(body, "b").unwrap();
+        assert_eq!(&body[start..start+len], b"Content-Type: text/plain\r\n\r\nsigned\r\n");
+
+        // "--bb" is not a delimiter for boundary "b", and the closing
+        // delimiter ends the part too.
+        let body = b"--b\nline\n--bb\nmore\n--b--\n";
+        let [start, len] = first_part_span(body, "b").unwrap();
+        assert_eq!(&body[start..start+len], b"line\n--bb\nmore");
+
+        assert!(first_part_span(b"--b\r\nno end\r\n", "b").is_none());
+        assert!(first_part_span(b"no delimiters\r\n", "b").is_none());
+    }
+
+    #[test]
+    fn multipart_signed_range() {
+        let buf = b"Content-Type: multipart/signed; boundary=b;\n\x20\
+protocol=\"application/PGP-signature\"; micalg=PGP-SHA256\n\
+\n\
+This is an OpenPGP/MIME signed message.\n\
+--b\n\
+Content-Type: text/plain\n\
+\n\
+Hello.\n\
+\n\
+--b\n\
+Content-Type: application/pgp-signature\n\
+\n\
+-----BEGIN PGP SIGNATURE-----\n\
+-----END PGP SIGNATURE-----\n\
+--b--\n";
+        let msg = Message::parse(buf).unwrap();
+        let found = detect(&msg);
+        assert_eq!(found.len(), 1);
+        let s = &found[0];
+        assert_eq!((s.scheme, s.protection), (Scheme::PgpMime, Protection::Signed));
+        assert_eq!(s.protocol.as_deref(), Some("application/pgp-signature"));
+        assert_eq!(s.micalg.as_deref(), Some("pgp-sha256"));
+
+        let [start, len] = s.signed_offset.unwrap();
+        assert_eq!(&buf[start..start+len], b"Content-Type: text/plain\n\nHello.\n");
+        assert_eq!(s.signed_content.as_deref(),
+                   Some(&b"Content-Type: text/plain\r\n\r\nHello.\r\n"[..]));
+        assert_eq!(s.signature_path.as_deref(), Some(&[1][..]));
+        assert!(s.signature.as_ref().unwrap().starts_with(b"-----BEGIN PGP SIGNATURE-----"));
+    }
+
+    #[test]
+    fn unknown_signature_protocol() {
+        let buf = b"Content-Type: multipart/signed; boundary=b; protocol=\"application/x-other\"\r\n\r\n\
+--b\r\n\r\nx\r\n--b\r\n\r\ny\r\n--b--\r\n";
+        assert!(detect(&Message::parse(buf).unwrap()).is_empty());
+    }
+
+    #[test]
+    fn multipart_encrypted_payload() {
+        let buf = b"Content-Type: multipart/encrypted; boundary=e; protocol=\"application/pgp-encrypted\"\r\n\r\n\
+--e\r\nContent-Type: application/pgp-encrypted\r\n\r\nVersion: 1\r\n\
+--e\r\nContent-Type: application/octet-stream\r\n\r\n-----BEGIN PGP MESSAGE-----\r\n\
+--e--\r\n";
+        let found = detect(&Message::parse(buf).unwrap());
+        assert_eq!(found.len(), 1);
+        assert_eq!(found[0].protection, Protection::Encrypted);
+        assert!(found[0].payload.as_ref().unwrap().starts_with(b"-----BEGIN PGP MESSAGE-----"));
+    }
+
+    #[test]
+    fn smime_types() {
+        let cases = [
+            ("application/pkcs7-mime; smime-type=signed-data", Protection::Signed),
+            ("application/pkcs7-mime; smime-type=certs-only", Protection::Signed),
+            ("application/pkcs7-mime; smime-type=Compressed-Data", Protection::Compressed),
+            ("application/pkcs7-mime; smime-type=enveloped-data", Protection::Encrypted),
+            ("application/pkcs7-mime; smime-type=authEnveloped-data", Protection::Encrypted),
+            ("application/x-pkcs7-mime", Protection::Encrypted),
+        ];
+        for (ctype, protection) in cases {
+            let buf = format!("Content-Type: {}\r\nContent-Transfer-Encoding: base64\r\n\r\nMIAG\r\n", ctype);
+            let found = detect(&Message::parse(buf.as_bytes()).unwrap());
+            assert_eq!(found.len(), 1, "{}", ctype);
+            assert_eq!((found[0].scheme, found[0].protection), (Scheme::Smime, protection), "{}", ctype);
+            assert_eq!(found[0].payload.as_deref(), Some(&[0x30, 0x80, 0x06][..]));
+        }
+    }
+
+    #[test]
+    fn inline_pgp_blocks() {
+        let body = b"Hi,\n\
+-----BEGIN PGP SIGNED MESSAGE-----\n\
+Hash: SHA256\n\
+\n\
+Signed text.\n\
+-----BEGIN PGP SIGNATURE-----\n\
+sig\n\
+-----END PGP SIGNATURE-----\n\
+and\n\
+-----BEGIN PGP MESSAGE-----\n\
+data\n\
+-----END PGP MESSAGE-----\n\
+-----BEGIN PGP MESSAGE-----\n\
+never closed\n";
+        let mut buf = b"Content-Type: text/plain\n\n".to_vec();
+        buf.extend_from_slice(body);
+        let found = detect(&Message::parse(&buf).unwrap());
+        assert_eq!(found.len(), 2);
+
+        let signed = &found[0];
+        assert_eq!((signed.scheme, signed.protection), (Scheme::InlinePgp, Protection::Signed));
+        let [start, len] = signed.signed_offset.unwrap();
+        assert_eq!(start, 4);
+        assert!(body[start..start+len].ends_with(b"-----END PGP SIGNATURE-----\n"));
+        let content = signed.signed_content.as_ref().unwrap();
+        assert!(content.starts_with(b"-----BEGIN PGP SIGNED MESSAGE-----\r\nHash: SHA256\r\n"));
+        assert!(content.ends_with(b"-----END PGP SIGNATURE-----\r\n"));
+
+        let encrypted = &found[1];
+        assert_eq!(encrypted.protection, Protection::Encrypted);
+        assert_eq!(encrypted.payload.as_deref(),
+                   Some(&b"-----BEGIN PGP MESSAGE-----\ndata\n-----END PGP MESSAGE-----\n"[..]));
+        assert!(encrypted.signed_content.is_none());
+    }
+}
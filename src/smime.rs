@@ -0,0 +1,696 @@
+//! Offline S/MIME signature verification (RFC 8551, RFC 5652 5.6) against a
+//! caller-supplied set of trusted certificates.
+//!
+//! The CMS framing is read by hand rather than through a DER decoder, as
+//! signers commonly emit BER (indefinite lengths, chunked OCTET STRINGs)
+//! and the signed attributes must be hashed exactly as they were sent.
+//! Certificates are decoded with x509-cert.
+
+use std::time::SystemTime;
+use std::time::UNIX_EPOCH;
+
+use p256::ecdsa::signature::hazmat::PrehashVerifier;
+use rsa::pkcs8::DecodePublicKey;
+use sha2::Digest;
+use x509_cert::der::asn1::ObjectIdentifier;
+use x509_cert::der::Decode;
+use x509_cert::der::Encode;
+use x509_cert::ext::pkix::name::GeneralName;
+use x509_cert::ext::pkix::BasicConstraints;
+use x509_cert::ext::pkix::ExtendedKeyUsage;
+use x509_cert::ext::pkix::SubjectAltName;
+use x509_cert::ext::pkix::SubjectKeyIdentifier;
+use x509_cert::spki::SubjectPublicKeyInfoOwned;
+use x509_cert::time::Time;
+use x509_cert::Certificate;
+
+use crate::address::Address;
+use crate::message::Message;
+use crate::security;
+use crate::security::Protection;
+use crate::security::Scheme;
+
+
+const ID_SIGNED_DATA: ObjectIdentifier = ObjectIdentifier::new_unwrap("1.2.840.113549.1.7.2");
+const ID_CONTENT_TYPE: ObjectIdentifier = ObjectIdentifier::new_unwrap("1.2.840.113549.1.9.3");
+const ID_MESSAGE_DIGEST: ObjectIdentifier = ObjectIdentifier::new_unwrap("1.2.840.113549.1.9.4");
+const ID_SIGNING_TIME: ObjectIdentifier = ObjectIdentifier::new_unwrap("1.2.840.113549.1.9.5");
+const ID_EMAIL_ADDRESS: ObjectIdentifier = ObjectIdentifier::new_unwrap("1.2.840.113549.1.9.1");
+const ID_KP_EMAIL_PROTECTION: ObjectIdentifier = ObjectIdentifier::new_unwrap("1.3.6.1.5.5.7.3.4");
+const ID_ANY_EXTENDED_KEY_USAGE: ObjectIdentifier = ObjectIdentifier::new_unwrap("2.5.29.37.0");
+
+const RSA_ENCRYPTION: ObjectIdentifier = ObjectIdentifier::new_unwrap("1.2.840.113549.1.1.1");
+const EC_PUBLIC_KEY: ObjectIdentifier = ObjectIdentifier::new_unwrap("1.2.840.10045.2.1");
+const SECP256R1: ObjectIdentifier = ObjectIdentifier::new_unwrap("1.2.840.10045.3.1.7");
+const SECP384R1: ObjectIdentifier = ObjectIdentifier::new_unwrap("1.3.132.0.34");
+
+/// Keys smaller than this are rejected, as for DKIM.
+const MIN_RSA_BITS: usize = 1024;
+
+/// Nesting limit for BER decoding and for certificate chains.
+const MAX_DEPTH: usize = 16;
+
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum SmimeStatus {
+    /// The signature is good and the signer chains to a trusted
+    /// certificate.
+    Valid,
+    /// The signature is good, but the signer's certificate could not be
+    /// validated against the trusted set.
+    Untrusted,
+    /// The signature or the content digest does not match.
+    Invalid,
+    /// The signature could not be checked: malformed, or an unsupported
+    /// algorithm.
+    Error,
+}
+
+
+impl SmimeStatus {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            SmimeStatus::Valid => "valid",
+            SmimeStatus::Untrusted => "untrusted",
+            SmimeStatus::Invalid => "invalid",
+            SmimeStatus::Error => "error",
+        }
+    }
+}
+
+
+#[derive(Clone, Debug)]
+pub struct SmimeResult {
+    /// Path of the multipart/signed or application/pkcs7-mime part.
+    pub path: Vec<usize>,
+    pub status: SmimeStatus,
+    pub reason: String,
+    /// Signer certificate subject, RFC 4514 style.
+    pub subject: Option<String>,
+    /// rfc822Name subjectAltNames and emailAddress subject attributes of
+    /// the signer certificate, lower-cased.
+    pub emails: Vec<String>,
+    /// The signingTime attribute, in seconds since the Unix epoch. It is not
+    /// used to validate the certificate.
+    pub signing_time: Option<i64>,
+    /// Whether one of `emails` is a From address of the enclosing message.
+    pub from_matches: bool,
+}
+
+
+impl SmimeResult {
+    fn new(path: &[usize], status: SmimeStatus, reason: &str) -> SmimeResult {
+        SmimeResult {
+            path: path.to_vec(),
+            status: status,
+            reason: reason.to_string(),
+            subject: None,
+            emails: Vec::new(),
+            signing_time: None,
+            from_matches: false,
+        }
+    }
+}
+
+
+/// One BER-encoded value. Tags are single-octet, which is all CMS uses.
+struct Tlv<'a> {
+    tag: u8,
+    /// Content octets; for indefinite lengths, up to the end-of-contents
+    /// marker.
+    content: &'a [u8],
+    /// The whole encoding, identifier and length octets included.
+    raw: &'a [u8],
+}
+
+
+fn read_tlv(data: &[u8], depth: usize) -> Option<(Tlv<'_>, &[u8])> {
+    let tag = *data.first()?;
+    if tag & 0x1f == 0x1f || depth > MAX_DEPTH {
+        return None;
+    }
+
+    let first = *data.get(1)?;
+    let (len, header) = match first {
+        0..=0x7f => (Some(first as usize), 2),
+        0x80 => (None, 2),
+        _ => {
+            let n = (first & 0x7f) as usize;
+            if n > 4 {
+                return None;
+            }
+            let len = data.get(2..2+n)?.iter().fold(0usize, |acc, &b| acc << 8 | b as usize);
+            (Some(len), 2 + n)
+        }
+    };
+
+    let (content, end) = match len {
+        Some(len) => {
+            let end = header.checked_add(len)?;
+            (data.get(header..end)?, end)
+        },
+        None => {
+            if tag & 0x20 == 0 {
+                return None;
+            }
+            let mut rest = &data[header..];
+            while !rest.starts_with(&[0, 0]) {
+                rest = read_tlv(rest, depth + 1)?.1;
+            }
+            let content_end = data.len() - rest.len();
+            (&data[header..content_end], content_end + 2)
+        }
+    };
+
+    let tlv = Tlv {
+        tag: tag,
+        content: content,
+        raw: &data[..end],
+    };
+    Some((tlv, &data[end..]))
+}
+
+
+fn children<'a>(tlv: &Tlv<'a>) -> Option<Vec<Tlv<'a>>> {
+    let mut out = Vec::new();
+    let mut rest = tlv.content;
+    while !rest.is_empty() {
+        let (child, r) = read_tlv(rest, 0)?;
+        out.push(child);
+        rest = r;
+    }
+    Some(out)
+}
+
+
+/// The value of an OCTET STRING, joining the chunks of a constructed one.
+fn octets(tlv: &Tlv, depth: usize) -> Option<Vec<u8>> {
+    match tlv.tag {
+        0x04 => Some(tlv.content.to_vec()),
+        0x24 if depth < MAX_DEPTH => {
+            let mut out = Vec::new();
+            for c in children(tlv)? {
+                out.extend(octets(&c, depth + 1)?);
+            }
+            Some(out)
+        },
+        _ => None
+    }
+}
+
+
+fn oid(tlv: &Tlv) -> Option<ObjectIdentifier> {
+    if tlv.tag != 0x06 {
+        return None;
+    }
+    ObjectIdentifier::from_bytes(tlv.content).ok()
+}
+
+
+/// The OID of an AlgorithmIdentifier.
+fn algorithm(tlv: &Tlv) -> Option<ObjectIdentifier> {
+    oid(children(tlv)?.first()?)
+}
+
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+enum Hash {
+    Sha1,
+    Sha256,
+    Sha384,
+    Sha512,
+}
+
+
+impl Hash {
+    /// From a digest algorithm, or a signature algorithm that names one.
+    fn from_oid(oid: &ObjectIdentifier) -> Option<Hash> {
+        match oid.to_string().as_str() {
+            "1.3.14.3.2.26" | "1.2.840.113549.1.1.5" | "1.2.840.10045.4.1" => Some(Hash::Sha1),
+            "2.16.840.1.101.3.4.2.1" | "1.2.840.113549.1.1.11" | "1.2.840.10045.4.3.2" => Some(Hash::Sha256),
+            "2.16.840.1.101.3.4.2.2" | "1.2.840.113549.1.1.12" | "1.2.840.10045.4.3.3" => Some(Hash::Sha384),
+            "2.16.840.1.101.3.4.2.3" | "1.2.840.113549.1.1.13" | "1.2.840.10045.4.3.4" => Some(Hash::Sha512),
+            _ => None
+        }
+    }
+
+    fn digest(&self, data: &[u8]) -> Vec<u8> {
+        match self {
+            Hash::Sha1 => sha1::Sha1::digest(data).to_vec(),
+            Hash::Sha256 => sha2::Sha256::digest(data).to_vec(),
+            Hash::Sha384 => sha2::Sha384::digest(data).to_vec(),
+            Hash::Sha512 => sha2::Sha512::digest(data).to_vec(),
+        }
+    }
+
+    fn pkcs1v15(&self) -> rsa::Pkcs1v15Sign {
+        match self {
+            Hash::Sha1 => rsa::Pkcs1v15Sign::new::<sha1::Sha1>(),
+            Hash::Sha256 => rsa::Pkcs1v15Sign::new::<sha2::Sha256>(),
+            Hash::Sha384 => rsa::Pkcs1v15Sign::new::<sha2::Sha384>(),
+            Hash::Sha512 => rsa::Pkcs1v15Sign::new::<sha2::Sha512>(),
+        }
+    }
+}
+
+
+/// The public key algorithm a SignerInfo signatureAlgorithm that
+/// `verify_signature` implements requires, and the hash it names, if any.
+/// RSASSA-PSS, among others, is not implemented.
+fn signature_algorithm(oid: &ObjectIdentifier) -> Option<(ObjectIdentifier, Option<Hash>)> {
+    match oid.to_string().as_str() {
+        "1.2.840.113549.1.1.1" => Some((RSA_ENCRYPTION, None)),
+        "1.2.840.113549.1.1.5" | "1.2.840.113549.1.1.11" | "1.2.840.113549.1.1.12" |
+        "1.2.840.113549.1.1.13" => Some((RSA_ENCRYPTION, Hash::from_oid(oid))),
+        "1.2.840.10045.2.1" => Some((EC_PUBLIC_KEY, None)),
+        "1.2.840.10045.4.1" | "1.2.840.10045.4.3.2" | "1.2.840.10045.4.3.3" |
+        "1.2.840.10045.4.3.4" => Some((EC_PUBLIC_KEY, Hash::from_oid(oid))),
+        _ => None
+    }
+}
+
+
+/// Check `signature` over data hashing to `digest` with the key in `spki`:
+/// RSA PKCS #1 v1.5, or ECDSA on P-256 or P-384.
+fn verify_signature(spki: &SubjectPublicKeyInfoOwned, hash: Hash, digest: &[u8], signature: &[u8])
+    -> Result<(), &'static str>
+{
+    if spki.algorithm.oid == RSA_ENCRYPTION {
+        let der = spki.to_der().map_err(|_| "malformed public key")?;
+        let key = rsa::RsaPublicKey::from_public_key_der(&der).map_err(|_| "malformed RSA key")?;
+        if rsa::traits::PublicKeyParts::size(&key) * 8 < MIN_RSA_BITS {
+            return Err("RSA key too small");
+        }
+        return key.verify(hash.pkcs1v15(), digest, signature).map_err(|_| "bad signature");
+    }
+
+    if spki.algorithm.oid != EC_PUBLIC_KEY {
+        return Err("unsupported key algorithm");
+    }
+    let curve = spki.algorithm.parameters.as_ref()
+        .and_then(|p| p.decode_as::<ObjectIdentifier>().ok());
+    let point = spki.subject_public_key.raw_bytes();
+    let ok = match curve {
+        Some(c) if c == SECP256R1 => {
+            let key = p256::ecdsa::VerifyingKey::from_sec1_bytes(point).map_err(|_| "malformed EC key")?;
+            let sig = p256::ecdsa::Signature::from_der(signature).map_err(|_| "malformed ECDSA signature")?;
+            key.verify_prehash(digest, &sig).is_ok()
+        },
+        Some(c) if c == SECP384R1 => {
+            let key = p384::ecdsa::VerifyingKey::from_sec1_bytes(point).map_err(|_| "malformed EC key")?;
+            let sig = p384::ecdsa::Signature::from_der(signature).map_err(|_| "malformed ECDSA signature")?;
+            key.verify_prehash(digest, &sig).is_ok()
+        },
+        _ => return Err("unsupported elliptic curve")
+    };
+    if ok { Ok(()) } else { Err("bad signature") }
+}
+
+
+/// Whether `issuer` signed `cert`.
+fn issued_by(cert: &Certificate, issuer: &Certificate) -> bool {
+    if cert.tbs_certificate.issuer != issuer.tbs_certificate.subject {
+        return false;
+    }
+    let hash = match Hash::from_oid(&cert.signature_algorithm.oid) {
+        Some(h) => h,
+        None => return false
+    };
+    let tbs = match cert.tbs_certificate.to_der() {
+        Ok(t) => t,
+        Err(_) => return false
+    };
+    let signature = match cert.signature.as_bytes() {
+        Some(s) => s,
+        None => return false
+    };
+    verify_signature(&issuer.tbs_certificate.subject_public_key_info, hash,
+                     &hash.digest(&tbs), signature).is_ok()
+}
+
+
+fn valid_at(cert: &Certificate, at: u64) -> bool {
+    let validity = &cert.tbs_certificate.validity;
+    validity.not_before.to_unix_duration().as_secs() <= at
+        && at <= validity.not_after.to_unix_duration().as_secs()
+}
+
+
+fn is_ca(cert: &Certificate) -> bool {
+    match cert.tbs_certificate.get::<BasicConstraints>() {
+        Ok(Some((_, bc))) => bc.ca,
+        _ => false
+    }
+}
+
+
+/// Find a path from `signer` through the certificates that came with the
+/// signature to one in `trusted`, with every certificate valid at `at`.
+fn validate_chain(signer: &Certificate, pool: &[Certificate], trusted: &[Certificate], at: u64)
+    -> Result<(), String>
+{
+    if let Ok(Some((_, eku))) = signer.tbs_certificate.get::<ExtendedKeyUsage>() {
+        if !eku.0.iter().any(|k| *k == ID_KP_EMAIL_PROTECTION || *k == ID_ANY_EXTENDED_KEY_USAGE) {
+            return Err("certificate is not for email protection".to_string());
+        }
+    }
+
+    let mut current = signer;
+    for _ in 0..MAX_DEPTH {
+        if !valid_at(current, at) {
+            return Err(format!("certificate for {} is expired or not yet valid",
+                               current.tbs_certificate.subject));
+        }
+        if trusted.contains(current) {
+            return Ok(());
+        }
+        if let Some(anchor) = trusted.iter().find(|t| issued_by(current, t)) {
+            if !valid_at(anchor, at) {
+                return Err(format!("trusted certificate for {} is expired or not yet valid",
+                                   anchor.tbs_certificate.subject));
+            }
+            return Ok(());
+        }
+        match pool.iter().find(|c| *c != current && is_ca(c) && issued_by(current, c)) {
+            Some(issuer) => current = issuer,
+            None => return Err(format!("no trusted issuer for {}", current.tbs_certificate.issuer))
+        }
+    }
+    Err("certificate chain too long".to_string())
+}
+
+
+fn certificate_emails(cert: &Certificate) -> Vec<String> {
+    let mut out = Vec::new();
+    if let Ok(Some((_, san))) = cert.tbs_certificate.get::<SubjectAltName>() {
+        for name in san.0 {
+            if let GeneralName::Rfc822Name(addr) = name {
+                out.push(addr.to_string().to_ascii_lowercase());
+            }
+        }
+    }
+    for rdn in &cert.tbs_certificate.subject.0 {
+        for atv in rdn.0.iter() {
+            if atv.oid == ID_EMAIL_ADDRESS {
+                let addr = String::from_utf8_lossy(atv.value.value()).to_ascii_lowercase();
+                if !out.contains(&addr) {
+                    out.push(addr);
+                }
+            }
+        }
+    }
+    out
+}
+
+
+/// Whether the signer identifier `sid` (IssuerAndSerialNumber or [0]
+/// SubjectKeyIdentifier) names `cert`.
+fn identifies(sid: &Tlv, cert: &Certificate) -> bool {
+    let tbs = &cert.tbs_certificate;
+    match sid.tag {
+        0x30 => {
+            let parts = match children(sid) {
+                Some(p) if p.len() == 2 => p,
+                _ => return false
+            };
+            let issuer_matches = match tbs.issuer.to_der() {
+                Ok(der) => der == parts[0].raw,
+                Err(_) => false
+            };
+            issuer_matches && parts[1].content == tbs.serial_number.as_bytes()
+        },
+        0x80 => match tbs.get::<SubjectKeyIdentifier>() {
+            Ok(Some((_, ski))) => ski.0.as_bytes() == sid.content,
+            _ => false
+        },
+        _ => false
+    }
+}
+
+
+fn now() -> u64 {
+    SystemTime::now().duration_since(UNIX_EPOCH).map(|d| d.as_secs()).unwrap_or(0)
+}
+
+
+/// Verify every signer of a CMS SignedData. `detached` is the signed
+/// content of a multipart/signed, already canonicalized; otherwise the
+/// content is taken from the SignedData itself.
+pub fn verify_cms(cms: &[u8], detached: Option<&[u8]>, trusted: &[Certificate], path: &[usize])
+    -> Vec<SmimeResult>
+{
+    let error = |reason: &str| vec![SmimeResult::new(path, SmimeStatus::Error, reason)];
+
+    let content_info = match read_tlv(cms, 0).and_then(|(t, _)| children(&t)) {
+        Some(c) => c,
+        None => return error("malformed ContentInfo")
+    };
+    if content_info.len() < 2 || oid(&content_info[0]) != Some(ID_SIGNED_DATA) {
+        return error("not a SignedData");
+    }
+    let signed_data = match children(&content_info[1]).and_then(|c| c.into_iter().next()) {
+        Some(sd) => sd,
+        None => return error("malformed SignedData")
+    };
+    let fields = match children(&signed_data) {
+        Some(f) if f.len() >= 4 => f,
+        _ => return error("malformed SignedData")
+    };
+
+    let content_type = children(&fields[2]).and_then(|c| c.first().and_then(oid));
+    let encapsulated = children(&fields[2])
+        .and_then(|c| c.into_iter().nth(1))
+        .and_then(|e| children(&e))
+        .and_then(|c| c.first().and_then(|o| octets(o, 0)));
+    let content = match (detached, &encapsulated) {
+        (Some(d), _) => d,
+        (None, Some(e)) => e.as_slice(),
+        (None, None) => return error("no signed content")
+    };
+
+    let mut certificates = Vec::new();
+    for field in &fields[3..fields.len()-1] {
+        if field.tag != 0xa0 {
+            continue;
+        }
+        for choice in children(field).unwrap_or_default() {
+            if let Ok(cert) = Certificate::from_der(choice.raw) {
+                certificates.push(cert);
+            }
+        }
+    }
+
+    let signer_infos = match children(&fields[fields.len()-1]) {
+        Some(s) if !s.is_empty() => s,
+        _ => return error("no signers")
+    };
+    signer_infos.iter()
+        .map(|si| verify_signer(si, content, content_type.as_ref(), &certificates, trusted, path))
+        .collect()
+}
+
+
+fn verify_signer(signer_info: &Tlv, content: &[u8], content_type: Option<&ObjectIdentifier>,
+                 certificates: &[Certificate], trusted: &[Certificate], path: &[usize])
+    -> SmimeResult
+{
+    let mut result = SmimeResult::new(path, SmimeStatus::Error, "");
+    let fields = match children(signer_info) {
+        Some(f) if f.len() >= 5 => f,
+        _ => {
+            result.reason = "malformed SignerInfo".to_string();
+            return result;
+        }
+    };
+
+    let cert = match certificates.iter().chain(trusted).find(|c| identifies(&fields[1], c)) {
+        Some(c) => c,
+        None => {
+            result.reason = "signer certificate not included".to_string();
+            return result;
+        }
+    };
+    result.subject = Some(cert.tbs_certificate.subject.to_string());
+    result.emails = certificate_emails(cert);
+
+    let hash = match algorithm(&fields[2]).as_ref().and_then(Hash::from_oid) {
+        Some(h) => h,
+        None => {
+            result.reason = "unsupported digest algorithm".to_string();
+            return result;
+        }
+    };
+
+    let (signed_attrs, rest) = match fields[3].tag {
+        0xa0 => (Some(&fields[3]), &fields[4..]),
+        _ => (None, &fields[3..])
+    };
+    let (key_algorithm, named_hash) = match rest.first().and_then(algorithm).as_ref()
+        .and_then(signature_algorithm)
+    {
+        Some(a) => a,
+        None => {
+            result.reason = "unsupported signature algorithm".to_string();
+            return result;
+        }
+    };
+    if named_hash.is_some_and(|h| h != hash) {
+        result.reason = "signature algorithm does not match digest algorithm".to_string();
+        return result;
+    }
+    if cert.tbs_certificate.subject_public_key_info.algorithm.oid != key_algorithm {
+        result.reason = "signature algorithm does not match signer key".to_string();
+        return result;
+    }
+
+    let signature = match rest.get(1).and_then(|s| octets(s, 0)) {
+        Some(s) => s,
+        None => {
+            result.reason = "missing signature".to_string();
+            return result;
+        }
+    };
+
+    let content_digest = hash.digest(content);
+    let digest = match signed_attrs {
+        None => content_digest,
+        Some(attrs) => {
+            let mut message_digest = None;
+            let mut signed_type = None;
+            for attr in children(attrs).unwrap_or_default() {
+                let parts = children(&attr).unwrap_or_default();
+                let value = match parts.get(1).and_then(|v| children(v)).and_then(|v| v.into_iter().next()) {
+                    Some(v) => v,
+                    None => continue
+                };
+                match parts.first().and_then(oid) {
+                    Some(o) if o == ID_MESSAGE_DIGEST => message_digest = octets(&value, 0),
+                    Some(o) if o == ID_CONTENT_TYPE => signed_type = oid(&value),
+                    Some(o) if o == ID_SIGNING_TIME => {
+                        result.signing_time = Time::from_der(value.raw).ok()
+                            .map(|t| t.to_unix_duration().as_secs() as i64);
+                    },
+                    _ => {}
+                }
+            }
+
+            if message_digest.as_deref() != Some(content_digest.as_slice()) {
+                result.status = SmimeStatus::Invalid;
+                result.reason = "content digest mismatch".to_string();
+                return result;
+            }
+
+            // RFC 5652 5.3: with signed attributes, the content type must
+            // be among them, and be the one the content is labelled with.
+            if signed_type.is_none() || content_type.is_none() {
+                result.reason = "missing content type".to_string();
+                return result;
+            }
+            if signed_type.as_ref() != content_type {
+                result.status = SmimeStatus::Invalid;
+                result.reason = "content type mismatch".to_string();
+                return result;
+            }
+
+            // RFC 5652 5.4: the signature covers the attributes encoded
+            // with an explicit SET OF tag in place of the [0].
+            let mut encoded = attrs.raw.to_vec();
+            encoded[0] = 0x31;
+            hash.digest(&encoded)
+        }
+    };
+
+    if let Err(e) = verify_signature(&cert.tbs_certificate.subject_public_key_info, hash, &digest, &signature) {
+        result.status = match e {
+            "bad signature" => SmimeStatus::Invalid,
+            _ => SmimeStatus::Error
+        };
+        result.reason = e.to_string();
+        return result;
+    }
+
+    // The signingTime attribute is the signer's own claim, so validating
+    // at it would let a compromised or expired key backdate signatures.
+    match validate_chain(cert, certificates, trusted, now()) {
+        Ok(()) => {
+            result.status = SmimeStatus::Valid;
+            result.reason = "signature verified".to_string();
+        },
+        Err(e) => {
+            result.status = SmimeStatus::Untrusted;
+            result.reason = e;
+        }
+    }
+    result
+}
+
+
+/// Trusted certificates from PEM (any number) or a single DER certificate.
+pub fn load_certificates(data: &[u8]) -> Result<Vec<Certificate>, String> {
+    let is_pem = data.windows(11).any(|w| w == b"-----BEGIN ");
+    let loaded = if is_pem {
+        Certificate::load_pem_chain(data)
+    } else {
+        Certificate::from_der(data).map(|c| vec![c])
+    };
+    loaded.map_err(|e| e.to_string())
+}
+
+
+/// From addresses of the message `part` belongs to: the root, or the
+/// nearest message embedded in a message/rfc822 part.
+fn from_addresses(part: &Message) -> Vec<String> {
+    let mut msg = part.clone();
+    while let Some(parent) = msg.parent() {
+        if parent.embedded_message().is_some() {
+            break;
+        }
+        msg = parent;
+    }
+
+    let mut out = Vec::new();
+    for addr in msg.headers().addresses("From").unwrap_or_default() {
+        match addr {
+            Address::Single(mb) => out.push(mb.addr.to_ascii_lowercase()),
+            Address::Group { members, .. } => {
+                out.extend(members.iter().map(|mb| mb.addr.to_ascii_lowercase()));
+            }
+        }
+    }
+    out
+}
+
+
+/// Verify the S/MIME signatures in `msg` and its descendants: detached
+/// multipart/signed signatures and opaque signed-data. Certificates are
+/// checked at the current time, not at the signer-supplied signing time,
+/// which is only reported.
+pub fn verify(msg: &Message, trusted: &[Certificate]) -> Vec<SmimeResult> {
+    let mut out = Vec::new();
+    for part in security::detect(msg) {
+        if part.scheme != Scheme::Smime || part.protection != Protection::Signed
+            || part.protocol.as_deref() == Some("certs-only")
+        {
+            continue;
+        }
+
+        let mut results = match (&part.signature, &part.payload) {
+            (Some(sig), _) => match &part.signed_content {
+                Some(content) => verify_cms(sig, Some(content), trusted, &part.path),
+                None => vec![SmimeResult::new(&part.path, SmimeStatus::Error, "signed content not found")]
+            },
+            (None, Some(cms)) => verify_cms(cms, None, trusted, &part.path),
+            (None, None) => vec![SmimeResult::new(&part.path, SmimeStatus::Error, "missing signature")]
+        };
+
+        let from = match msg.descendant(&part.path[msg.path().len()..]) {
+            Some(p) => from_addresses(&p),
+            None => Vec::new()
+        };
+        for r in results.iter_mut() {
+            r.from_matches = r.emails.iter().any(|e| from.contains(e));
+        }
+        out.extend(results);
+    }
+    out
+}
@@ -0,0 +1,426 @@
+//! Conversation threading following https://www.jwz.org/doc/threading.html
+
+use std::collections::HashMap;
+
+use crate::message::Message;
+use crate::msgid::parse_msgids;
+
+
+/// The fields of one message that threading looks at.
+#[derive(Clone, Debug, Default)]
+pub struct ThreadInput {
+    pub message_id: Option<String>,
+    /// References followed by any In-Reply-To id not already listed, oldest
+    /// ancestor first.
+    pub references: Vec<String>,
+    pub subject: String,
+}
+
+
+/// A node of the resulting thread tree. `index` is the position of the
+/// message in the input, or None for a placeholder standing in for a
+/// message that was referenced but not supplied.
+#[derive(Clone, Debug)]
+pub struct ThreadNode {
+    pub index: Option<usize>,
+    pub message_id: Option<String>,
+    pub children: Vec<ThreadNode>,
+}
+
+
+impl ThreadInput {
+    pub fn from_message(msg: &Message) -> ThreadInput {
+        let headers = msg.headers();
+
+        let message_id = headers.first("Message-ID")
+            .and_then(|v| parse_msgids(&v).into_iter().next());
+
+        let mut references = Vec::new();
+        for value in headers.all("References") {
+            references.extend(parse_msgids(&value));
+        }
+
+        // JWZ: use the first In-Reply-To id only if References lacks it.
+        if let Some(value) = headers.first("In-Reply-To") {
+            if let Some(id) = parse_msgids(&value).into_iter().next() {
+                if !references.contains(&id) {
+                    references.push(id);
+                }
+            }
+        }
+
+        ThreadInput {
+            message_id: message_id,
+            references: references,
+            subject: headers.first("Subject").unwrap_or_default(),
+        }
+    }
+}
+
+
+const REPLY_PREFIXES: &[&str] = &[
+    "re", "fw", "fwd", "aw", "wg", "sv", "vs", "antw", "ref", "tr", "rif",
+];
+
+
+/// Strip any number of reply and forward prefixes ("Re:", "Fwd:", "AW:",
+/// "Re[2]:"), returning the base subject and whether anything was removed.
+pub fn base_subject(subject: &str) -> (String, bool) {
+    let mut s = subject.trim();
+    let mut stripped = false;
+
+    while let Some(colon) = s.find(':') {
+        let mut prefix = s[..colon].trim_end();
+        // "Re[2]" / "Re(2)" counters
+        if let Some(i) = prefix.find(['[', '(']) {
+            let close = if prefix[i..].starts_with('[') { ']' } else { ')' };
+            match prefix[i+1..].strip_suffix(close) {
+                Some(n) if !n.is_empty() && n.chars().all(|c| c.is_ascii_digit()) => prefix = &prefix[..i],
+                _ => {}
+            }
+        }
+
+        let lower = prefix.to_lowercase();
+        if !REPLY_PREFIXES.contains(&lower.as_str()) {
+            break;
+        }
+
+        s = s[colon+1..].trim_start();
+        stripped = true;
+    }
+
+    (s.split_whitespace().collect::<Vec<_>>().join(" ").to_lowercase(), stripped)
+}
+
+
+struct Container {
+    message: Option<usize>,
+    id: Option<String>,
+    parent: Option<usize>,
+    children: Vec<usize>,
+}
+
+
+struct Threader {
+    containers: Vec<Container>,
+}
+
+
+impl Threader {
+    fn new_container(&mut self, id: Option<String>) -> usize {
+        self.containers.push(Container {
+            message: None,
+            id: id,
+            parent: None,
+            children: Vec::new(),
+        });
+        self.containers.len() - 1
+    }
+
+    /// Whether `a` is `b` or one of its ancestors.
+    fn is_ancestor(&self, a: usize, b: usize) -> bool {
+        let mut cur = Some(b);
+        while let Some(c) = cur {
+            if c == a {
+                return true;
+            }
+            cur = self.containers[c].parent;
+        }
+        false
+    }
+
+    fn unlink(&mut self, child: usize) {
+        if let Some(parent) = self.containers[child].parent.take() {
+            self.containers[parent].children.retain(|&c| c != child);
+        }
+    }
+
+    fn link(&mut self, parent: usize, child: usize) {
+        self.unlink(child);
+        self.containers[child].parent = Some(parent);
+        self.containers[parent].children.push(child);
+    }
+
+    /// Step 4: drop empty leaves and splice the children of other empty
+    /// containers into their parent. At the root level an empty container
+    /// is only replaced by its child if it has exactly one.
+    fn prune(&mut self, nodes: Vec<usize>, is_root: bool) -> Vec<usize> {
+        let mut out = Vec::new();
+        for c in nodes {
+            let children = std::mem::take(&mut self.containers[c].children);
+            let children = self.prune(children, false);
+            self.containers[c].children = children.clone();
+
+            if self.containers[c].message.is_some() {
+                out.push(c);
+            } else if children.is_empty() {
+                continue;
+            } else if !is_root || children.len() == 1 {
+                for &child in &children {
+                    self.containers[child].parent = self.containers[c].parent;
+                }
+                out.extend(children);
+            } else {
+                out.push(c);
+            }
+        }
+        out
+    }
+
+    fn subject<'a>(&self, inputs: &'a [ThreadInput], c: usize) -> Option<&'a str> {
+        let c = match self.containers[c].message {
+            Some(_) => c,
+            None => *self.containers[c].children.first()?
+        };
+        self.containers[c].message.map(|i| inputs[i].subject.as_str())
+    }
+
+    /// Step 5: merge root-level threads that share a base subject.
+    fn group_by_subject(&mut self, inputs: &[ThreadInput], roots: Vec<usize>) -> Vec<usize> {
+        let mut table: HashMap<String, usize> = HashMap::new();
+
+        for &c in &roots {
+            let (base, is_reply) = match self.subject(inputs, c) {
+                Some(s) => base_subject(s),
+                None => continue
+            };
+            if base.is_empty() {
+                continue;
+            }
+
+            let replace = match table.get(&base) {
+                None => true,
+                Some(&old) => {
+                    let old_reply = self.subject(inputs, old)
+                        .map(|s| base_subject(s).1)
+                        .unwrap_or(false);
+                    (self.containers[c].message.is_none() &&
+                     self.containers[old].message.is_some()) ||
+                    (old_reply && !is_reply)
+                }
+            };
+            if replace {
+                table.insert(base, c);
+            }
+        }
+
+        let mut out = Vec::new();
+        for c in roots {
+            let (base, is_reply) = match self.subject(inputs, c) {
+                Some(s) => base_subject(s),
+                None => {
+                    out.push(c);
+                    continue;
+                }
+            };
+
+            let target = match table.get(&base) {
+                Some(&t) if t != c && !base.is_empty() => t,
+                _ => {
+                    out.push(c);
+                    continue;
+                }
+            };
+
+            let target_empty = self.containers[target].message.is_none();
+            let target_reply = self.subject(inputs, target)
+                .map(|s| base_subject(s).1)
+                .unwrap_or(false);
+            if target_empty && self.containers[c].message.is_none() {
+                // Both placeholders: adopt c's children.
+                for child in std::mem::take(&mut self.containers[c].children) {
+                    self.containers[child].parent = None;
+                    self.link(target, child);
+                }
+            } else if target_empty || (is_reply && !target_reply) {
+                self.link(target, c);
+            } else {
+                // Neither is a reply to the other (both are, or neither is):
+                // make them siblings under a new placeholder that takes
+                // target's place.
+                let holder = self.new_container(None);
+                self.containers[holder].children.push(target);
+                self.containers[holder].children.push(c);
+                self.containers[target].parent = Some(holder);
+                self.containers[c].parent = Some(holder);
+                table.insert(base, holder);
+                out.push(holder);
+            }
+        }
+
+        out.retain(|&c| self.containers[c].parent.is_none());
+        out
+    }
+
+    fn first_index(&self, c: usize) -> usize {
+        let own = self.containers[c].message.unwrap_or(usize::MAX);
+        self.containers[c].children.iter()
+            .map(|&child| self.first_index(child))
+            .fold(own, usize::min)
+    }
+
+    fn build(&self, inputs: &[ThreadInput], c: usize) -> ThreadNode {
+        let mut children = self.containers[c].children.clone();
+        children.sort_by_key(|&child| self.first_index(child));
+
+        ThreadNode {
+            index: self.containers[c].message,
+            message_id: match self.containers[c].message {
+                Some(i) => inputs[i].message_id.clone(),
+                None => self.containers[c].id.clone(),
+            },
+            children: children.iter().map(|&child| self.build(inputs, child)).collect(),
+        }
+    }
+}
+
+
+/// Thread `inputs` into conversation trees, returned in order of their
+/// earliest message.
+pub fn thread(inputs: &[ThreadInput]) -> Vec<ThreadNode> {
+    let mut t = Threader { containers: Vec::new() };
+    let mut id_table: HashMap<String, usize> = HashMap::new();
+
+    // Step 1
+    for (i, input) in inputs.iter().enumerate() {
+        // 1A: find or create the message's container. Duplicate or missing
+        // ids get a container of their own.
+        let c = match &input.message_id {
+            Some(id) => match id_table.get(id) {
+                Some(&c) if t.containers[c].message.is_none() => c,
+                Some(_) => t.new_container(None),
+                None => {
+                    let c = t.new_container(Some(id.clone()));
+                    id_table.insert(id.clone(), c);
+                    c
+                }
+            },
+            None => t.new_container(None)
+        };
+        t.containers[c].message = Some(i);
+
+        // 1B: chain the references together, without creating loops or
+        // overriding links made earlier.
+        let mut prev: Option<usize> = None;
+        for id in &input.references {
+            let r = match id_table.get(id) {
+                Some(&r) => r,
+                None => {
+                    let r = t.new_container(Some(id.clone()));
+                    id_table.insert(id.clone(), r);
+                    r
+                }
+            };
+
+            if let Some(p) = prev {
+                if t.containers[r].parent.is_none() && !t.is_ancestor(r, p) {
+                    t.link(p, r);
+                }
+            }
+            prev = Some(r);
+        }
+
+        // 1C: the last reference is the message's parent.
+        match prev {
+            Some(p) if !t.is_ancestor(c, p) => t.link(p, c),
+            Some(_) => {},
+            None => t.unlink(c)
+        }
+    }
+
+    // Step 2
+    let roots: Vec<usize> = (0..t.containers.len())
+        .filter(|&c| t.containers[c].parent.is_none())
+        .collect();
+
+    // Steps 4 and 5
+    let roots = t.prune(roots, true);
+    let mut roots = t.group_by_subject(inputs, roots);
+
+    roots.sort_by_key(|&c| t.first_index(c));
+    roots.iter().map(|&c| t.build(inputs, c)).collect()
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn input(id: &str, references: &[&str], subject: &str) -> ThreadInput {
+        ThreadInput {
+            message_id: Some(id.to_string()),
+            references: references.iter().map(|r| r.to_string()).collect(),
+            subject: subject.to_string(),
+        }
+    }
+
+    /// The tree as nested (index, children) pairs; None for placeholders.
+    fn shape(node: &ThreadNode) -> String {
+        let children: Vec<String> = node.children.iter().map(shape).collect();
+        let own = match node.index {
+            Some(i) => i.to_string(),
+            None => "_".to_string()
+        };
+        if children.is_empty() {
+            own
+        } else {
+            format!("{}({})", own, children.join(" "))
+        }
+    }
+
+    fn shapes(inputs: &[ThreadInput]) -> Vec<String> {
+        thread(inputs).iter().map(shape).collect()
+    }
+
+    #[test]
+    fn base_subjects() {
+        assert_eq!(base_subject("Re: Fwd: AW:  Hello   World"), ("hello world".to_string(), true));
+        assert_eq!(base_subject("Re[2]: x"), ("x".to_string(), true));
+        assert_eq!(base_subject("Meeting: 10:00"), ("meeting: 10:00".to_string(), false));
+        assert_eq!(base_subject("Re(3): x"), ("x".to_string(), true));
+        assert_eq!(base_subject("Re[2): x"), ("re[2): x".to_string(), false));
+        assert_eq!(base_subject("Re[]: x"), ("re[]: x".to_string(), false));
+        // Non-ASCII before the colon must not be sliced through.
+        assert_eq!(base_subject("Notes (caf\u{e9}: lunch"), ("notes (caf\u{e9}: lunch".to_string(), false));
+        assert_eq!(base_subject("Re[\u{e9}]: x"), ("re[\u{e9}]: x".to_string(), false));
+    }
+
+    #[test]
+    fn references_build_the_tree() {
+        let inputs = vec![
+            input("a", &[], "topic"),
+            input("b", &["a"], "Re: topic"),
+            input("c", &["a", "b"], "Re: topic"),
+            input("d", &["missing"], "other"),
+        ];
+        assert_eq!(shapes(&inputs), vec!["0(1(2))", "3"]);
+    }
+
+    #[test]
+    fn reply_joins_original_by_subject() {
+        let inputs = vec![
+            input("a", &[], "topic"),
+            input("b", &[], "Re: topic"),
+        ];
+        assert_eq!(shapes(&inputs), vec!["0(1)"]);
+    }
+
+    #[test]
+    fn two_replies_become_siblings() {
+        let inputs = vec![
+            input("a", &[], "Re: topic"),
+            input("b", &[], "Re: topic"),
+        ];
+        assert_eq!(shapes(&inputs), vec!["_(0 1)"]);
+    }
+
+    #[test]
+    fn two_originals_become_siblings() {
+        let inputs = vec![
+            input("a", &[], "topic"),
+            input("b", &[], "topic"),
+            input("c", &[], "Re: topic"),
+        ];
+        assert_eq!(shapes(&inputs), vec!["_(0 1 2)"]);
+    }
+}
@@ -0,0 +1,511 @@
+//! Decoding of application/ms-tnef ("winmail.dat") parts, following
+//! [MS-OXTNEF] and, for compressed RTF, [MS-OXRTFCP].
+
+const TNEF_SIGNATURE: u32 = 0x223e9f78;
+
+const LVL_MESSAGE: u8 = 1;
+const LVL_ATTACHMENT: u8 = 2;
+
+// Attribute ids, without their type in the high word.
+const ATT_BODY: u32 = 0x800c;
+const ATT_ATTACH_DATA: u32 = 0x800f;
+const ATT_ATTACH_TITLE: u32 = 0x8010;
+const ATT_ATTACH_REND_DATA: u32 = 0x9002;
+const ATT_MSG_PROPS: u32 = 0x9003;
+const ATT_ATTACHMENT: u32 = 0x9005;
+const ATT_OEM_CODEPAGE: u32 = 0x9007;
+
+// MAPI property ids.
+const PR_BODY: u16 = 0x1000;
+const PR_RTF_COMPRESSED: u16 = 0x1009;
+const PR_HTML: u16 = 0x1013;
+const PR_ATTACH_DATA_BIN: u16 = 0x3701;
+const PR_ATTACH_FILENAME: u16 = 0x3704;
+const PR_ATTACH_LONG_FILENAME: u16 = 0x3707;
+const PR_ATTACH_MIME_TAG: u16 = 0x370e;
+const PR_ATTACH_CONTENT_ID: u16 = 0x3712;
+
+// MAPI property types.
+const PT_STRING8: u16 = 0x001e;
+const PT_UNICODE: u16 = 0x001f;
+const MV_FLAG: u16 = 0x1000;
+
+/// Dictionary [MS-OXRTFCP] 2.1.3.1.1 preloads before decompression.
+const RTF_PREBUF: &[u8] = b"{\\rtf1\\ansi\\mac\\deff0\\deftab720{\\fonttbl;}{\\f0\\fnil \\froman \\fswiss \\fmodern \\fscript \\fdecor MS Sans SerifSymbolArialTimes New RomanCourier{\\colortbl\\red0\\green0\\blue0\r\n\\par \\pard\\plain\\f0\\fs20\\b\\i\\u\\tab\\tx";
+
+
+#[derive(Clone, Debug, Default)]
+pub struct TnefAttachment {
+    pub filename: Option<String>,
+    pub mime_type: Option<String>,
+    pub content_id: Option<String>,
+    pub data: Vec<u8>,
+}
+
+
+#[derive(Clone, Debug, Default)]
+pub struct Tnef {
+    /// The codepage of 8-bit strings and bodies, from attOemCodepage.
+    pub codepage: Option<u32>,
+    pub text: Option<String>,
+    /// HTML body in `codepage`.
+    pub html: Option<Vec<u8>>,
+    /// Decompressed RTF body.
+    pub rtf: Option<Vec<u8>>,
+    pub attachments: Vec<TnefAttachment>,
+}
+
+
+struct Reader<'a> {
+    buf: &'a [u8],
+    pos: usize,
+}
+
+
+impl<'a> Reader<'a> {
+    fn take(&mut self, n: usize) -> Option<&'a [u8]> {
+        let end = self.pos.checked_add(n)?;
+        let out = self.buf.get(self.pos..end)?;
+        self.pos = end;
+        Some(out)
+    }
+
+    fn u8(&mut self) -> Option<u8> {
+        self.take(1).map(|b| b[0])
+    }
+
+    fn u16(&mut self) -> Option<u16> {
+        self.take(2).map(|b| u16::from_le_bytes([b[0], b[1]]))
+    }
+
+    fn u32(&mut self) -> Option<u32> {
+        self.take(4).map(|b| u32::from_le_bytes([b[0], b[1], b[2], b[3]]))
+    }
+
+    fn at_end(&self) -> bool {
+        self.pos >= self.buf.len()
+    }
+}
+
+
+/// Whether a part with this type and filename holds TNEF data.
+pub fn is_tnef(mime_type: &str, filename: Option<&str>) -> bool {
+    mime_type.eq_ignore_ascii_case("application/ms-tnef") ||
+    mime_type.eq_ignore_ascii_case("application/vnd.ms-tnef") ||
+    filename.is_some_and(|f| f.eq_ignore_ascii_case("winmail.dat"))
+}
+
+
+/// The WHATWG label for a Windows codepage, if it is one we can name.
+pub fn codepage_label(codepage: u32) -> Option<&'static str> {
+    Some(match codepage {
+        874 => "windows-874",
+        932 => "shift_jis",
+        936 => "gbk",
+        949 => "euc-kr",
+        950 => "big5",
+        1250 => "windows-1250",
+        1251 => "windows-1251",
+        1252 => "windows-1252",
+        1253 => "windows-1253",
+        1254 => "windows-1254",
+        1255 => "windows-1255",
+        1256 => "windows-1256",
+        1257 => "windows-1257",
+        1258 => "windows-1258",
+        20127 => "us-ascii",
+        28591 => "iso-8859-1",
+        65001 => "utf-8",
+        _ => return None
+    })
+}
+
+
+fn utf16_string(bytes: &[u8]) -> String {
+    let units: Vec<u16> = bytes.chunks_exact(2)
+        .map(|c| u16::from_le_bytes([c[0], c[1]]))
+        .collect();
+    String::from_utf16_lossy(&units).trim_end_matches('\0').to_string()
+}
+
+
+fn ansi_string(bytes: &[u8], codepage: Option<u32>) -> String {
+    let bytes = match bytes.iter().position(|&b| b == 0) {
+        Some(i) => &bytes[..i],
+        None => bytes
+    };
+    let label = codepage.and_then(codepage_label);
+    crate::charset::decode(bytes, label, false).text
+}
+
+
+struct Prop {
+    id: u16,
+    ptype: u16,
+    values: Vec<Vec<u8>>,
+}
+
+
+impl Prop {
+    fn string(&self, codepage: Option<u32>) -> Option<String> {
+        let v = self.values.first()?;
+        match self.ptype {
+            PT_UNICODE => Some(utf16_string(v)),
+            PT_STRING8 => Some(ansi_string(v, codepage)),
+            _ => None
+        }
+    }
+
+    fn bytes(&self) -> Option<Vec<u8>> {
+        self.values.first().cloned()
+    }
+}
+
+
+fn fixed_size(ptype: u16) -> Option<usize> {
+    match ptype {
+        0x0001 | 0x0002 | 0x0003 | 0x0004 | 0x000a | 0x000b => Some(4),
+        0x0005 | 0x0006 | 0x0007 | 0x0014 | 0x0040 => Some(8),
+        0x0048 => Some(16),
+        _ => None
+    }
+}
+
+
+fn read_var_value(r: &mut Reader) -> Option<Vec<u8>> {
+    let len = r.u32()? as usize;
+    let data = r.take(len)?.to_vec();
+    r.take((4 - len % 4) % 4)?;
+    Some(data)
+}
+
+
+/// Read a MAPI property list (attMsgProps, attAttachment).
+fn read_props(data: &[u8]) -> Option<Vec<Prop>> {
+    let mut r = Reader { buf: data, pos: 0 };
+    let count = r.u32()?;
+    let mut props = Vec::new();
+
+    for _ in 0..count {
+        let ptype = r.u16()?;
+        let id = r.u16()?;
+
+        if id >= 0x8000 {
+            // Named property: GUID, then a numeric id or a UTF-16 name.
+            r.take(16)?;
+            match r.u32()? {
+                0 => { r.u32()?; },
+                _ => { read_var_value(&mut r)?; }
+            }
+        }
+
+        let base = ptype & !MV_FLAG;
+        let mut values = Vec::new();
+        match fixed_size(base) {
+            Some(size) => {
+                let n = if ptype & MV_FLAG != 0 { r.u32()? } else { 1 };
+                for _ in 0..n {
+                    values.push(r.take(size)?.to_vec());
+                }
+            },
+            None => {
+                // PT_STRING8, PT_UNICODE, PT_BINARY and PT_OBJECT carry a
+                // count even when single-valued.
+                let n = r.u32()?;
+                for _ in 0..n {
+                    values.push(read_var_value(&mut r)?);
+                }
+            }
+        }
+
+        props.push(Prop {
+            id: id,
+            ptype: base,
+            values: values,
+        });
+    }
+
+    Some(props)
+}
+
+
+/// Decompress a PR_RTF_COMPRESSED value. None if it is truncated, fails
+/// its CRC, or decompresses to less than its stated size.
+pub fn decompress_rtf(data: &[u8]) -> Option<Vec<u8>> {
+    let mut r = Reader { buf: data, pos: 0 };
+    let comp_size = r.u32()? as usize;
+    let raw_size = r.u32()? as usize;
+    let comp_type = r.take(4)?;
+    let crc = r.u32()?;
+
+    // The compressed size counts everything after itself.
+    let end = comp_size.checked_add(4)?;
+    if end < r.pos || end > data.len() {
+        return None;
+    }
+    if comp_type == b"MELA" {
+        return Some(data[r.pos..end].to_vec());
+    }
+    if comp_type != b"LZFu" || crate::crc::update(0, &data[r.pos..end]) != crc {
+        return None;
+    }
+
+    let mut dict = [0u8; 4096];
+    dict[..RTF_PREBUF.len()].copy_from_slice(RTF_PREBUF);
+    let mut write = RTF_PREBUF.len();
+    let mut out = Vec::new();
+
+    while r.pos < end {
+        let control = r.u8()?;
+        for bit in 0..8 {
+            if r.pos >= end {
+                break;
+            }
+
+            if control & (1 << bit) == 0 {
+                let b = r.u8()?;
+                out.push(b);
+                dict[write] = b;
+                write = (write + 1) % 4096;
+                continue;
+            }
+
+            let hi = r.u8()? as usize;
+            let lo = r.u8()? as usize;
+            let offset = (hi << 4) | (lo >> 4);
+            let length = (lo & 0xf) + 2;
+            if offset == write {
+                return finish_rtf(out, raw_size);
+            }
+            for i in 0..length {
+                let b = dict[(offset + i) % 4096];
+                out.push(b);
+                dict[write] = b;
+                write = (write + 1) % 4096;
+            }
+        }
+    }
+
+    finish_rtf(out, raw_size)
+}
+
+
+/// Decompressed RTF cut to its stated size; writers may pad the stream.
+fn finish_rtf(mut out: Vec<u8>, raw_size: usize) -> Option<Vec<u8>> {
+    if out.len() < raw_size {
+        return None;
+    }
+    out.truncate(raw_size);
+    Some(out)
+}
+
+
+/// Decode a TNEF stream, or None if `data` does not start with the TNEF
+/// signature. Attributes after a truncated or malformed one are ignored.
+pub fn parse(data: &[u8]) -> Option<Tnef> {
+    let mut r = Reader { buf: data, pos: 0 };
+    if r.u32()? != TNEF_SIGNATURE {
+        return None;
+    }
+    r.u16()?;   // legacy key
+
+    let mut tnef = Tnef::default();
+    let mut current: Option<TnefAttachment> = None;
+
+    while !r.at_end() {
+        let attr = (|| {
+            let level = r.u8()?;
+            let id = r.u32()? & 0xffff;
+            let len = r.u32()? as usize;
+            let value = r.take(len)?;
+            r.u16()?;   // checksum
+            Some((level, id, value))
+        })();
+        let (level, id, value) = match attr {
+            Some(a) => a,
+            None => break
+        };
+
+        match (level, id) {
+            (LVL_MESSAGE, ATT_OEM_CODEPAGE) if value.len() >= 4 => {
+                tnef.codepage = Some(u32::from_le_bytes([value[0], value[1], value[2], value[3]]));
+            },
+            (LVL_MESSAGE, ATT_BODY) => {
+                tnef.text = Some(ansi_string(value, tnef.codepage));
+            },
+            (LVL_MESSAGE, ATT_MSG_PROPS) => {
+                for prop in read_props(value).unwrap_or_default() {
+                    match prop.id {
+                        PR_BODY if tnef.text.is_none() => {
+                            tnef.text = prop.string(tnef.codepage);
+                        },
+                        PR_HTML => {
+                            tnef.html = prop.bytes()
+                                .map(|b| b.strip_suffix(b"\0").unwrap_or(&b).to_vec());
+                        },
+                        PR_RTF_COMPRESSED => {
+                            tnef.rtf = prop.bytes().and_then(|b| decompress_rtf(&b));
+                        },
+                        _ => {}
+                    }
+                }
+            },
+            (LVL_ATTACHMENT, ATT_ATTACH_REND_DATA) => {
+                if let Some(a) = current.take() {
+                    tnef.attachments.push(a);
+                }
+                current = Some(TnefAttachment::default());
+            },
+            (LVL_ATTACHMENT, ATT_ATTACH_TITLE) => {
+                let a = current.get_or_insert_with(TnefAttachment::default);
+                a.filename = Some(ansi_string(value, tnef.codepage));
+            },
+            (LVL_ATTACHMENT, ATT_ATTACH_DATA) => {
+                let a = current.get_or_insert_with(TnefAttachment::default);
+                a.data = value.to_vec();
+            },
+            (LVL_ATTACHMENT, ATT_ATTACHMENT) => {
+                let a = current.get_or_insert_with(TnefAttachment::default);
+                for prop in read_props(value).unwrap_or_default() {
+                    match prop.id {
+                        PR_ATTACH_LONG_FILENAME => {
+                            a.filename = prop.string(tnef.codepage).or(a.filename.take());
+                        },
+                        PR_ATTACH_FILENAME if a.filename.is_none() => {
+                            a.filename = prop.string(tnef.codepage);
+                        },
+                        PR_ATTACH_MIME_TAG => a.mime_type = prop.string(tnef.codepage),
+                        PR_ATTACH_CONTENT_ID => a.content_id = prop.string(tnef.codepage),
+                        PR_ATTACH_DATA_BIN if a.data.is_empty() => {
+                            a.data = prop.bytes().unwrap_or_default();
+                        },
+                        _ => {}
+                    }
+                }
+            },
+            _ => {}
+        }
+    }
+
+    if let Some(a) = current.take() {
+        tnef.attachments.push(a);
+    }
+    Some(tnef)
+}
+
+
+/// A header parameter value: quoted if ASCII, else RFC 2231 encoded.
+fn param(name: &str, value: &str) -> String {
+    let value: String = value.chars().filter(|c| !c.is_control()).collect();
+    if value.is_ascii() {
+        let escaped = value.replace('\\', "\\\\").replace('"', "\\\"");
+        return format!("{}=\"{}\"", name, escaped);
+    }
+
+    let mut encoded = String::new();
+    for b in value.bytes() {
+        if b.is_ascii_alphanumeric() || b"!#$&+-.^_`|~".contains(&b) {
+            encoded.push(b as char);
+        } else {
+            encoded.push_str(&format!("%{:02X}", b));
+        }
+    }
+    format!("{}*=utf-8''{}", name, encoded)
+}
+
+
+fn entity(headers: &[String], body: &[u8]) -> Vec<u8> {
+    let mut out = Vec::new();
+    for h in headers {
+        out.extend_from_slice(h.as_bytes());
+        out.extend_from_slice(b"\r\n");
+    }
+    out.extend_from_slice(b"\r\n");
+    out.extend_from_slice(body);
+    out
+}
+
+
+fn text_entity(mime_type: &str, body: &[u8], charset: Option<&str>) -> Vec<u8> {
+    let ctype = match charset {
+        Some(cs) => format!("Content-Type: {}; charset={}", mime_type, cs),
+        None => format!("Content-Type: {}", mime_type)
+    };
+    entity(&[ctype, "Content-Transfer-Encoding: binary".to_string()], body)
+}
+
+
+/// A standalone attachment entity holding `data` in binary encoding. Also
+/// used for the blocks found by `blocks::find_blocks`.
+pub(crate) fn attachment_entity(filename: Option<&str>, mime_type: Option<&str>,
+                                content_id: Option<&str>, data: &[u8]) -> Vec<u8>
+{
+    let mime_type = mime_type
+        .filter(|m| m.contains('/') && !m.contains(char::is_whitespace))
+        .unwrap_or("application/octet-stream");
+
+    let mut headers = Vec::new();
+    match filename {
+        Some(name) => {
+            headers.push(format!("Content-Type: {}; {}", mime_type, param("name", name)));
+            headers.push(format!("Content-Disposition: attachment; {}", param("filename", name)));
+        },
+        None => {
+            headers.push(format!("Content-Type: {}", mime_type));
+            headers.push("Content-Disposition: attachment".to_string());
+        }
+    }
+    if let Some(cid) = content_id {
+        let cid: String = cid.chars().filter(|c| !c.is_control() && *c != '<' && *c != '>').collect();
+        headers.push(format!("Content-ID: <{}>", cid));
+    }
+    headers.push("Content-Transfer-Encoding: binary".to_string());
+    entity(&headers, data)
+}
+
+
+/// Render the contents of `tnef` as standalone MIME entities: the body
+/// (text and HTML as a multipart/alternative if both are present), the RTF
+/// body if there is no HTML one, then each attachment.
+pub(crate) fn to_mime(tnef: &Tnef) -> Vec<Vec<u8>> {
+    let charset = tnef.codepage.and_then(codepage_label);
+    let mut out = Vec::new();
+
+    let text = tnef.text.as_ref().map(|t| text_entity("text/plain", t.as_bytes(), Some("utf-8")));
+    let html = tnef.html.as_ref().map(|h| text_entity("text/html", h, charset));
+    match (text, html) {
+        (Some(text), Some(html)) => {
+            let mut boundary = "tnef-alternative".to_string();
+            while text.windows(boundary.len()).any(|w| w == boundary.as_bytes()) ||
+                  html.windows(boundary.len()).any(|w| w == boundary.as_bytes()) {
+                boundary.push('-');
+            }
+
+            let mut body = Vec::new();
+            for part in [text, html] {
+                body.extend_from_slice(format!("--{}\r\n", boundary).as_bytes());
+                body.extend_from_slice(&part);
+                body.extend_from_slice(b"\r\n");
+            }
+            body.extend_from_slice(format!("--{}--\r\n", boundary).as_bytes());
+
+            let ctype = format!("Content-Type: multipart/alternative; boundary=\"{}\"", boundary);
+            out.push(entity(&[ctype], &body));
+        },
+        (Some(part), None) | (None, Some(part)) => out.push(part),
+        (None, None) => {}
+    }
+
+    if tnef.html.is_none() {
+        if let Some(rtf) = &tnef.rtf {
+            out.push(text_entity("text/rtf", rtf, None));
+        }
+    }
+
+    for a in &tnef.attachments {
+        out.push(attachment_entity(a.filename.as_deref(), a.mime_type.as_deref(),
+                                   a.content_id.as_deref(), &a.data));
+    }
+
+    out
+}
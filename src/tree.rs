@@ -0,0 +1,236 @@
+use std::collections::BTreeMap;
+
+use serde::Serialize;
+
+use crate::charset;
+use crate::message;
+use crate::message::Message;
+use crate::message::Node;
+use crate::message::slice_offset;
+use crate::parts;
+
+
+#[derive(Clone, Debug, Serialize)]
+pub struct TreeHeader {
+    pub name: String,
+    /// Unfolded, with encoded-words decoded by `charset::decode_header`.
+    pub value: String,
+    pub offset: [usize; 2],
+}
+
+
+#[derive(Clone, Debug, Serialize)]
+pub struct TreeText {
+    pub text: String,
+    pub charset: &'static str,
+    pub detected: bool,
+}
+
+
+/// A serializable summary of one part and, recursively, its subparts.
+/// Offsets are relative to `Message::buffer()`.
+#[derive(Clone, Debug, Serialize)]
+pub struct PartTree {
+    pub path: Vec<usize>,
+    pub offset: [usize; 2],
+    pub header_offset: [usize; 2],
+    pub body_offset: [usize; 2],
+    pub mime_type: String,
+    /// The charset parameter as declared, if any.
+    pub charset: Option<String>,
+    pub params: BTreeMap<String, String>,
+    pub disposition: String,
+    pub filename: Option<String>,
+    pub content_id: Option<String>,
+    pub encoding: &'static str,
+    /// Size of the body as stored, before transfer decoding (unlike the
+    /// decoded "size" of `walk()`).
+    pub encoded_size: usize,
+    /// "container", "body", "inline" or "attachment"; see `parts::PartKind`.
+    pub kind: &'static str,
+    pub headers: Vec<TreeHeader>,
+    /// Decoded text of `body` parts, if requested.
+    pub text: Option<TreeText>,
+    pub children: Vec<PartTree>,
+}
+
+
+/// Summarize `msg`, located at `node`; `related_resource` is whether it is
+/// an inline resource of its multipart/related parent. Children, and
+/// everything about them, are reached through the node rather than by path
+/// from the root.
+fn build(msg: &Message, node: Node, related_resource: bool, with_text: bool) -> PartTree {
+    let (pm, buf) = (node.pm, node.buf);
+
+    let subparts: Vec<(Message, Node)> = (0..msg.child_count(node))
+        .map(|i| msg.child_node(node, i))
+        .collect();
+    let ids: Vec<Option<String>> = subparts.iter()
+        .map(|(_, child)| parts::header_content_id(child.pm))
+        .collect();
+    let root = match pm.ctype.mimetype.eq_ignore_ascii_case("multipart/related") {
+        true => Some(parts::related_root_index(pm.ctype.params.get("start"), &ids)),
+        false => None
+    };
+
+    let kind = parts::kind_of(pm, subparts.len(), related_resource);
+
+    let fields = message::header_fields(pm, buf).into_iter()
+        .map(|f| TreeHeader {
+            value: charset::decode_header(f.raw_value, false),
+            name: f.name,
+            offset: f.offset,
+        })
+        .collect();
+
+    let header_bytes = pm.get_headers().get_raw_bytes();
+    let body = message::encoded_body(pm);
+    let declared = pm.ctype.params.get("charset");
+
+    let text = match kind {
+        parts::PartKind::Body if with_text => pm.get_body_raw().ok().map(|raw| {
+            let decoded = charset::decode(&raw, declared.map(|s| s.as_str()), false);
+            TreeText {
+                text: decoded.text,
+                charset: decoded.charset,
+                detected: decoded.detected,
+            }
+        }),
+        _ => None
+    };
+
+    let children = subparts.iter().enumerate()
+        .map(|(i, (sub, child))| {
+            let resource = root.is_some_and(|r| r != i) && ids[i].is_some();
+            build(sub, *child, resource, with_text)
+        })
+        .collect();
+
+    PartTree {
+        path: msg.path().to_vec(),
+        offset: [slice_offset(buf, pm.raw_bytes), pm.raw_bytes.len()],
+        header_offset: [slice_offset(buf, header_bytes), header_bytes.len()],
+        body_offset: [slice_offset(buf, body), body.len()],
+        mime_type: pm.ctype.mimetype.clone(),
+        charset: declared.cloned(),
+        params: pm.ctype.params.clone(),
+        disposition: message::disposition_name(pm),
+        filename: message::part_filename(pm),
+        content_id: parts::header_content_id(pm),
+        encoding: message::encoding_name(pm),
+        encoded_size: body.len(),
+        kind: kind.as_str(),
+        headers: fields,
+        text: text,
+        children: children,
+    }
+}
+
+
+/// Summarize `msg` and all of its descendants in a single traversal. With
+/// `with_text`, parts classified as body text carry their decoded text.
+pub fn tree(msg: &Message, with_text: bool) -> PartTree {
+    let related_resource = parts::is_related_resource(msg, msg.parent().as_ref());
+    build(msg, msg.node(), related_resource, with_text)
+}
+
+
+/// `tree` serialized as a JSON document.
+pub fn to_json(msg: &Message, with_text: bool) -> String {
+    serde_json::to_string(&tree(msg, with_text)).unwrap()
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const MESSAGE: &[u8] = b"Subject: =?utf-8?q?caf=C3=A9?=\r\n\
+Content-Type: multipart/mixed; boundary=m\r\n\
+\r\n\
+--m\r\n\
+Content-Type: text/plain; charset=iso-8859-1\r\n\
+Content-Transfer-Encoding: quoted-printable\r\n\
+\r\n\
+caf=E9\r\n\
+--m\r\n\
+Content-Type: message/rfc822\r\n\
+\r\n\
+Subject: inner\r\n\
+Content-Type: application/octet-stream\r\n\
+Content-Transfer-Encoding: base64\r\n\
+\r\n\
+AAEC\r\n\
+--m--\r\n";
+
+    fn check(tree: &PartTree, msg: &Message) {
+        assert_eq!(tree.path, msg.path());
+        assert_eq!(tree.offset, msg.offset());
+        assert_eq!(tree.header_offset, msg.headers().offset());
+        assert_eq!(tree.body_offset, msg.body_offset());
+        assert_eq!(&tree.mime_type, msg.mime_type());
+        assert_eq!(tree.encoding, msg.body_encoding());
+        assert_eq!(tree.encoded_size, msg.body_encoded().len());
+        assert_eq!(tree.kind, parts::classify(msg, msg.parent().as_ref()).as_str());
+        assert_eq!(tree.content_id, parts::content_id(msg));
+        let offsets: Vec<[usize; 2]> = msg.headers().fields().iter().map(|f| f.offset).collect();
+        assert_eq!(tree.headers.iter().map(|h| h.offset).collect::<Vec<_>>(), offsets);
+        assert_eq!(tree.children.len(), msg.subpart_count());
+        for (child, sub) in tree.children.iter().zip(msg.subparts()) {
+            check(child, &sub);
+        }
+    }
+
+    #[test]
+    fn tree_matches_message() {
+        let msg = Message::parse(MESSAGE).unwrap();
+        let t = tree(&msg, true);
+        check(&t, &msg);
+
+        assert_eq!(t.headers[0].value, "caf\u{e9}");
+        let text = t.children[0].text.as_ref().unwrap();
+        assert_eq!(text.text, "caf\u{e9}\r\n");
+        assert_eq!(text.charset, "windows-1252");
+
+        let inner = &t.children[1].children[0];
+        assert_eq!(inner.path, vec![1, 0]);
+        assert_eq!(inner.headers[0].value, "inner");
+        assert_eq!(inner.encoded_size, 6);
+        assert!(inner.text.is_none());
+    }
+
+    #[test]
+    fn related_parts_are_classified_from_their_nodes() {
+        let raw = b"Content-Type: multipart/related; boundary=r; start=\"<root@x>\"\r\n\
+\r\n\
+--r\r\n\
+Content-Type: image/png\r\n\
+Content-ID: <img@x>\r\n\
+\r\n\
+png\r\n\
+--r\r\n\
+Content-Type: text/html\r\n\
+Content-ID: <root@x>\r\n\
+\r\n\
+<img src=cid:img@x>\r\n\
+--r--\r\n";
+        let msg = Message::parse(raw).unwrap();
+        let t = tree(&msg, false);
+        check(&t, &msg);
+        assert_eq!(t.children[0].kind, "inline");
+        assert_eq!(t.children[0].content_id.as_deref(), Some("img@x"));
+        assert_eq!(t.children[1].kind, "body");
+
+        let sub = msg.subpart(0).unwrap();
+        assert_eq!(tree(&sub, false).kind, "inline");
+    }
+
+    #[test]
+    fn subtree_of_a_part() {
+        let msg = Message::parse(MESSAGE).unwrap();
+        let sub = msg.subpart(1).unwrap();
+        let t = tree(&sub, false);
+        check(&t, &sub);
+        assert!(t.children[0].text.is_none());
+    }
+}
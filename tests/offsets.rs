@@ -0,0 +1,123 @@
+//! Every constructor reports offsets into the buffer the caller supplied.
+
+use std::fs;
+
+use mailpar::maildir::Maildir;
+use mailpar::mbox::MboxFormat;
+use mailpar::mbox::MboxReader;
+use mailpar::Backing;
+use mailpar::Message;
+
+
+const MESSAGE: &[u8] = b"From sender@example.com Mon Jan  1 00:00:00 2024\r\n\
+Subject: offsets\r\n\
+Content-Type: multipart/mixed; boundary=\"b\"\r\n\
+\r\n\
+preamble\r\n\
+--b\r\n\
+Content-Type: text/plain\r\n\
+\r\n\
+first\r\n\
+--b\r\n\
+Content-Type: text/html\r\n\
+X-Empty:\r\n\
+X-Blank: \r\n\
+X-Folded: a\r\n b\r\n\
+\r\n\
+<p>second</p>\r\n\
+--b--\r\n";
+
+
+fn check_part(buf: &[u8], msg: &Message) {
+    let [start, len] = msg.offset();
+    assert_eq!(&buf[start..start+len], msg.raw_bytes(), "part {:?}", msg.path());
+
+    let [start, len] = msg.body_offset();
+    assert_eq!(&buf[start..start+len], msg.body_encoded(), "body {:?}", msg.path());
+
+    let headers = msg.headers();
+    let [start, len] = headers.offset();
+    assert_eq!(&buf[start..start+len], headers.raw_bytes(), "headers {:?}", msg.path());
+
+    // The fields tile the header block up to the blank line.
+    let mut pos = headers.offset()[0];
+    for field in headers.fields() {
+        let [start, len] = field.offset;
+        assert_eq!(start, pos);
+        assert!(buf[start..].starts_with(field.raw_key));
+        assert!(buf[start..start+len].ends_with(b"\r\n"));
+        pos = start + len;
+    }
+    assert!(buf[pos..].starts_with(b"\r\n"));
+
+    for sub in msg.subparts() {
+        check_part(buf, &sub);
+    }
+}
+
+
+fn check(buf: &[u8], msg: &Message) {
+    assert_eq!(msg.subpart_count(), 2);
+    // The root part starts after the "From " line.
+    assert_eq!(msg.offset()[0], buf.iter().position(|&b| b == b'\n').unwrap() + 1);
+    check_part(buf, msg);
+}
+
+
+fn scratch(name: &str) -> std::path::PathBuf {
+    let path = std::env::temp_dir().join(format!("mailpar-offsets-{}-{}", name, std::process::id()));
+    let _ = fs::remove_dir_all(&path);
+    path
+}
+
+
+#[test]
+fn parse() {
+    check(MESSAGE, &Message::parse(MESSAGE).unwrap());
+    check(MESSAGE, &Message::parse_lenient(MESSAGE));
+}
+
+
+#[test]
+fn from_backing() {
+    check(MESSAGE, &Message::from_backing(Backing::Owned(MESSAGE.to_vec())).unwrap());
+    check(MESSAGE, &Message::from_backing_lenient(Backing::External(Box::new(MESSAGE))));
+}
+
+
+#[test]
+fn from_path() {
+    let path = scratch("file");
+    fs::write(&path, MESSAGE).unwrap();
+    check(MESSAGE, &Message::from_path(&path).unwrap());
+    check(MESSAGE, &Message::from_path_lenient(&path).unwrap());
+    fs::remove_file(&path).unwrap();
+}
+
+
+#[test]
+fn from_maildir_entry() {
+    let root = scratch("maildir");
+    let maildir = Maildir::create(&root).unwrap();
+    let entry = maildir.deliver(MESSAGE).unwrap();
+    check(MESSAGE, &Message::from_maildir_entry(&entry).unwrap());
+    fs::remove_dir_all(&root).unwrap();
+}
+
+
+#[test]
+fn from_mbox_message() {
+    let mut mailbox = MESSAGE.to_vec();
+    mailbox.extend_from_slice(b"\r\n");
+    mailbox.extend_from_slice(MESSAGE);
+
+    let messages: Vec<_> = MboxReader::new(&mailbox, MboxFormat::Mboxrd).collect();
+    assert_eq!(messages.len(), 2);
+    for m in messages {
+        let base = m.offset;
+        let msg = Message::from_mbox_message(m).unwrap();
+        check(MESSAGE, &msg);
+        let [start, len] = msg.subpart(1).unwrap().offset();
+        assert_eq!(&mailbox[base+start..base+start+len], msg.subpart(1).unwrap().raw_bytes());
+    }
+}
@@ -0,0 +1,129 @@
+use mailpar::message::Message;
+use mailpar::smime;
+use mailpar::smime::SmimeResult;
+use mailpar::smime::SmimeStatus;
+
+
+const CA: &[u8] = include_bytes!("fixtures/smime/ca.pem");
+const VALID: &[u8] = include_bytes!("fixtures/smime/valid.eml");
+const EXPIRED: &[u8] = include_bytes!("fixtures/smime/expired.eml");
+const WRONG_EKU: &[u8] = include_bytes!("fixtures/smime/wrong_eku.eml");
+const OPAQUE_BER: &[u8] = include_bytes!("fixtures/smime/opaque_ber.eml");
+const RSA_PSS: &[u8] = include_bytes!("fixtures/smime/rsa_pss.eml");
+const HASH_MISMATCH: &[u8] = include_bytes!("fixtures/smime/hash_mismatch.eml");
+const CONTENT_TYPE_MISMATCH: &[u8] = include_bytes!("fixtures/smime/content_type_mismatch.eml");
+
+
+fn verify(buf: &[u8], trusted: &[u8]) -> SmimeResult {
+    let trusted = if trusted.is_empty() {
+        Vec::new()
+    } else {
+        smime::load_certificates(trusted).unwrap()
+    };
+    let msg = Message::parse(buf).unwrap();
+    let mut results = smime::verify(&msg, &trusted);
+    assert_eq!(results.len(), 1);
+    results.remove(0)
+}
+
+
+fn replace(buf: &[u8], from: &str, to: &str) -> Vec<u8> {
+    String::from_utf8(buf.to_vec()).unwrap().replace(from, to).into_bytes()
+}
+
+
+#[test]
+fn valid() {
+    let r = verify(VALID, CA);
+    assert_eq!(r.status, SmimeStatus::Valid, "{}", r.reason);
+    assert_eq!(r.path, Vec::<usize>::new());
+    assert_eq!(r.subject.as_deref(), Some("CN=Alice"));
+    assert_eq!(r.emails, vec!["alice@example.com".to_string()]);
+    assert!(r.from_matches);
+    assert!(r.signing_time.is_some());
+}
+
+
+#[test]
+fn untrusted() {
+    let r = verify(VALID, b"");
+    assert_eq!(r.status, SmimeStatus::Untrusted);
+    assert_eq!(r.reason, "no trusted issuer for CN=Test CA");
+}
+
+
+#[test]
+fn tampered_content() {
+    let r = verify(&replace(VALID, "Hello Bob.", "Hello Eve."), CA);
+    assert_eq!(r.status, SmimeStatus::Invalid);
+    assert_eq!(r.reason, "content digest mismatch");
+}
+
+
+#[test]
+fn from_mismatch() {
+    let r = verify(&replace(VALID, "From: Alice <alice@", "From: Alice <mallory@"), CA);
+    assert_eq!(r.status, SmimeStatus::Valid);
+    assert!(!r.from_matches);
+}
+
+
+#[test]
+fn expired_signer() {
+    // Signed recently by a certificate that expired in 2001: the signing
+    // time does not make it valid.
+    let r = verify(EXPIRED, CA);
+    assert_eq!(r.status, SmimeStatus::Untrusted);
+    assert_eq!(r.reason, "certificate for CN=Alice (expired) is expired or not yet valid");
+}
+
+
+#[test]
+fn wrong_extended_key_usage() {
+    let r = verify(WRONG_EKU, CA);
+    assert_eq!(r.status, SmimeStatus::Untrusted);
+    assert_eq!(r.reason, "certificate is not for email protection");
+}
+
+
+#[test]
+fn opaque_indefinite_length() {
+    let r = verify(OPAQUE_BER, CA);
+    assert_eq!(r.status, SmimeStatus::Valid, "{}", r.reason);
+    assert!(r.from_matches);
+}
+
+
+#[test]
+fn signed_content_not_found() {
+    // mailparse still splits the parts at "--sig;", but it is not a
+    // delimiter line, so the signed part cannot be located.
+    let r = verify(&replace(VALID, "\r\n--sig\r\n", "\r\n--sig;\r\n"), CA);
+    assert_eq!(r.status, SmimeStatus::Error);
+    assert_eq!(r.reason, "signed content not found");
+}
+
+
+#[test]
+fn unsupported_signature_algorithm() {
+    // RSASSA-PSS is not implemented: that is not a bad signature.
+    let r = verify(RSA_PSS, CA);
+    assert_eq!(r.status, SmimeStatus::Error);
+    assert_eq!(r.reason, "unsupported signature algorithm");
+}
+
+
+#[test]
+fn signature_algorithm_names_another_hash() {
+    let r = verify(HASH_MISMATCH, CA);
+    assert_eq!(r.status, SmimeStatus::Error);
+    assert_eq!(r.reason, "signature algorithm does not match digest algorithm");
+}
+
+
+#[test]
+fn content_type_attribute_mismatch() {
+    let r = verify(CONTENT_TYPE_MISMATCH, CA);
+    assert_eq!(r.status, SmimeStatus::Invalid);
+    assert_eq!(r.reason, "content type mismatch");
+}
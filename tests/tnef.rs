@@ -0,0 +1,92 @@
+use base64::Engine;
+
+use mailpar::tnef;
+use mailpar::Message;
+
+
+/// An RTF body and two attachments, one with its data in attAttachData and
+/// one in PR_ATTACH_DATA_BIN.
+const WINMAIL: &[u8] = include_bytes!("fixtures/tnef/winmail.dat");
+
+const RTF: &[u8] = b"{\\rtf1\\ansi\\ansicpg1252\\pard Quarterly figures attached.\\par}\r\n";
+
+/// The example of MS-OXRTFCP 3.1.1.1, which uses back references.
+const LZFU: &[u8] = b"\x2d\x00\x00\x00\x2b\x00\x00\x00LZFu\xf1\xc5\xc7\xa7\
+\x03\x00\x0a\x00rcpg125B2\x0a\xf3 hel\x09\x00 bw\x05\xb0ld}\x0a\x80\x0f\xa0";
+
+
+#[test]
+fn attachments_and_rtf_body() {
+    let t = tnef::parse(WINMAIL).unwrap();
+    assert_eq!(t.codepage, Some(1252));
+    assert_eq!(t.rtf.as_deref(), Some(RTF));
+    assert!(t.text.is_none());
+    assert!(t.html.is_none());
+
+    assert_eq!(t.attachments.len(), 2);
+    let report = &t.attachments[0];
+    assert_eq!(report.filename.as_deref(), Some("Quarterly report.txt"));
+    assert_eq!(report.mime_type.as_deref(), Some("text/plain"));
+    assert_eq!(report.data, b"Revenue: 42\r\n");
+    let bin = &t.attachments[1];
+    assert_eq!(bin.filename.as_deref(), Some("caf\u{e9}.bin"));
+    assert_eq!(bin.mime_type, None);
+    assert_eq!(bin.data, [0, 1, 2, 3, 4, 5]);
+}
+
+
+#[test]
+fn winmail_dat_parts() {
+    let mut buf = b"Content-Type: multipart/mixed; boundary=m\r\n\r\n--m\r\n\
+Content-Type: application/ms-tnef; name=winmail.dat\r\n\
+Content-Transfer-Encoding: base64\r\n\r\n".to_vec();
+    buf.extend_from_slice(base64::engine::general_purpose::STANDARD.encode(WINMAIL).as_bytes());
+    buf.extend_from_slice(b"\r\n--m--\r\n");
+
+    let msg = Message::parse(&buf).unwrap();
+    let winmail = msg.subpart(0).unwrap();
+    let parts = winmail.subparts();
+    assert_eq!(parts.len(), 3);
+    assert_eq!(parts[0].mime_type(), "text/rtf");
+    assert_eq!(parts[0].body_raw().unwrap(), RTF);
+    assert_eq!(parts[1].filename().as_deref(), Some("Quarterly report.txt"));
+    assert_eq!(parts[1].body_raw().unwrap(), b"Revenue: 42\r\n");
+    assert_eq!(parts[2].filename().as_deref(), Some("caf\u{e9}.bin"));
+    assert_eq!(parts[2].body_raw().unwrap(), [0, 1, 2, 3, 4, 5]);
+}
+
+
+#[test]
+fn truncated_or_garbage_streams() {
+    assert!(tnef::parse(b"").is_none());
+    assert!(tnef::parse(&WINMAIL[..3]).is_none());
+    assert!(tnef::parse(b"PK\x03\x04 not a TNEF stream").is_none());
+
+    // Attributes before the cut survive; the rest are ignored.
+    let t = tnef::parse(&WINMAIL[..WINMAIL.len() - 20]).unwrap();
+    assert_eq!(t.rtf.as_deref(), Some(RTF));
+    assert_eq!(t.attachments.len(), 2);
+    assert!(t.attachments[1].data.is_empty());
+}
+
+
+#[test]
+fn lzfu_decompression() {
+    assert_eq!(tnef::decompress_rtf(LZFU).unwrap(),
+               b"{\\rtf1\\ansi\\ansicpg1252\\pard hello world}\r\n");
+
+    // A corrupted byte fails the CRC.
+    let mut corrupt = LZFU.to_vec();
+    corrupt[20] ^= 1;
+    assert!(tnef::decompress_rtf(&corrupt).is_none());
+
+    // The stream is shorter than its compressed size says.
+    assert!(tnef::decompress_rtf(&LZFU[..LZFU.len() - 1]).is_none());
+
+    // It decompresses to less than its uncompressed size says.
+    let mut long = LZFU.to_vec();
+    long[4] += 1;
+    assert!(tnef::decompress_rtf(&long).is_none());
+
+    assert!(tnef::decompress_rtf(b"\x0c\x00\x00\x00\x00\x00\x00\x00XXXX\x00\x00\x00\x00").is_none());
+}